@@ -4,6 +4,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .message_attribute(".", derive_serde)
         .enum_attribute(".", derive_serde)
+        // Added in a later version of the protocol, missing from the snapshots persisted before.
+        .field_attribute(
+            "opentelemetry.proto.trace.v1.Span.flags",
+            "#[serde(default)]",
+        )
+        .field_attribute(
+            "opentelemetry.proto.trace.v1.Span.Link.flags",
+            "#[serde(default)]",
+        )
         .compile(
             &["proto/opentelemetry/proto/collector/trace/v1/trace_service.proto"],
             &["proto/"],
@@ -1,38 +1,66 @@
-use otlp_embedded::{ui_app, Config, State, TraceServiceImpl, TraceServiceServer};
+use std::net::SocketAddr;
+
+use otlp_embedded::{serve_with_shutdown, Config, State};
+
+/// Read the bind address from the environment variable, or use the default if not set.
+fn bind_addr(var: &str, default: &str) -> SocketAddr {
+    let addr = std::env::var(var).unwrap_or_else(|_| default.to_owned());
+    addr.parse()
+        .unwrap_or_else(|e| panic!("invalid address `{addr}` in `{var}`: {e}"))
+}
 
 #[tokio::main]
 async fn main() {
+    let ui_addr = bind_addr("OTLP_EMBEDDED_UI_ADDR", "0.0.0.0:10188");
+    let grpc_addr = bind_addr("OTLP_EMBEDDED_GRPC_ADDR", "0.0.0.0:43177");
+
     let state = State::new(Config {
         max_length: 100,
         max_memory_usage: 1 << 27, // 128 MiB
+        ..Default::default()
     });
     let state_clone = state.clone();
-    let state_clone_2 = state.clone();
-
-    tokio::spawn(async {
-        axum::serve(
-            tokio::net::TcpListener::bind("0.0.0.0:10188")
-                .await
-                .unwrap(),
-            ui_app(state, "/"),
-        )
-        .await
-        .unwrap();
-    });
 
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
         loop {
             interval.tick().await;
-            let state = state_clone_2.read().await;
+            let state = state_clone.read().await;
             println!("Len: {}", state.len());
             println!("Estimated memory usage: {}", state.estimated_memory_usage());
         }
     });
 
-    tonic::transport::Server::builder()
-        .add_service(TraceServiceServer::new(TraceServiceImpl::new(state_clone)))
-        .serve("0.0.0.0:43177".parse().unwrap())
-        .await
-        .unwrap();
+    // Stop both servers on Ctrl-C, after finishing the in-flight requests.
+    serve_with_shutdown(state, ui_addr, grpc_addr, async {
+        tokio::signal::ctrl_c().await.unwrap();
+    })
+    .await
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_addr() {
+        assert_eq!(
+            bind_addr("OTLP_EMBEDDED_TEST_UNSET_ADDR", "0.0.0.0:10188"),
+            "0.0.0.0:10188".parse().unwrap()
+        );
+
+        std::env::set_var("OTLP_EMBEDDED_TEST_ADDR", "127.0.0.1:4317");
+        assert_eq!(
+            bind_addr("OTLP_EMBEDDED_TEST_ADDR", "0.0.0.0:10188"),
+            "127.0.0.1:4317".parse().unwrap()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid address `nope` in `OTLP_EMBEDDED_TEST_BAD_ADDR`")]
+    fn test_bind_addr_invalid() {
+        std::env::set_var("OTLP_EMBEDDED_TEST_BAD_ADDR", "nope");
+        bind_addr("OTLP_EMBEDDED_TEST_BAD_ADDR", "0.0.0.0:10188");
+    }
 }
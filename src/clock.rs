@@ -0,0 +1,72 @@
+use std::{
+    fmt::Debug,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+/// A source of the current time, used for age-based behaviors like
+/// [`Config::force_complete_after`](crate::Config::force_complete_after) and
+/// [`Config::max_age`](crate::Config::max_age).
+pub trait Clock: Debug + Send + Sync + 'static {
+    /// Get the current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// The [`Clock`] backed by the system time, which is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A [`Clock`] that only moves when told to, for testing purposes.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<SystemTime>,
+}
+
+impl MockClock {
+    /// Create a new [`MockClock`] starting at the given time.
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            now: Mutex::new(now),
+        }
+    }
+
+    /// Set the current time.
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    /// Advance the current time by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let clock = MockClock::new(start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), start + Duration::from_secs(5));
+
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+
+use tokio::sync::mpsc;
+use tonic::transport::{Channel, Endpoint};
+
+use crate::proto::collector::trace::v1::{
+    trace_service_client::TraceServiceClient, ExportTraceServiceRequest,
+};
+
+/// The maximum number of attempts to forward a request.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// The delay before the first retry, doubled for each subsequent one.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The timeout of connecting to the upstream collector.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The timeout of each attempt to forward a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum number of requests waiting to be forwarded. Further requests are dropped.
+const QUEUE_CAPACITY: usize = 128;
+
+/// Forwards the export requests to an upstream OTLP/gRPC collector in the background.
+///
+/// Requests are queued and forwarded one by one by a single worker task, so that a slow or
+/// unreachable upstream only costs a bounded amount of memory.
+pub(crate) struct Forwarder {
+    endpoint: Endpoint,
+    /// Created on the first request, as the worker must be spawned within a Tokio runtime.
+    queue: OnceLock<mpsc::Sender<ExportTraceServiceRequest>>,
+    /// The number of requests dropped, either due to a full queue or after all attempts failed.
+    dropped: Arc<AtomicU64>,
+}
+
+impl Forwarder {
+    /// Create a new [`Forwarder`] to the given endpoint, e.g., `http://localhost:4317`.
+    pub fn new(endpoint: &str) -> Result<Self, tonic::transport::Error> {
+        let endpoint = Endpoint::from_shared(endpoint.to_owned())?
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT);
+
+        Ok(Self {
+            endpoint,
+            queue: OnceLock::new(),
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Queue the request to be forwarded, retrying on failures.
+    ///
+    /// This never blocks the caller. If the queue is full, the request is dropped. Failures
+    /// are only logged and counted in [`Forwarder::dropped_count`].
+    pub fn forward(&self, request: ExportTraceServiceRequest) {
+        let queue = self.queue.get_or_init(|| {
+            let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+            let client = TraceServiceClient::new(self.endpoint.connect_lazy());
+            let uri = self.endpoint.uri().clone();
+            tokio::spawn(run(client, uri, rx, self.dropped.clone()));
+            tx
+        });
+
+        if queue.try_send(request).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            let uri = self.endpoint.uri();
+            tracing::warn!(%uri, dropped, "forward queue is full, dropping request");
+        }
+    }
+
+    /// Get the total number of requests dropped without being forwarded.
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Forward the queued requests one by one until the [`Forwarder`] is dropped.
+async fn run(
+    mut client: TraceServiceClient<Channel>,
+    uri: tonic::transport::Uri,
+    mut rx: mpsc::Receiver<ExportTraceServiceRequest>,
+    dropped: Arc<AtomicU64>,
+) {
+    while let Some(request) = rx.recv().await {
+        let mut backoff = INITIAL_BACKOFF;
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            match client.export(request.clone()).await {
+                Ok(_) => break,
+                Err(e) if attempt < MAX_ATTEMPTS => {
+                    tracing::debug!(%uri, attempt, error = %e, "failed to forward, retrying");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => {
+                    dropped.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!(%uri, error = %e, "failed to forward, giving up");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tonic::{
+        transport::{server::TcpIncoming, Server},
+        Request, Response, Status,
+    };
+
+    use super::*;
+    use crate::{
+        proto::collector::trace::v1::{
+            trace_service_server::{TraceService, TraceServiceServer},
+            ExportTraceServiceResponse,
+        },
+        test_util::*,
+        Config, State, TraceServiceImpl,
+    };
+
+    /// A downstream collector recording the requests received.
+    struct Recorder(mpsc::UnboundedSender<ExportTraceServiceRequest>);
+
+    #[tonic::async_trait]
+    impl TraceService for Recorder {
+        async fn export(
+            &self,
+            request: Request<ExportTraceServiceRequest>,
+        ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+            let _ = self.0.send(request.into_inner());
+            Ok(Response::new(ExportTraceServiceResponse::default()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::spawn(
+            Server::builder()
+                .add_service(TraceServiceServer::new(Recorder(tx)))
+                .serve_with_incoming(incoming),
+        );
+
+        let state = State::new(Config {
+            forward_endpoint: Some(format!("http://{addr}")),
+            ..Default::default()
+        });
+        let service = TraceServiceImpl::new(state.clone());
+        let request = request("svc", vec![span(1, 1, None), span(1, 2, Some(1))]);
+        service.export(Request::new(request.clone())).await.unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+            .await
+            .expect("request not forwarded")
+            .unwrap();
+        assert_eq!(received, request);
+        assert_eq!(state.read().await.diagnostics().dropped_forwards, 0);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_count() {
+        // Nothing listens on the port, so the worker is kept busy retrying the first request.
+        let forwarder = Forwarder::new("http://127.0.0.1:1").unwrap();
+        for _ in 0..QUEUE_CAPACITY + 10 {
+            forwarder.forward(request("svc", vec![span(1, 1, None)]));
+        }
+        assert!(forwarder.dropped_count() >= 9);
+    }
+}
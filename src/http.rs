@@ -0,0 +1,251 @@
+use std::net::SocketAddr;
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Extension, Json, Router,
+};
+use http_body_util::LengthLimitError;
+use prost::Message;
+use serde_json::json;
+use tower_http::{decompression::RequestDecompressionLayer, trace::TraceLayer};
+
+use crate::{
+    otlp_json::decode_export_request,
+    proto::collector::trace::v1::{
+        ExportTracePartialSuccess, ExportTraceServiceRequest, ExportTraceServiceResponse,
+    },
+    rate_limit::source_key,
+    service::export,
+    StateRef,
+};
+
+/// Create a new [`axum::Router`] that receives traces over OTLP/HTTP at `POST /v1/traces`,
+/// and stores them in the given [`StateRef`].
+///
+/// Both binary protobuf (`application/x-protobuf`) and JSON (`application/json`) encoded
+/// requests are supported, optionally compressed with `gzip` or `deflate` as indicated by the
+/// `Content-Encoding` header. The size of the request body is limited by
+/// [`Config::max_request_body_size`](crate::Config::max_request_body_size), which applies to
+/// the decompressed body, so that a small compressed request cannot inflate without bound.
+///
+/// To rate limit the requests by the peer address with
+/// [`Config::max_exports_per_second`](crate::Config::max_exports_per_second), serve the app
+/// with [`Router::into_make_service_with_connect_info`], otherwise all requests without the
+/// configured header share a single quota.
+pub fn ingest_app(state: StateRef) -> Router {
+    Router::new()
+        .route("/v1/traces", post(traces))
+        .layer(Extension(state))
+        .layer(RequestDecompressionLayer::new())
+        .layer(TraceLayer::new_for_http())
+}
+
+async fn traces(
+    Extension(state): Extension<StateRef>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    body: Body,
+) -> impl IntoResponse {
+    let allowed = state.read().await.allow_export(|header| {
+        let value = header.and_then(|h| headers.get(h));
+        source_key(
+            value.and_then(|v| v.to_str().ok()),
+            connect_info.map(|c| c.0),
+        )
+    });
+    if !allowed {
+        return (StatusCode::TOO_MANY_REQUESTS, "Export rate limit exceeded").into_response();
+    }
+
+    // The body is already decompressed by the layer, so the limit applies to the actual size.
+    let limit = state.read().await.max_request_body_size();
+    let body = match axum::body::to_bytes(body, limit).await {
+        Ok(body) => body,
+        Err(e) => {
+            return if e.into_inner().is::<LengthLimitError>() {
+                let msg = format!("Request body exceeds the limit of {limit} bytes");
+                (StatusCode::PAYLOAD_TOO_LARGE, msg).into_response()
+            } else {
+                bad_request_with_msg("Failed to read request body")
+            };
+        }
+    };
+
+    let is_json = (headers.get(header::CONTENT_TYPE))
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+
+    let request = if is_json {
+        decode_export_request(&body).map_err(|e| e.to_string())
+    } else {
+        ExportTraceServiceRequest::decode(body).map_err(|e| e.to_string())
+    };
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => return bad_request_with_msg(format!("Failed to decode request: {e}")),
+    };
+
+    let response = match export(&state, request).await {
+        Ok(response) => response,
+        // Reject all spans in the request.
+        Err(e) => ExportTraceServiceResponse {
+            partial_success: Some(ExportTracePartialSuccess {
+                rejected_spans: e.spans as i64,
+                error_message: e.to_string(),
+            }),
+        },
+    };
+
+    if is_json {
+        let res = match response.partial_success {
+            Some(p) => json!({
+                "partialSuccess": {
+                    "rejectedSpans": p.rejected_spans.to_string(),
+                    "errorMessage": p.error_message,
+                }
+            }),
+            None => json!({}),
+        };
+        Json(res).into_response()
+    } else {
+        (
+            [(header::CONTENT_TYPE, "application/x-protobuf")],
+            response.encode_to_vec(),
+        )
+            .into_response()
+    }
+}
+
+fn bad_request_with_msg(msg: impl Into<String>) -> Response {
+    (StatusCode::BAD_REQUEST, msg.into()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use axum::http::Request;
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+    use crate::{test_util::*, Config, State};
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn post(body: Vec<u8>, encoding: &str) -> Request<Body> {
+        Request::post("/v1/traces")
+            .header(header::CONTENT_TYPE, "application/x-protobuf")
+            .header(header::CONTENT_ENCODING, encoding)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_gzip_request() {
+        let state = State::new(Config {
+            max_request_body_size: 1024,
+            ..Default::default()
+        });
+        let app = ingest_app(state.clone());
+
+        let body = request("svc", vec![span(1, 1, None)]).encode_to_vec();
+        let (status, _, _) = send(&app, post(gzip(&body), "gzip")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(state.read().await.len(), 1);
+
+        // The limit applies to the decompressed body.
+        let mut large = span(2, 1, None);
+        large.attributes = vec![kv("key", &"x".repeat(4096))];
+        let body = gzip(&request("svc", vec![large]).encode_to_vec());
+        assert!(body.len() < 1024);
+        let (status, _, _) = send(&app, post(body, "gzip")).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(state.read().await.len(), 1);
+
+        let (status, _, _) = send(&app, post(Vec::new(), "unknown")).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_request_body_limit() {
+        let state = State::new(Config {
+            max_request_body_size: 1024,
+            ..Default::default()
+        });
+        let app = ingest_app(state.clone());
+
+        let body = request("svc", vec![span(1, 1, None)]).encode_to_vec();
+        assert!(body.len() < 1024);
+        let (status, _, _) = send(&app, post(body, "identity")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let mut large = span(2, 1, None);
+        large.attributes = vec![kv("key", &"x".repeat(4096))];
+        let body = request("svc", vec![large]).encode_to_vec();
+        let (status, _, body) = send(&app, post(body, "identity")).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "Request body exceeds the limit of 1024 bytes"
+        );
+        assert_eq!(state.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_json_request() {
+        let state = State::with_capacity(10);
+        let app = ingest_app(state.clone());
+
+        let body = r#"{ "resourceSpans": [{ "scopeSpans": [{ "spans": [{
+            "traceId": "01010101010101010101010101010101",
+            "spanId": "0101010101010101",
+            "name": "op"
+        }] }] }] }"#;
+        let request = Request::post("/v1/traces")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        let (status, headers, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        assert_eq!(body, b"{}");
+        assert!(state.read().await.peek_by_id(&trace_id(1)).is_some());
+
+        let request = Request::post("/v1/traces")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{"))
+            .unwrap();
+        let (status, _, _) = send(&app, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let state = State::new(Config {
+            max_exports_per_second: 1,
+            clock: clock(),
+            ..Default::default()
+        });
+        let app = ingest_app(state.clone());
+
+        let body = request("svc", vec![span(1, 1, None)]).encode_to_vec();
+        let (status, _, _) = send(&app, post(body.clone(), "identity")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Without the peer address, all requests share a single quota.
+        let (status, _, body) = send(&app, post(body, "identity")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "Export rate limit exceeded"
+        );
+    }
+}
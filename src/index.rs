@@ -0,0 +1,163 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    jaeger::model::any_value_to_serde_value,
+    proto::common::v1::{AnyValue, KeyValue},
+    trace::TraceId,
+};
+
+/// Values longer than this are not indexed, to bound the memory usage of the index.
+const MAX_INDEXED_VALUE_LEN: usize = 128;
+
+/// The estimated memory overhead of each association between a tag and a trace, in addition
+/// to the lengths of the key, the value and the trace ID.
+const ENTRY_OVERHEAD: usize = 128;
+
+type Tag = (String, String);
+
+/// Render the attribute value as a string to be matched against the tag queries.
+pub(crate) fn tag_value(value: &AnyValue) -> Option<String> {
+    let value = value.value.clone()?;
+    match any_value_to_serde_value(value) {
+        serde_json::Value::String(s) => Some(s),
+        other => Some(other.to_string()),
+    }
+}
+
+/// An inverted index from the attribute tags to the IDs of the traces having them.
+#[derive(Default)]
+pub(crate) struct TagIndex {
+    traces: HashMap<Tag, HashSet<TraceId>>,
+    /// The reverse mapping, for removing the traces from the index.
+    tags: HashMap<TraceId, HashSet<Tag>>,
+}
+
+fn entry_size((key, value): &Tag, id: &[u8]) -> usize {
+    ENTRY_OVERHEAD + key.len() + value.len() + id.len()
+}
+
+impl TagIndex {
+    /// Index the attributes for the trace, returning the increment of the estimated memory usage.
+    pub fn insert(&mut self, id: &[u8], attributes: &[KeyValue]) -> usize {
+        let mut size = 0;
+
+        for kv in attributes {
+            let Some(value) = kv.value.as_ref().and_then(tag_value) else {
+                continue;
+            };
+            if value.len() > MAX_INDEXED_VALUE_LEN {
+                continue;
+            }
+            let tag = (kv.key.clone(), value);
+
+            let tags = self.tags.entry(id.to_vec()).or_default();
+            if tags.contains(&tag) {
+                continue;
+            }
+            size += entry_size(&tag, id);
+            self.traces
+                .entry(tag.clone())
+                .or_default()
+                .insert(id.to_vec());
+            tags.insert(tag);
+        }
+
+        size
+    }
+
+    /// Remove the trace from the index, returning the decrement of the estimated memory usage.
+    pub fn remove(&mut self, id: &[u8]) -> usize {
+        let Some(tags) = self.tags.remove(id) else {
+            return 0;
+        };
+        let mut size = 0;
+
+        for tag in tags {
+            size += entry_size(&tag, id);
+            if let Some(traces) = self.traces.get_mut(&tag) {
+                traces.remove(id);
+                if traces.is_empty() {
+                    self.traces.remove(&tag);
+                }
+            }
+        }
+
+        size
+    }
+
+    /// Get the IDs of the traces having all the given tags.
+    pub fn get_all(&self, tags: &[(String, String)]) -> HashSet<TraceId> {
+        let mut sets = tags.iter().map(|tag| self.traces.get(tag));
+        let Some(Some(first)) = sets.next() else {
+            return HashSet::new();
+        };
+        let mut ids = first.clone();
+        for set in sets {
+            let Some(set) = set else {
+                return HashSet::new();
+            };
+            ids.retain(|id| set.contains(id));
+        }
+        ids
+    }
+
+    /// Get the distinct keys of the indexed tags.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.traces.keys().map(|(key, _)| key.as_str())
+    }
+
+    /// Get the distinct values of the indexed tags with the given key.
+    pub fn values<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        (self.traces.keys())
+            .filter(move |(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn clear(&mut self) {
+        self.traces.clear();
+        self.tags.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::test_util::*;
+
+    fn tag(key: &str, value: &str) -> (String, String) {
+        (key.to_owned(), value.to_owned())
+    }
+
+    #[test]
+    fn test_tag_index() {
+        let mut index = TagIndex::default();
+        let long = "x".repeat(MAX_INDEXED_VALUE_LEN + 1);
+        let size_1 = index.insert(&trace_id(1), &[kv("a", "1"), kv("b", "2"), kv("c", &long)]);
+        let size_2 = index.insert(&trace_id(2), &[kv("a", "1")]);
+        // Duplicates are not counted.
+        assert_eq!(index.insert(&trace_id(2), &[kv("a", "1")]), 0);
+
+        assert_eq!(
+            index.get_all(&[tag("a", "1")]),
+            HashSet::from([trace_id(1), trace_id(2)])
+        );
+        assert_eq!(
+            index.get_all(&[tag("a", "1"), tag("b", "2")]),
+            HashSet::from([trace_id(1)])
+        );
+        assert!(index.get_all(&[tag("a", "1"), tag("b", "3")]).is_empty());
+        assert!(index.get_all(&[tag("c", &long)]).is_empty());
+        assert_eq!(index.keys().sorted().collect_vec(), ["a", "b"]);
+
+        assert_eq!(index.remove(&trace_id(1)), size_1);
+        assert_eq!(
+            index.get_all(&[tag("a", "1")]),
+            HashSet::from([trace_id(2)])
+        );
+        assert!(index.get_all(&[tag("b", "2")]).is_empty());
+        assert_eq!(index.remove(&trace_id(2)), size_2);
+        assert_eq!(index.remove(&trace_id(2)), 0);
+    }
+}
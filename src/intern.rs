@@ -0,0 +1,99 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hasher},
+    sync::{Arc, Weak},
+};
+
+use prost::Message;
+
+use crate::proto::resource::v1::Resource;
+
+/// The number of entries below which the dropped resources are not pruned.
+const MIN_PRUNE_LEN: usize = 1024;
+
+/// Interns the resources, so that identical ones received in different exports share a single
+/// allocation.
+///
+/// Only weak references are held, so that a resource is freed once no trace refers to it.
+#[derive(Default)]
+pub(crate) struct ResourceInterner {
+    /// The resources by the hash of their encoded bytes. Collisions are resolved by comparing.
+    resources: HashMap<u64, Vec<Weak<Resource>>>,
+    /// The last resource interned and the result, since consecutive spans usually share one.
+    last: Option<(Arc<Resource>, Arc<Resource>)>,
+    /// The number of entries after the last pruning.
+    pruned_len: usize,
+}
+
+impl ResourceInterner {
+    /// Get the interned resource identical to the given one, or intern it if there's none.
+    pub fn intern(&mut self, resource: Arc<Resource>) -> Arc<Resource> {
+        if let Some((input, output)) = &self.last {
+            if Arc::ptr_eq(input, &resource) {
+                return output.clone();
+            }
+        }
+
+        let mut hasher = DefaultHasher::new();
+        hasher.write(&resource.encode_to_vec());
+        let entries = self.resources.entry(hasher.finish()).or_default();
+        entries.retain(|r| r.strong_count() > 0);
+
+        let found = (entries.iter()).find_map(|r| r.upgrade().filter(|r| **r == *resource));
+        let interned = match found {
+            Some(interned) => interned,
+            None => {
+                entries.push(Arc::downgrade(&resource));
+                resource.clone()
+            }
+        };
+
+        self.prune();
+        self.last = Some((resource, interned.clone()));
+        interned
+    }
+
+    /// Remove the entries of the dropped resources, once the map doubles in size since the
+    /// last pruning.
+    fn prune(&mut self) {
+        if self.resources.len() <= (self.pruned_len * 2).max(MIN_PRUNE_LEN) {
+            return;
+        }
+        self.resources.retain(|_, entries| {
+            entries.retain(|r| r.strong_count() > 0);
+            !entries.is_empty()
+        });
+        self.pruned_len = self.resources.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::kv;
+
+    fn resource(service: &str) -> Arc<Resource> {
+        Arc::new(Resource {
+            attributes: vec![kv("service.name", service)],
+            dropped_attributes_count: 0,
+        })
+    }
+
+    #[test]
+    fn test_intern() {
+        let mut interner = ResourceInterner::default();
+        let a = interner.intern(resource("a"));
+
+        // Identical resources from another export are replaced with the interned one.
+        assert!(Arc::ptr_eq(&interner.intern(resource("a")), &a));
+        let b = interner.intern(resource("b"));
+        assert!(!Arc::ptr_eq(&a, &b));
+        assert!(Arc::ptr_eq(&interner.intern(resource("b")), &b));
+
+        // Once dropped, the next identical one is interned afresh.
+        drop(a);
+        interner.intern(resource("c"));
+        let a = resource("a");
+        assert!(Arc::ptr_eq(&interner.intern(a.clone()), &a));
+    }
+}
@@ -4,8 +4,8 @@ use serde::Serialize;
 
 use crate::{
     proto::{
-        common::v1::{any_value, AnyValue, KeyValue},
-        trace::v1::Span,
+        common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue},
+        trace::v1::{span::SpanKind, status::StatusCode, Span},
     },
     trace::SpanValue,
 };
@@ -14,22 +14,42 @@ fn hex(bytes: &[u8]) -> String {
     ::hex::encode(bytes)
 }
 
-fn any_value_to_serde_value(value: any_value::Value) -> serde_json::Value {
+/// Convert the value into a structured JSON value, keeping nested arrays and maps as is.
+fn any_value_to_json(value: any_value::Value) -> serde_json::Value {
+    match value {
+        // Keep empty elements as `null` so that the positions of the others are preserved.
+        any_value::Value::ArrayValue(a) => a
+            .values
+            .into_iter()
+            .map(|v| v.value.map(any_value_to_json).unwrap_or_default())
+            .collect::<Vec<_>>()
+            .into(),
+        any_value::Value::KvlistValue(kv) => kv
+            .values
+            .into_iter()
+            .map(|kv| {
+                let value = (kv.value.and_then(|v| v.value))
+                    .map(any_value_to_json)
+                    .unwrap_or_default();
+                (kv.key, value)
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        other => any_value_to_serde_value(other),
+    }
+}
+
+pub(crate) fn any_value_to_serde_value(value: any_value::Value) -> serde_json::Value {
     match value {
         any_value::Value::StringValue(s) => s.into(),
         any_value::Value::BoolValue(b) => b.into(),
         any_value::Value::IntValue(i) => i.into(),
         any_value::Value::DoubleValue(d) => d.into(),
-        any_value::Value::ArrayValue(a) => {
-            let v = a
-                .values
-                .into_iter()
-                .flat_map(|v| v.value.map(any_value_to_serde_value))
-                .collect::<Vec<_>>();
-            serde_json::Value::Array(v).to_string().into()
+        // Jaeger tags only support scalar values, so encode the structured ones as JSON strings.
+        any_value::Value::ArrayValue(_) | any_value::Value::KvlistValue(_) => {
+            any_value_to_json(value).to_string().into()
         }
         any_value::Value::BytesValue(b) => hex(&b).into(),
-        any_value::Value::KvlistValue(_) => "unsupported".into(),
     }
 }
 
@@ -41,6 +61,32 @@ pub(crate) struct JaegerKv {
     pub value: serde_json::Value,
 }
 
+impl JaegerKv {
+    fn string(key: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            r#type: "string",
+            value: value.into().into(),
+        }
+    }
+
+    fn int64(key: impl Into<String>, value: i64) -> Self {
+        Self {
+            key: key.into(),
+            r#type: "int64",
+            value: value.into(),
+        }
+    }
+
+    fn bool(key: impl Into<String>, value: bool) -> Self {
+        Self {
+            key: key.into(),
+            r#type: "bool",
+            value: value.into(),
+        }
+    }
+}
+
 impl From<KeyValue> for JaegerKv {
     fn from(kv: KeyValue) -> Self {
         let key = kv.key;
@@ -93,16 +139,62 @@ impl From<&SpanValue> for JaegerProcess {
     }
 }
 
-pub(crate) fn span_to_jaeger_json(span: Span, process: String) -> serde_json::Value {
+/// The sampled bit, which is the same in both the W3C trace flags and the Jaeger flags. Other
+/// bits have different meanings, so they are not carried over.
+const SAMPLED_FLAG: u32 = 0x01;
+
+/// The name of the span events recording exceptions, following the semantic conventions.
+const EXCEPTION_EVENT: &str = "exception";
+
+/// Convert the attributes of an `exception` event into the log fields of the OpenTracing
+/// conventions, which are recognized by the Jaeger UI.
+fn exception_fields(attributes: Vec<KeyValue>) -> Vec<JaegerKv> {
+    // The `message` added from the event name is replaced by the exception message.
+    let has_message = attributes.iter().any(|kv| kv.key == "exception.message");
+    let mut fields = vec![JaegerKv::string("event", "error")];
+
+    for kv in attributes {
+        let key = match kv.key.as_str() {
+            "exception.type" => "error.kind",
+            "exception.message" => "message",
+            "exception.stacktrace" => "stack",
+            "message" if has_message => continue,
+            _ => {
+                fields.push(JaegerKv::from(kv));
+                continue;
+            }
+        };
+        fields.push(JaegerKv::from(KeyValue {
+            key: key.to_owned(),
+            value: kv.value,
+        }));
+    }
+
+    fields
+}
+
+/// The tag of the self time of a span, i.e., the duration not covered by its children.
+const SELF_TIME_TAG: &str = "self_time_us";
+
+pub(crate) fn span_to_jaeger_json(
+    span: Span,
+    scope: &InstrumentationScope,
+    process: String,
+    self_time_nanos: Option<u64>,
+) -> serde_json::Value {
+    let has_exception = span.events.iter().any(|e| e.name == EXCEPTION_EVENT);
+
     let logs = span
         .events
         .into_iter()
         .map(|e| {
-            let fields = e
-                .attributes
-                .into_iter()
-                .map(JaegerKv::from)
-                .collect::<Vec<_>>();
+            let fields = if e.name == EXCEPTION_EVENT {
+                exception_fields(e.attributes)
+            } else {
+                (e.attributes.into_iter())
+                    .map(JaegerKv::from)
+                    .collect::<Vec<_>>()
+            };
 
             let timestamp = e.time_unix_nano / 1000;
 
@@ -113,49 +205,395 @@ pub(crate) fn span_to_jaeger_json(span: Span, process: String) -> serde_json::Va
         })
         .collect::<Vec<_>>();
 
-    let tags = span
+    let mut tags = span
         .attributes
         .into_iter()
         .map(JaegerKv::from)
         .collect::<Vec<_>>();
 
-    let mut references = if span.links.is_empty() {
-        None
-    } else {
-        Some(
-            span.links
-                .into_iter()
-                .map(|link| {
-                    serde_json::json!({
-                        "refType": "FOLLOWS_FROM",
-                        "traceID": hex(&link.trace_id),
-                        "spanID": hex(&link.span_id)
-                    })
-                })
-                .collect::<Vec<_>>(),
-        )
+    let kind = match SpanKind::try_from(span.kind).unwrap_or_default() {
+        SpanKind::Unspecified => None,
+        SpanKind::Internal => Some("internal"),
+        SpanKind::Server => Some("server"),
+        SpanKind::Client => Some("client"),
+        SpanKind::Producer => Some("producer"),
+        SpanKind::Consumer => Some("consumer"),
     };
 
+    if let Some(kind) = kind {
+        tags.push(JaegerKv::string("span.kind", kind));
+    }
+
+    if !scope.name.is_empty() {
+        tags.push(JaegerKv::string("otel.library.name", scope.name.clone()));
+    }
+    if !scope.version.is_empty() {
+        tags.push(JaegerKv::string(
+            "otel.library.version",
+            scope.version.clone(),
+        ));
+    }
+
+    // Only tag errors, so that they're highlighted in the UI. Ok and unset are left untagged
+    // following the Jaeger conventions.
+    if let Some(status) = &span.status {
+        if status.code() == StatusCode::Error {
+            tags.push(JaegerKv::bool("error", true));
+            tags.push(JaegerKv::string("otel.status_code", "ERROR"));
+            if !status.message.is_empty() {
+                tags.push(JaegerKv::string(
+                    "otel.status_description",
+                    status.message.clone(),
+                ));
+            }
+        }
+    }
+
+    // Exceptions are errors even if the status is not set.
+    if has_exception && !tags.iter().any(|t| t.key == "error") {
+        tags.push(JaegerKv::bool("error", true));
+    }
+
+    if let Some(self_time) = self_time_nanos {
+        let self_time = i64::try_from(self_time / 1000).unwrap_or(i64::MAX);
+        tags.push(JaegerKv::int64(SELF_TIME_TAG, self_time));
+    }
+
+    let mut references = Vec::with_capacity(span.links.len() + 1);
+
+    // The parent must come first, as the UI may take the first reference as the parent.
     if !span.parent_span_id.is_empty() {
-        let val = serde_json::json!({
+        references.push(serde_json::json!({
             "refType": "CHILD_OF",
             "traceID": hex(&span.trace_id),
             "spanID": hex(&span.parent_span_id),
-        });
-        references.get_or_insert_with(Vec::new).push(val);
+        }));
     }
 
+    // A link within the same trace points to a span this one causally depends on, so it's
+    // `CHILD_OF` like the parent, while a link to another trace is `FOLLOWS_FROM`. The rest of
+    // the context is kept in the tags.
+    references.extend(span.links.into_iter().map(|link| {
+        let ref_type = if link.trace_id == span.trace_id {
+            "CHILD_OF"
+        } else {
+            "FOLLOWS_FROM"
+        };
+        let mut tags = link
+            .attributes
+            .into_iter()
+            .map(JaegerKv::from)
+            .collect::<Vec<_>>();
+        if !link.trace_state.is_empty() {
+            tags.push(JaegerKv::string("w3c.tracestate", link.trace_state));
+        }
+
+        serde_json::json!({
+            "refType": ref_type,
+            "traceID": hex(&link.trace_id),
+            "spanID": hex(&link.span_id),
+            "tags": tags,
+        })
+    }));
+
+    let references = (!references.is_empty()).then_some(references);
+
+    // Clamp the duration to zero for a buggy producer, and let the user know in the UI.
+    let warnings = (span.end_time_unix_nano < span.start_time_unix_nano)
+        .then(|| vec!["span ends before it starts, duration clamped to zero"]);
+
     serde_json::json!({
         "traceID": hex(&span.trace_id),
         "spanID": hex(&span.span_id),
         "startTime": span.start_time_unix_nano / 1000,
-        "duration": (span.end_time_unix_nano - span.start_time_unix_nano) / 1000,
+        "duration": (span.end_time_unix_nano).saturating_sub(span.start_time_unix_nano) / 1000,
         "operationName": span.name,
         "tags": tags,
         "logs": logs,
-        // "flags": span.flags,
+        "flags": span.flags & SAMPLED_FLAG,
         "processID": process,
-        "warnings": None::<String>,
+        "warnings": warnings,
         "references": references,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        proto::{
+            common::v1::{ArrayValue, KeyValueList},
+            trace::v1::{
+                span::{Event, Link},
+                Status,
+            },
+        },
+        test_util::{kv, span, span_id, trace_id, START},
+    };
+
+    fn value(value: any_value::Value) -> Option<AnyValue> {
+        Some(AnyValue { value: Some(value) })
+    }
+
+    /// Convert the span and get its tags as `(key, value)` pairs.
+    fn tags(span: Span) -> Vec<(String, serde_json::Value)> {
+        let json = span_to_jaeger_json(span, &InstrumentationScope::default(), "p1".into(), None);
+        (json["tags"].as_array().unwrap().iter())
+            .map(|t| (t["key"].as_str().unwrap().to_owned(), t["value"].clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_kvlist_tag() {
+        let kv = KeyValue {
+            key: "map".to_owned(),
+            value: value(any_value::Value::KvlistValue(KeyValueList {
+                values: vec![
+                    KeyValue {
+                        key: "a".to_owned(),
+                        value: value(any_value::Value::IntValue(1)),
+                    },
+                    KeyValue {
+                        key: "b".to_owned(),
+                        value: value(any_value::Value::ArrayValue(ArrayValue {
+                            values: vec![AnyValue {
+                                value: Some(any_value::Value::StringValue("x".to_owned())),
+                            }],
+                        })),
+                    },
+                ],
+            })),
+        };
+
+        let tag = JaegerKv::from(kv);
+        assert_eq!(tag.r#type, "string");
+        let decoded: serde_json::Value = serde_json::from_str(tag.value.as_str().unwrap()).unwrap();
+        assert_eq!(decoded, json!({ "a": 1, "b": ["x"] }));
+    }
+
+    #[test]
+    fn test_status_tags() {
+        let mut error = span(1, 1, None);
+        error.status = Some(Status {
+            message: "boom".to_owned(),
+            code: StatusCode::Error as i32,
+        });
+        assert_eq!(
+            tags(error),
+            [
+                ("error".to_owned(), json!(true)),
+                ("otel.status_code".to_owned(), json!("ERROR")),
+                ("otel.status_description".to_owned(), json!("boom")),
+            ]
+        );
+
+        let mut ok = span(1, 1, None);
+        ok.status = Some(Status {
+            message: String::new(),
+            code: StatusCode::Ok as i32,
+        });
+        assert!(tags(ok).is_empty());
+    }
+
+    #[test]
+    fn test_span_kind_tag() {
+        for (kind, tag) in [
+            (SpanKind::Internal, "internal"),
+            (SpanKind::Server, "server"),
+            (SpanKind::Client, "client"),
+            (SpanKind::Producer, "producer"),
+            (SpanKind::Consumer, "consumer"),
+        ] {
+            let mut span = span(1, 1, None);
+            span.kind = kind as i32;
+            assert_eq!(tags(span), [("span.kind".to_owned(), json!(tag))]);
+        }
+
+        // Unspecified kind is omitted.
+        let mut unspecified = span(1, 1, None);
+        unspecified.kind = SpanKind::Unspecified as i32;
+        assert!(tags(unspecified).is_empty());
+    }
+
+    #[test]
+    fn test_references() {
+        let mut span = span(1, 2, Some(1));
+        span.links = vec![
+            Link {
+                trace_id: trace_id(3),
+                span_id: span_id(4),
+                trace_state: "k=v".to_owned(),
+                attributes: vec![kv("reason", "retry")],
+                dropped_attributes_count: 0,
+                flags: 0,
+            },
+            Link {
+                trace_id: trace_id(1),
+                span_id: span_id(5),
+                ..Default::default()
+            },
+        ];
+        let json = span_to_jaeger_json(span, &InstrumentationScope::default(), "p1".into(), None);
+
+        assert_eq!(
+            json["references"],
+            json!([
+                {
+                    "refType": "CHILD_OF",
+                    "traceID": hex(&trace_id(1)),
+                    "spanID": hex(&span_id(1)),
+                },
+                {
+                    "refType": "FOLLOWS_FROM",
+                    "traceID": hex(&trace_id(3)),
+                    "spanID": hex(&span_id(4)),
+                    "tags": [
+                        { "key": "reason", "type": "string", "value": "retry" },
+                        { "key": "w3c.tracestate", "type": "string", "value": "k=v" },
+                    ],
+                },
+                {
+                    "refType": "CHILD_OF",
+                    "traceID": hex(&trace_id(1)),
+                    "spanID": hex(&span_id(5)),
+                    "tags": [],
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_scope_tags() {
+        let scope = InstrumentationScope {
+            name: "my-lib".to_owned(),
+            version: "1.0".to_owned(),
+            ..Default::default()
+        };
+        let json = span_to_jaeger_json(span(1, 1, None), &scope, "p1".into(), None);
+        let scope_tags = (json["tags"].as_array().unwrap().iter())
+            .filter(|t| t["key"].as_str().unwrap().starts_with("otel.library."))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            scope_tags,
+            [
+                json!({ "key": "otel.library.name", "type": "string", "value": "my-lib" }),
+                json!({ "key": "otel.library.version", "type": "string", "value": "1.0" }),
+            ]
+        );
+
+        // Nothing for an empty scope.
+        let tags = tags(span(1, 1, None));
+        assert!(!tags.iter().any(|(k, _)| k.starts_with("otel.library.")));
+    }
+
+    #[test]
+    fn test_negative_duration_warning() {
+        let to_json =
+            |span| span_to_jaeger_json(span, &InstrumentationScope::default(), "p1".into(), None);
+
+        let mut negative = span(1, 1, None);
+        negative.end_time_unix_nano = negative.start_time_unix_nano - 1;
+        let negative = to_json(negative);
+        assert_eq!(negative["duration"], 0);
+        assert_eq!(
+            negative["warnings"],
+            json!(["span ends before it starts, duration clamped to zero"])
+        );
+
+        assert_eq!(
+            to_json(span(1, 1, None))["warnings"],
+            serde_json::Value::Null
+        );
+    }
+
+    #[test]
+    fn test_array_tag() {
+        let kv = KeyValue {
+            key: "array".to_owned(),
+            value: value(any_value::Value::ArrayValue(ArrayValue {
+                values: vec![
+                    AnyValue {
+                        value: Some(any_value::Value::IntValue(1)),
+                    },
+                    AnyValue { value: None },
+                    AnyValue {
+                        value: Some(any_value::Value::BoolValue(true)),
+                    },
+                ],
+            })),
+        };
+
+        let tag = JaegerKv::from(kv);
+        assert_eq!(tag.r#type, "string");
+        let decoded: serde_json::Value = serde_json::from_str(tag.value.as_str().unwrap()).unwrap();
+        // The empty element keeps its position.
+        assert_eq!(decoded, json!([1, null, true]));
+
+        let array = |values: Vec<i64>| KeyValue {
+            key: "array".to_owned(),
+            value: value(any_value::Value::ArrayValue(ArrayValue {
+                values: (values.into_iter())
+                    .map(|i| AnyValue {
+                        value: Some(any_value::Value::IntValue(i)),
+                    })
+                    .collect(),
+            })),
+        };
+
+        let tag = JaegerKv::from(array(vec![1, 2, 3]));
+        assert_eq!(tag.r#type, "string");
+        assert_eq!(tag.value, json!("[1,2,3]"));
+
+        let tag = JaegerKv::from(array(vec![]));
+        assert_eq!(tag.r#type, "string");
+        assert_eq!(tag.value, json!("[]"));
+    }
+
+    #[test]
+    fn test_exception_event() {
+        let mut span = span(1, 1, None);
+        span.events = vec![Event {
+            time_unix_nano: START,
+            name: "exception".to_owned(),
+            attributes: vec![
+                kv("message", "exception"),
+                kv("exception.type", "IOError"),
+                kv("exception.message", "disk full"),
+                kv("exception.stacktrace", "at main()"),
+                kv("thread", "main"),
+            ],
+            dropped_attributes_count: 0,
+        }];
+        let json = span_to_jaeger_json(span, &InstrumentationScope::default(), "p1".into(), None);
+
+        assert_eq!(
+            json["logs"][0]["fields"],
+            json!([
+                { "key": "event", "type": "string", "value": "error" },
+                { "key": "error.kind", "type": "string", "value": "IOError" },
+                { "key": "message", "type": "string", "value": "disk full" },
+                { "key": "stack", "type": "string", "value": "at main()" },
+                { "key": "thread", "type": "string", "value": "main" },
+            ])
+        );
+        // Marked as an error even without the status.
+        let tags = json["tags"].as_array().unwrap();
+        assert!(tags.contains(&json!({ "key": "error", "type": "bool", "value": true })));
+    }
+
+    #[test]
+    fn test_flags() {
+        let to_json =
+            |span| span_to_jaeger_json(span, &InstrumentationScope::default(), "p1".into(), None);
+
+        assert_eq!(to_json(span(1, 1, None))["flags"], 0);
+
+        // Only the sampled bit is carried over, not the remote bits of the context.
+        let mut sampled = span(1, 1, None);
+        sampled.flags = 0x01 | 0x100 | 0x200;
+        assert_eq!(to_json(sampled)["flags"], 1);
+    }
+}
@@ -1,70 +1,585 @@
-use std::cmp::Reverse;
+use std::{cmp::Reverse, collections::BTreeMap, sync::Arc};
 
 use axum::{
-    extract::{Path, Query},
-    http::{header, StatusCode, Uri},
+    async_trait,
+    extract::{
+        self,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        FromRequestParts, Request,
+    },
+    http::{header, request::Parts, HeaderMap, StatusCode, Uri},
+    middleware::{self, Next},
     response::{Html, IntoResponse, Response},
-    routing::get,
+    routing::{get, post},
     Extension, Json, Router,
 };
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use itertools::Itertools;
+use prost::Message as _;
 use rust_embed::RustEmbed;
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize, Serializer};
 use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tower_http::{compression::CompressionLayer, cors::CorsLayer, trace::TraceLayer};
 
-use crate::StateRef;
+use crate::{StateRef, Trace};
 
-// TODO: make `base_path` optional.
 /// Create a new [`axum::Router`] for the Jaeger UI to visualize the traces
 /// stored in the given [`StateRef`].
 ///
 /// The `base_path` is used for the application to load static assets correctly.
-/// It should start and end with `/`. For example,
+/// See [`UiBuilder::base_path`] for details.
 ///
-/// - if the application is served at `http://localhost:3000/`, then `base_path`
-///   should be `/`.
-/// - if the application is served at `http://localhost:3000/trace/`, then
-///   `base_path` should be `/trace/`.
-pub fn app(state: StateRef, base_path: &str) -> Router {
-    if !base_path.starts_with('/') || !base_path.ends_with('/') {
-        panic!("base_path must start and end with /");
+/// Use [`UiBuilder`] for more options.
+pub fn app(state: StateRef, base_path: Option<&str>) -> Router {
+    UiBuilder::new(state).base_path(base_path).build()
+}
+
+/// Same as [`app`], but with the given [`CorsLayer`] applied.
+/// See [`UiBuilder::with_cors`] for details.
+pub fn app_with_cors(state: StateRef, base_path: Option<&str>, cors: CorsLayer) -> Router {
+    UiBuilder::new(state)
+        .base_path(base_path)
+        .with_cors(cors)
+        .build()
+}
+
+/// Builder for the [`axum::Router`] of the Jaeger UI to visualize the traces
+/// stored in the given [`StateRef`].
+///
+/// Besides the UI and its API, the router also serves a health check at `/healthz`
+/// for liveness and readiness probes.
+///
+/// ```no_run
+/// # use otlp_embedded::{CorsLayer, State, UiBuilder};
+/// let state = State::with_capacity(100);
+/// let router = UiBuilder::new(state)
+///     .base_path("/trace/")
+///     .with_metrics(false)
+///     .with_cors(CorsLayer::permissive())
+///     .build();
+/// ```
+pub struct UiBuilder {
+    state: StateRef,
+    base_path: Option<String>,
+    title: Option<String>,
+    metrics: bool,
+    max_limit: usize,
+    auth_token: Option<String>,
+    cors: Option<CorsLayer>,
+}
+
+impl UiBuilder {
+    /// The default maximum number of traces returned by a single search, which is 1500.
+    pub const DEFAULT_MAX_LIMIT: usize = 1500;
+
+    /// Create a new [`UiBuilder`] with the default options.
+    pub fn new(state: StateRef) -> Self {
+        Self {
+            state,
+            base_path: None,
+            title: None,
+            metrics: true,
+            max_limit: Self::DEFAULT_MAX_LIMIT,
+            auth_token: None,
+            cors: None,
+        }
+    }
+
+    /// Set the base path for the application to load static assets correctly.
+    /// For example,
+    ///
+    /// - if the application is served at `http://localhost:3000/`, then `base_path`
+    ///   should be `None` or `Some("/")`, which is the default.
+    /// - if the application is served at `http://localhost:3000/trace/`, then
+    ///   `base_path` should be `Some("/trace/")`.
+    ///
+    /// Missing leading or trailing slashes will be inserted automatically.
+    ///
+    /// The application is served both at the root and under the base path, so that it works
+    /// no matter whether the reverse proxy strips the prefix or not.
+    pub fn base_path<'a>(mut self, base_path: impl Into<Option<&'a str>>) -> Self {
+        self.base_path = base_path.into().map(normalize_base_path);
+        self
+    }
+
+    /// Set the title of the page, which is `Jaeger UI` by default.
+    pub fn title(mut self, title: impl Into<String>) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    /// Whether to serve the self-metrics in Prometheus text format at `/metrics`.
+    ///
+    /// Enabled by default.
+    pub fn with_metrics(mut self, metrics: bool) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Set the maximum number of traces returned by a single search. Larger `limit`s
+    /// requested by the client are clamped to this value.
+    pub fn max_limit(mut self, max_limit: usize) -> Self {
+        self.max_limit = max_limit;
+        self
+    }
+
+    /// Require the `Authorization: Bearer <token>` header with the given token for all API
+    /// requests under `/api/`, responding `401 Unauthorized` otherwise. Disabled by default.
+    ///
+    /// As browsers cannot attach the header to a websocket, `/api/traces/stream` also accepts
+    /// the token in the `access_token` query parameter, e.g.,
+    /// `/api/traces/stream?access_token=<token>`. Note that the URL may end up in the logs of
+    /// proxies, so prefer the header where possible.
+    ///
+    /// The static assets of the UI, `/healthz` and `/metrics` are still served without
+    /// the token, as browsers cannot attach the header when loading the page.
+    pub fn with_auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// Apply the given [`CorsLayer`] to the UI and the API, so that they can be accessed
+    /// from other origins. Disabled by default.
+    ///
+    /// For local development, [`CorsLayer::permissive`] can be used. For production, prefer
+    /// allowing specific origins only, e.g.,
+    ///
+    /// ```no_run
+    /// # use axum::http::{header, HeaderValue, Method};
+    /// # use otlp_embedded::{CorsLayer, State, UiBuilder};
+    /// # let state = State::with_capacity(100);
+    /// let cors = CorsLayer::new()
+    ///     .allow_origin("https://example.com".parse::<HeaderValue>().unwrap())
+    ///     .allow_methods([Method::GET, Method::POST])
+    ///     .allow_headers([header::CONTENT_TYPE]);
+    /// let router = UiBuilder::new(state).with_cors(cors).build();
+    /// ```
+    pub fn with_cors(mut self, cors: CorsLayer) -> Self {
+        self.cors = Some(cors);
+        self
+    }
+
+    /// Build the [`axum::Router`].
+    pub fn build(self) -> Router {
+        let Self {
+            state,
+            base_path,
+            title,
+            metrics: enable_metrics,
+            max_limit,
+            auth_token,
+            cors,
+        } = self;
+
+        let base_path = base_path.unwrap_or_else(|| "/".to_owned());
+        let index_html = render_index_html(&base_path, title.as_deref());
+
+        let api = Router::new()
+            .route("/traces/:hex_id", get(trace).delete(remove))
+            .route("/traces/stream", get(stream))
+            .route("/traces/:hex_id/download", get(download))
+            .route("/traces/:hex_id/tempo", get(tempo))
+            .route("/traces/:hex_id/otlp", get(otlp))
+            .route("/traces/:hex_id/status", get(status))
+            .route("/services", get(services))
+            .route("/services/grouped", get(services_grouped))
+            .route("/services/:service/operations", get(operations))
+            .route("/services/:service/stats", get(stats))
+            .route("/trace-duration-histogram", get(duration_histogram))
+            .route(
+                "/traces",
+                get(move |query, params, state| traces(query, params, state, max_limit)),
+            )
+            .route("/clear", post(clear))
+            .route("/diagnostics", get(diagnostics))
+            .route("/debug/memory", get(memory))
+            .route("/tag-keys", get(tag_keys))
+            .route("/tag-values/:key", get(tag_values))
+            .fallback(|_: Uri| async move { not_found_with_msg("API not supported") });
+        let api = match auth_token {
+            Some(token) => {
+                let token: Arc<str> = token.into();
+                api.layer(middleware::from_fn(move |req: Request, next: Next| {
+                    let token = token.clone();
+                    async move {
+                        if is_authorized(&req, &token) {
+                            next.run(req).await
+                        } else {
+                            error_with_msg(StatusCode::UNAUTHORIZED, "Unauthorized")
+                        }
+                    }
+                }))
+            }
+            None => api,
+        };
+
+        let mut router = Router::new()
+            .nest("/api/", api)
+            .route("/healthz", get(healthz));
+        if enable_metrics {
+            router = router.route("/metrics", get(metrics));
+        }
+        let router = router
+            .layer(Extension(state))
+            .fallback(|uri| async move { static_handler(uri, &index_html).await });
+
+        // Also serve everything under the base path, in case a reverse proxy forwards the
+        // requests without stripping the prefix.
+        let router = if base_path == "/" {
+            router
+        } else {
+            Router::new()
+                .nest(base_path.trim_end_matches('/'), router.clone())
+                .merge(router)
+        };
+        // Compress the responses if accepted by the client, as the JSON of a large trace can be
+        // megabytes. Already-compressed formats like images are skipped by the default predicate.
+        let router = router
+            .layer(CompressionLayer::new())
+            .layer(TraceLayer::new_for_http());
+
+        match cors {
+            Some(cors) => router.layer(cors),
+            None => router,
+        }
+    }
+}
+
+/// Check whether the request carries the expected token, either in the `Authorization`
+/// header, or in the `access_token` query parameter for the websocket stream.
+fn is_authorized(req: &Request, token: &str) -> bool {
+    let from_header = (req.headers().get(header::AUTHORIZATION))
+        .and_then(|v| v.as_bytes().strip_prefix(b"Bearer "))
+        .is_some_and(|v| constant_time_eq(v, token.as_bytes()));
+    if from_header {
+        return true;
+    }
+
+    // The path is relative to `/api/` as the router is nested.
+    if req.uri().path() != "/traces/stream" {
+        return false;
+    }
+    let query = extract::Query::<BTreeMap<String, String>>::try_from_uri(req.uri());
+    query.is_ok_and(|extract::Query(params)| {
+        (params.get("access_token"))
+            .is_some_and(|v| constant_time_eq(v.as_bytes(), token.as_bytes()))
+    })
+}
+
+/// Compare the bytes in constant time regarding the content, so that the token cannot be
+/// guessed byte by byte from the response time. The length is not protected.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Ensure the base path starts and ends with `/`.
+fn normalize_base_path(base_path: &str) -> String {
+    let trimmed = base_path.trim_matches('/');
+    if trimmed.is_empty() {
+        "/".to_owned()
+    } else {
+        format!("/{trimmed}/")
+    }
+}
+
+/// Decode a trace ID in hex, which is the canonical format, or in URL-safe base64 as used by
+/// some OTLP/JSON clients.
+///
+/// A trace ID is either 8 or 16 bytes, i.e., 16 or 32 characters in hex, or 11 or 22 in
+/// base64 without padding. The lengths don't overlap, so the format is never ambiguous.
+fn decode_trace_id(id: &str) -> Result<Vec<u8>, String> {
+    if matches!(id.len(), 16 | 32) {
+        if let Ok(id) = hex::decode(id) {
+            return Ok(id);
+        }
+    }
+    match URL_SAFE_NO_PAD.decode(id.trim_end_matches('=')) {
+        Ok(id) if matches!(id.len(), 8 | 16) => Ok(id),
+        _ => Err(format!("Invalid trace ID {id}: expected hex or base64")),
+    }
+}
+
+/// A trace in the Jaeger batch format, serialized directly into the response body.
+#[derive(Serialize)]
+struct JaegerBatch<T> {
+    data: [T; 1],
+}
+
+/// The trace annotated with its critical path, which is highlighted in the UI.
+struct WithCriticalPath<'a>(&'a Trace);
+
+impl Serialize for WithCriticalPath<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.0).serialize_jaeger(serializer, Some(&self.0.critical_path()))
     }
-    let base_tag = format!(r#"<base href="{base_path}""#);
+}
 
-    let api = Router::new()
-        .route("/traces/:hex_id", get(trace))
-        .route("/services", get(services))
-        .route("/services/:service/operations", get(operations))
-        .route("/traces", get(traces))
-        .layer(Extension(state))
-        .fallback(|_: Uri| async move { not_found_with_msg("API not supported") });
+/// The formats of a trace that can be requested with the `Accept` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TraceFormat {
+    Jaeger,
+    Otlp,
+    Tempo,
+}
 
-    Router::new()
-        .nest("/api/", api)
-        .fallback(|uri| async move { static_handler(uri, &base_tag).await })
+impl TraceFormat {
+    /// Pick the first supported media type in the `Accept` header, ignoring the quality
+    /// values. Falls back to Jaeger if none is supported.
+    fn from_accept(headers: &HeaderMap) -> Self {
+        let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+            return Self::Jaeger;
+        };
+        (accept.split(','))
+            .map(|media| media.split(';').next().unwrap_or_default().trim())
+            .find_map(|media| match media {
+                "application/json" => Some(Self::Jaeger),
+                "application/x-protobuf" => Some(Self::Otlp),
+                "application/vnd.tempo+json" => Some(Self::Tempo),
+                _ => None,
+            })
+            .unwrap_or(Self::Jaeger)
+    }
 }
 
+/// Get the trace in the Jaeger format, or the OTLP or Tempo format if requested with the
+/// `Accept` header.
 async fn trace(
     Path(hex_id): Path<String>,
     Extension(state): Extension<StateRef>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let id = match decode_trace_id(&hex_id) {
+        Ok(id) => id,
+        Err(msg) => return bad_request_with_msg(msg),
+    };
+    let trace = state.read().await.get_by_id(&id);
+
+    if let Some(trace) = trace {
+        match TraceFormat::from_accept(&headers) {
+            TraceFormat::Jaeger => {
+                let batch = JaegerBatch {
+                    data: [WithCriticalPath(&trace)],
+                };
+                Json(batch).into_response()
+            }
+            TraceFormat::Otlp => (
+                [(header::CONTENT_TYPE, "application/x-protobuf")],
+                trace.to_otlp_request().encode_to_vec(),
+            )
+                .into_response(),
+            TraceFormat::Tempo => (
+                [(header::CONTENT_TYPE, "application/vnd.tempo+json")],
+                Json(trace.to_tempo_batch()),
+            )
+                .into_response(),
+        }
+    } else {
+        not_found_with_msg(format!("Trace {hex_id} not found, maybe expired."))
+    }
+}
+
+/// Stream the summaries of the newly completed traces over a websocket, in the same format
+/// as [`Trace::to_jaeger_entry`](crate::Trace::to_jaeger_entry).
+///
+/// If the client is too slow, the oldest pending traces are skipped.
+async fn stream(ws: WebSocketUpgrade, Extension(state): Extension<StateRef>) -> impl IntoResponse {
+    let rx = state.read().await.subscribe();
+    ws.on_upgrade(move |socket| stream_completed(socket, rx, state))
+}
+
+async fn stream_completed(
+    mut socket: WebSocket,
+    mut rx: broadcast::Receiver<Vec<u8>>,
+    state: StateRef,
+) {
+    loop {
+        let id = tokio::select! {
+            id = rx.recv() => match id {
+                Ok(id) => id,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::debug!(skipped, "trace stream lagging behind, skipped");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            // Ignore the messages from the client, until it's closed.
+            msg = socket.recv() => match msg {
+                Some(Ok(msg)) if !matches!(msg, Message::Close(_)) => continue,
+                _ => break,
+            },
+        };
+
+        // The trace may have been evicted in the meantime.
+        let Some(trace) = state.read().await.peek_by_id(&id) else {
+            continue;
+        };
+        let entry = trace.to_jaeger_entry().to_string();
+        if socket.send(Message::Text(entry)).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn remove(
+    Path(hex_id): Path<String>,
+    Extension(state): Extension<StateRef>,
+) -> impl IntoResponse {
+    let id = match decode_trace_id(&hex_id) {
+        Ok(id) => id,
+        Err(msg) => return bad_request_with_msg(msg),
+    };
+
+    if state.read().await.remove_by_id(&id) {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        not_found_with_msg(format!("Trace {hex_id} not found, maybe expired."))
+    }
+}
+
+async fn download(
+    Path(hex_id): Path<String>,
+    Extension(state): Extension<StateRef>,
+) -> impl IntoResponse {
+    let id = match decode_trace_id(&hex_id) {
+        Ok(id) => id,
+        Err(msg) => return bad_request_with_msg(msg),
+    };
+    let trace = state.read().await.get_by_id(&id);
+
+    if let Some(trace) = trace {
+        let disposition = format!(r#"attachment; filename="{}.json""#, trace.hex_id());
+        (
+            [
+                (header::CONTENT_TYPE, "application/json".to_owned()),
+                (header::CONTENT_DISPOSITION, disposition),
+            ],
+            Json(JaegerBatch { data: [&trace] }),
+        )
+            .into_response()
+    } else {
+        not_found_with_msg(format!("Trace {hex_id} not found, maybe expired."))
+    }
+}
+
+async fn tempo(
+    Path(hex_id): Path<String>,
+    Extension(state): Extension<StateRef>,
+) -> impl IntoResponse {
+    let id = match decode_trace_id(&hex_id) {
+        Ok(id) => id,
+        Err(msg) => return bad_request_with_msg(msg),
+    };
+    let trace = state.read().await.get_by_id(&id);
+
+    if let Some(trace) = trace {
+        Json(trace.to_tempo_batch()).into_response()
+    } else {
+        not_found_with_msg(format!("Trace {hex_id} not found, maybe expired."))
+    }
+}
+
+async fn otlp(
+    Path(hex_id): Path<String>,
+    Extension(state): Extension<StateRef>,
 ) -> impl IntoResponse {
-    let id = hex::decode(&hex_id).unwrap_or_default();
-    let trace = state.write().await.get_by_id(&id);
+    let id = match decode_trace_id(&hex_id) {
+        Ok(id) => id,
+        Err(msg) => return bad_request_with_msg(msg),
+    };
+    let trace = state.read().await.get_by_id(&id);
 
     if let Some(trace) = trace {
-        Json(trace.to_jaeger_batch()).into_response()
+        (
+            [(header::CONTENT_TYPE, "application/x-protobuf")],
+            trace.to_otlp_request().encode_to_vec(),
+        )
+            .into_response()
     } else {
         not_found_with_msg(format!("Trace {hex_id} not found, maybe expired."))
     }
 }
 
-async fn services(Extension(state): Extension<StateRef>) -> impl IntoResponse {
+/// Get whether the trace is complete, and the IDs of the parent spans not received yet.
+async fn status(
+    Path(hex_id): Path<String>,
+    Extension(state): Extension<StateRef>,
+) -> impl IntoResponse {
+    let id = match decode_trace_id(&hex_id) {
+        Ok(id) => id,
+        Err(msg) => return bad_request_with_msg(msg),
+    };
+    let trace = state.read().await.peek_by_id(&id);
+
+    if let Some(trace) = trace {
+        let missing_parents = trace
+            .missing_span_ids()
+            .iter()
+            .map(hex::encode)
+            .collect_vec();
+        Json(json!({
+            "complete": trace.is_complete(),
+            "span_count": trace.span_count(),
+            "missing_parents": missing_parents,
+        }))
+        .into_response()
+    } else {
+        not_found_with_msg(format!("Trace {hex_id} not found, maybe expired."))
+    }
+}
+
+#[derive(Deserialize)]
+struct ServicesQuery {
+    /// Whether to include the number of complete traces of each service.
+    #[serde(default)]
+    counts: bool,
+}
+
+async fn services(
+    Query(query): Query<ServicesQuery>,
+    Extension(state): Extension<StateRef>,
+) -> impl IntoResponse {
     let state = state.read().await;
     let all_services = state.get_all_services();
     let len = all_services.len();
 
+    let data = if query.counts {
+        let counts = state.get_services_with_counts();
+        (all_services.into_iter())
+            .map(|service| {
+                json!({
+                    "service": service,
+                    "count": counts.get(&service).copied().unwrap_or(0),
+                })
+            })
+            .collect_vec()
+            .into()
+    } else {
+        json!(all_services)
+    };
+
+    let res = json!({
+        "data": data,
+        "total": len,
+    });
+
+    Json(res).into_response()
+}
+
+async fn services_grouped(Extension(state): Extension<StateRef>) -> impl IntoResponse {
+    let state = state.read().await;
+    let services = (state.get_all_services_grouped().into_iter())
+        .map(|(service, environment)| {
+            json!({
+                "service": service,
+                "environment": environment,
+            })
+        })
+        .collect_vec();
+    let len = services.len();
+
     let res = json!({
-        "data": all_services,
+        "data": services,
         "total": len,
     });
 
@@ -87,61 +602,363 @@ async fn operations(
     Json(res).into_response()
 }
 
+async fn stats(
+    Path(service): Path<String>,
+    Extension(state): Extension<StateRef>,
+) -> impl IntoResponse {
+    let stats = state.read().await.operation_stats(&service);
+    let len = stats.len();
+
+    // Durations are in microseconds, same as the Jaeger API.
+    let stats = (stats.into_iter())
+        .map(|s| {
+            json!({
+                "name": s.name,
+                "count": s.count,
+                "p50": s.p50.as_micros() as u64,
+                "p95": s.p95.as_micros() as u64,
+                "p99": s.p99.as_micros() as u64,
+                "errorCount": s.error_count,
+            })
+        })
+        .collect_vec();
+
+    let res = json!({
+        "data": stats,
+        "total": len,
+    });
+
+    Json(res).into_response()
+}
+
+#[derive(Deserialize)]
+struct HistogramQuery {
+    service: Option<String>,
+    #[serde(default = "default_buckets")]
+    buckets: usize,
+}
+
+fn default_buckets() -> usize {
+    10
+}
+
+/// The maximum number of buckets, to bound the size of the response.
+const MAX_BUCKETS: usize = 100;
+
+async fn duration_histogram(
+    Query(query): Query<HistogramQuery>,
+    Extension(state): Extension<StateRef>,
+) -> impl IntoResponse {
+    let buckets = (state.read().await)
+        .duration_histogram(query.service.as_deref(), query.buckets.min(MAX_BUCKETS));
+    let len = buckets.len();
+
+    // Durations are in microseconds, same as the Jaeger API.
+    let buckets = (buckets.into_iter())
+        .map(|b| {
+            json!({
+                "start": b.start.as_micros() as u64,
+                "end": b.end.as_micros() as u64,
+                "count": b.count,
+            })
+        })
+        .collect_vec();
+
+    let res = json!({
+        "data": buckets,
+        "total": len,
+    });
+
+    Json(res).into_response()
+}
+
 #[derive(Deserialize)]
 struct TracesQuery {
     service: Option<String>,
     operation: Option<String>,
+    /// The tags to match, as a JSON object of strings, same as Jaeger.
+    tags: Option<String>,
+    /// The minimum number of spans in the trace.
+    #[serde(rename = "minSpans")]
+    min_spans: Option<usize>,
+    /// Whether to include the traces that are not complete yet.
+    #[serde(rename = "includeIncomplete", default)]
+    include_incomplete: bool,
+    #[serde(default = "default_limit")]
     limit: usize,
+    #[serde(default)]
+    offset: usize,
+}
+
+/// The default number of traces returned by a search, same as Jaeger.
+fn default_limit() -> usize {
+    20
 }
 
 async fn traces(
     Query(TracesQuery {
         service,
         operation,
+        tags,
+        min_spans,
+        include_incomplete,
         limit,
+        offset,
     }): Query<TracesQuery>,
+    Query(params): Query<Vec<(String, String)>>,
     Extension(state): Extension<StateRef>,
+    max_limit: usize,
 ) -> impl IntoResponse {
-    let traces = (state.read().await)
-        .get_all_complete()
+    // The `traceID` parameter can be repeated, which is not supported by `TracesQuery`.
+    let hex_ids = (params.into_iter())
+        .filter(|(k, _)| k == "traceID")
+        .map(|(_, v)| v)
+        .collect_vec();
+    if !hex_ids.is_empty() {
+        return traces_by_ids(&hex_ids, state).await;
+    }
+
+    let limit = limit.min(max_limit);
+
+    let tags = match tags.as_deref().map(serde_json::from_str::<BTreeMap<_, _>>) {
+        None => Vec::new(),
+        Some(Ok(tags)) => tags.into_iter().collect_vec(),
+        Some(Err(e)) => return bad_request_with_msg(format!("invalid tags: {e}")),
+    };
+
+    let traces = {
+        let state = state.read().await;
+        if include_incomplete {
+            state.get_all_with_tags(&tags)
+        } else {
+            state.get_all_complete_with_tags(&tags)
+        }
+    };
+    let traces = (traces.into_iter())
         .filter(|t| {
             if let Some(service) = &service {
-                t.service_name().unwrap() == service
+                t.service_name() == Some(service.as_str())
             } else {
                 true
             }
         })
         .filter(|t| {
             if let Some(operation) = &operation {
-                t.operation().unwrap() == operation
+                t.operation() == Some(operation.as_str())
+            } else {
+                true
+            }
+        })
+        .filter(|t| {
+            if let Some(min_spans) = min_spans {
+                t.span_count() >= min_spans
             } else {
                 true
             }
         })
         .sorted_by_cached_key(|t| Reverse(t.end_time))
-        .map(|t| t.to_jaeger())
+        .collect_vec();
+    let total = traces.len();
+
+    let traces = (traces.iter())
+        .skip(offset)
         .take(limit)
+        .map(|t| {
+            let mut trace = t.to_jaeger();
+            // Mark the traces with spans not received yet, which are still in flight or have
+            // been treated as complete due to `force_complete_after`.
+            if let (false, Some(obj)) = (t.is_complete(), trace.as_object_mut()) {
+                obj.insert("incomplete".to_owned(), true.into());
+            }
+            trace
+        })
         .collect_vec();
 
     let mock = json!({
         "data": traces,
-        "total": traces.len(),
+        "total": total,
     });
 
     Json(mock).into_response()
 }
 
+/// Get the traces with the given IDs, same as Jaeger. Traces not found are omitted.
+async fn traces_by_ids(hex_ids: &[String], state: StateRef) -> Response {
+    let ids = match hex_ids
+        .iter()
+        .map(|id| decode_trace_id(id))
+        .try_collect::<_, Vec<_>, _>()
+    {
+        Ok(ids) => ids,
+        Err(msg) => return bad_request_with_msg(msg),
+    };
+
+    let traces = {
+        let state = state.read().await;
+        (ids.iter())
+            .filter_map(|id| state.get_by_id(id))
+            .map(|t| t.to_jaeger())
+            .collect_vec()
+    };
+
+    Json(json!({
+        "data": traces,
+        "total": traces.len(),
+    }))
+    .into_response()
+}
+
+async fn clear(Extension(state): Extension<StateRef>) -> impl IntoResponse {
+    let removed = state.write().await.clear();
+
+    Json(json!({ "removed": removed })).into_response()
+}
+
+async fn diagnostics(Extension(state): Extension<StateRef>) -> impl IntoResponse {
+    let diagnostics = state.read().await.diagnostics();
+
+    Json(json!({
+        "empty_span_id": diagnostics.empty_span_id,
+        "non_positive_duration": diagnostics.non_positive_duration,
+        "implausible_timestamp": diagnostics.implausible_timestamp,
+        "missing_parents": diagnostics.missing_parents,
+        "dropped_forwards": diagnostics.dropped_forwards,
+    }))
+    .into_response()
+}
+
+async fn tag_keys(Extension(state): Extension<StateRef>) -> impl IntoResponse {
+    let keys = state.read().await.all_attribute_keys();
+    let len = keys.len();
+
+    let res = json!({
+        "data": keys,
+        "total": len,
+    });
+
+    Json(res).into_response()
+}
+
+#[derive(Deserialize)]
+struct LimitQuery {
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+async fn tag_values(
+    Path(key): Path<String>,
+    Query(query): Query<LimitQuery>,
+    Extension(state): Extension<StateRef>,
+) -> impl IntoResponse {
+    let values = state.read().await.attribute_values(&key, query.limit);
+    let len = values.len();
+
+    let res = json!({
+        "data": values,
+        "total": len,
+    });
+
+    Json(res).into_response()
+}
+
+/// Get the largest traces by the estimated memory usage, to find out why traces are evicted.
+async fn memory(
+    Query(query): Query<LimitQuery>,
+    Extension(state): Extension<StateRef>,
+) -> impl IntoResponse {
+    let state = state.read().await;
+    let traces = (state.heaviest_traces(query.limit).into_iter())
+        .map(|(id, size)| {
+            json!({
+                "traceID": hex::encode(id),
+                "size": size,
+            })
+        })
+        .collect_vec();
+
+    Json(json!({
+        "data": traces,
+        "total": state.len(),
+        "estimatedMemoryUsage": state.estimated_memory_usage(),
+    }))
+    .into_response()
+}
+
+async fn healthz(Extension(state): Extension<StateRef>) -> impl IntoResponse {
+    let state = state.read().await;
+
+    Json(json!({
+        "status": "ok",
+        "traces": state.len(),
+        "spans": state.total_spans(),
+        "memory_bytes": state.estimated_memory_usage(),
+    }))
+    .into_response()
+}
+
+async fn metrics(Extension(state): Extension<StateRef>) -> impl IntoResponse {
+    let state = state.read().await;
+
+    let metrics = [
+        (
+            "otlp_embedded_traces_total",
+            "gauge",
+            "Number of traces in the state.",
+            state.len() as u64,
+        ),
+        (
+            "otlp_embedded_spans",
+            "gauge",
+            "Number of spans in the traces.",
+            state.total_spans() as u64,
+        ),
+        (
+            "otlp_embedded_estimated_memory_bytes",
+            "gauge",
+            "Estimated memory usage of the traces in bytes.",
+            state.estimated_memory_usage() as u64,
+        ),
+        (
+            "otlp_embedded_spans_ingested_total",
+            "counter",
+            "Number of spans ingested.",
+            state.spans_ingested(),
+        ),
+        (
+            "otlp_embedded_traces_evicted_total",
+            "counter",
+            "Number of traces evicted due to the capacity.",
+            state.evicted_count(),
+        ),
+        (
+            "otlp_embedded_ingest_queue_depth",
+            "gauge",
+            "Number of export requests pending in the ingest queue.",
+            state.ingest_queue_depth() as u64,
+        ),
+    ];
+
+    let body = metrics
+        .into_iter()
+        .map(|(name, r#type, help, value)| {
+            format!("# HELP {name} {help}\n# TYPE {name} {type}\n{name} {value}\n")
+        })
+        .join("");
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
 const INDEX_HTML: &str = "index.html";
 
 #[derive(RustEmbed)]
 #[folder = "jaeger-ui/build"]
 struct Assets;
 
-async fn static_handler(uri: Uri, base_tag: &str) -> Response {
+async fn static_handler(uri: Uri, index_html: &str) -> Response {
     let path = uri.path().trim_start_matches('/');
 
     if path == INDEX_HTML {
-        return index_html(base_tag);
+        return Html(index_html.to_owned()).into_response();
     }
 
     match Assets::get(path) {
@@ -162,25 +979,958 @@ async fn static_handler(uri: Uri, base_tag: &str) -> Response {
                 // Due to the frontend is a SPA (Single Page Application),
                 // it has own frontend routes, we should return the ROOT PAGE
                 // to avoid frontend route 404.
-                (StatusCode::TEMPORARY_REDIRECT, index_html(base_tag)).into_response()
+                (StatusCode::TEMPORARY_REDIRECT, Html(index_html.to_owned())).into_response()
             }
         }
     }
 }
 
-fn index_html(base_tag: &str) -> Response {
+fn render_index_html(base_path: &str, title: Option<&str>) -> String {
     let file = Assets::get(INDEX_HTML).unwrap();
-    let data = std::str::from_utf8(&file.data)
+    let mut data = std::str::from_utf8(&file.data)
         .unwrap()
-        .replace(r#"<base href="/""#, base_tag);
+        .replace(r#"<base href="/""#, &format!(r#"<base href="{base_path}""#));
+
+    // Replace the content of `<title>`. Leave it unchanged if not found.
+    if let Some(title) = title {
+        if let Some(start) = data.find("<title>") {
+            let start = start + "<title>".len();
+            if let Some(len) = data[start..].find("</title>") {
+                data.replace_range(start..start + len, &escape_html(title));
+            }
+        }
+    }
 
-    Html(data).into_response()
+    data
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
 }
 
 fn not_found() -> Response {
-    not_found_with_msg("Not Found")
+    (StatusCode::NOT_FOUND, "Not Found").into_response()
+}
+
+/// Build an API error response in the format of Jaeger, so that the UI can display the message.
+fn error_with_msg(status: StatusCode, msg: impl Into<String>) -> Response {
+    let body = json!({
+        "data": null,
+        "total": 0,
+        "errors": [{ "code": status.as_u16(), "msg": msg.into() }],
+    });
+    (status, Json(body)).into_response()
+}
+
+/// Same as [`extract::Path`], but rejects with an error in the format of Jaeger.
+struct Path<T>(T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match extract::Path::from_request_parts(parts, state).await {
+            Ok(extract::Path(value)) => Ok(Self(value)),
+            Err(e) => Err(error_with_msg(e.status(), e.body_text())),
+        }
+    }
+}
+
+/// Same as [`extract::Query`], but rejects with an error in the format of Jaeger.
+struct Query<T>(T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match extract::Query::from_request_parts(parts, state).await {
+            Ok(extract::Query(value)) => Ok(Self(value)),
+            Err(e) => Err(error_with_msg(e.status(), e.body_text())),
+        }
+    }
 }
 
 fn not_found_with_msg(msg: impl Into<String>) -> Response {
-    (StatusCode::NOT_FOUND, msg.into()).into_response()
+    error_with_msg(StatusCode::NOT_FOUND, msg)
+}
+
+fn bad_request_with_msg(msg: impl Into<String>) -> Response {
+    error_with_msg(StatusCode::BAD_REQUEST, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderValue, Method};
+
+    use super::*;
+    use crate::{test_util::*, Config, State};
+
+    #[tokio::test]
+    async fn test_base_path() {
+        let router = app(State::with_capacity(10), None);
+        assert_eq!(get_json(&router, "/healthz").await.0, StatusCode::OK);
+
+        // Served both under the base path and at the root.
+        let router = app(State::with_capacity(10), Some("trace"));
+        assert_eq!(get_json(&router, "/trace/healthz").await.0, StatusCode::OK);
+        assert_eq!(get_json(&router, "/healthz").await.0, StatusCode::OK);
+
+        assert_eq!(normalize_base_path(""), "/");
+        assert_eq!(normalize_base_path("/trace"), "/trace/");
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let state = State::with_capacity(10);
+        ingest(&state, "svc", vec![span(1, 1, None)]).await;
+        let router = app(state, None);
+
+        let hex_id = hex::encode(trace_id(1));
+        let request = Request::get(format!("/api/traces/{hex_id}/download"))
+            .body(Default::default())
+            .unwrap();
+        let (status, headers, body) = send(&router, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            headers[header::CONTENT_DISPOSITION],
+            format!(r#"attachment; filename="{hex_id}.json""#)
+        );
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["data"][0]["traceID"], hex_id);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let state = State::with_capacity(1);
+        ingest(&state, "svc", vec![span(1, 1, None)]).await;
+        ingest(&state, "svc", vec![span(2, 1, None), span(2, 2, Some(1))]).await;
+
+        let router = app(state, None);
+        let request = Request::get("/metrics").body(Default::default()).unwrap();
+        let (status, _, body) = send(&router, request).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("# TYPE otlp_embedded_traces_total gauge\n"));
+        assert!(body.contains("\notlp_embedded_traces_total 1\n"));
+        assert!(body.contains("\notlp_embedded_spans_ingested_total 3\n"));
+        assert!(body.contains("\notlp_embedded_traces_evicted_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_clear() {
+        let state = State::with_capacity(10);
+        ingest(&state, "svc", vec![span(1, 1, None), span(2, 1, None)]).await;
+        let router = app(state.clone(), None);
+
+        let request = Request::post("/api/clear")
+            .body(Default::default())
+            .unwrap();
+        let (status, _, body) = send(&router, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, br#"{"removed":2}"#);
+        assert!(state.read().await.is_empty());
+        assert_eq!(state.read().await.evicted_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_trace_status_codes() {
+        let state = State::with_capacity(10);
+        ingest(&state, "svc", vec![span(1, 1, None)]).await;
+        let router = app(state, None);
+
+        let (status, body) = get_json(&router, "/api/traces/not-hex").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["errors"][0]["code"], 400);
+
+        let missing = hex::encode(trace_id(2));
+        let (status, body) = get_json(&router, &format!("/api/traces/{missing}")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["errors"][0]["code"], 404);
+
+        let found = hex::encode(trace_id(1));
+        let (status, body) = get_json(&router, &format!("/api/traces/{found}")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"][0]["traceID"], found);
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let request = || {
+            Request::get("/healthz")
+                .header(header::ORIGIN, "https://example.com")
+                .body(Default::default())
+                .unwrap()
+        };
+
+        let router = app_with_cors(State::with_capacity(10), None, CorsLayer::permissive());
+        let (_, headers, _) = send(&router, request()).await;
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+
+        let router = app(State::with_capacity(10), None);
+        let (_, headers, _) = send(&router, request()).await;
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // The preflight request is answered by the layer.
+        let cors = CorsLayer::new()
+            .allow_origin(HeaderValue::from_static("https://example.com"))
+            .allow_methods([Method::GET]);
+        let router = app_with_cors(State::with_capacity(10), None, cors);
+        let preflight = Request::options("/api/traces")
+            .header(header::ORIGIN, "https://example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(Default::default())
+            .unwrap();
+        let (status, headers, _) = send(&router, preflight).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET");
+    }
+
+    #[tokio::test]
+    async fn test_builder() {
+        let state = State::with_capacity(10);
+        for i in 1..=3 {
+            ingest(&state, "svc", vec![span(i, 1, None)]).await;
+        }
+        let router = UiBuilder::new(state)
+            .max_limit(2)
+            .with_metrics(false)
+            .build();
+
+        let (status, body) = get_json(&router, "/api/traces?limit=10").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"].as_array().unwrap().len(), 2);
+        assert_eq!(body["total"], 3);
+
+        // Falls back to the UI.
+        let request = Request::get("/metrics").body(Default::default()).unwrap();
+        let (status, _, _) = send(&router, request).await;
+        assert_eq!(status, StatusCode::TEMPORARY_REDIRECT);
+    }
+
+    #[tokio::test]
+    async fn test_title() {
+        let router = UiBuilder::new(State::with_capacity(10))
+            .base_path("/trace/")
+            .title("<Traces> & more")
+            .build();
+
+        let request = Request::get("/index.html")
+            .body(Default::default())
+            .unwrap();
+        let (status, _, body) = send(&router, request).await;
+        assert_eq!(status, StatusCode::OK);
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("<title>&lt;Traces&gt; &amp; more</title>"));
+        assert!(body.contains(r#"<base href="/trace/""#));
+    }
+
+    #[tokio::test]
+    async fn test_pagination() {
+        let state = State::with_capacity(10);
+        for i in 1..=5 {
+            let mut span = span(i, 1, None);
+            span.end_time_unix_nano += i as u64;
+            ingest(&state, "svc", vec![span]).await;
+        }
+        let router = app(state, None);
+
+        // Sorted by the end time, from the latest, so the 3rd and the 4th latest are returned.
+        let (status, body) = get_json(&router, "/api/traces?offset=2&limit=2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 5);
+        let ids = (body["data"].as_array().unwrap().iter())
+            .map(|trace| trace["traceID"].clone())
+            .collect_vec();
+        assert_eq!(ids, [hex::encode(trace_id(3)), hex::encode(trace_id(2))]);
+
+        let (_, body) = get_json(&router, "/api/traces?offset=5").await;
+        assert!(body["data"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_healthz() {
+        let state = State::with_capacity(10);
+        ingest(&state, "svc", vec![span(1, 1, None), span(1, 2, Some(1))]).await;
+        let router = app(state, None);
+
+        let (status, body) = get_json(&router, "/healthz").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["traces"], 1);
+        assert_eq!(body["spans"], 2);
+        assert!(body["memory_bytes"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_default_limit() {
+        let state = State::with_capacity(100);
+        for i in 1..=25 {
+            ingest(&state, "svc", vec![span(i, 1, None)]).await;
+        }
+        let router = app(state, None);
+
+        let (status, body) = get_json(&router, "/api/traces?service=svc").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"].as_array().unwrap().len(), default_limit());
+        assert_eq!(body["total"], 25);
+    }
+
+    #[tokio::test]
+    async fn test_tempo() {
+        let state = State::with_capacity(10);
+        ingest(&state, "svc", vec![span(1, 1, None), span(1, 2, Some(1))]).await;
+        let router = app(state, None);
+
+        let hex_id = hex::encode(trace_id(1));
+        let (status, body) = get_json(&router, &format!("/api/traces/{hex_id}/tempo")).await;
+        assert_eq!(status, StatusCode::OK);
+        let batches = body["batches"].as_array().unwrap();
+        assert_eq!(batches.len(), 2);
+        let names = (batches.iter())
+            .map(|b| b["instrumentationLibrarySpans"][0]["spans"][0]["name"].clone())
+            .sorted_by_key(|n| n.to_string())
+            .collect_vec();
+        assert_eq!(names, [json!("span-1"), json!("span-2")]);
+
+        let hex_id = hex::encode(trace_id(2));
+        let (status, _) = get_json(&router, &format!("/api/traces/{hex_id}/tempo")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_accept() {
+        use crate::proto::collector::trace::v1::ExportTraceServiceRequest;
+
+        let state = State::with_capacity(10);
+        ingest(&state, "svc", vec![span(1, 1, None), span(1, 2, Some(1))]).await;
+        let router = app(state, None);
+
+        let get = |accept: Option<&str>| {
+            let uri = format!("/api/traces/{}", hex::encode(trace_id(1)));
+            let mut request = Request::get(uri);
+            if let Some(accept) = accept {
+                request = request.header(header::ACCEPT, accept);
+            }
+            send(&router, request.body(Default::default()).unwrap())
+        };
+
+        for accept in [None, Some("application/json"), Some("text/html, */*;q=0.8")] {
+            let (status, headers, body) = get(accept).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(
+                headers[header::CONTENT_TYPE],
+                "application/json",
+                "{accept:?}"
+            );
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["data"][0]["spans"].as_array().unwrap().len(), 2);
+        }
+
+        // The first supported one is picked, regardless of the quality values.
+        let (_, headers, body) = get(Some("text/html, application/x-protobuf;q=0.5")).await;
+        assert_eq!(headers[header::CONTENT_TYPE], "application/x-protobuf");
+        let decoded = ExportTraceServiceRequest::decode(&body[..]).unwrap();
+        assert_eq!(decoded.resource_spans[0].scope_spans[0].spans.len(), 2);
+
+        let (_, headers, body) = get(Some("application/vnd.tempo+json")).await;
+        assert_eq!(headers[header::CONTENT_TYPE], "application/vnd.tempo+json");
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["batches"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_diagnostics() {
+        let state = State::with_capacity(10);
+        let mut empty = span(1, 1, None);
+        empty.span_id.clear();
+        let mut negative = span(2, 3, Some(2));
+        negative.end_time_unix_nano = negative.start_time_unix_nano;
+        ingest(&state, "svc", vec![empty, negative]).await;
+        let router = app(state, None);
+
+        let (status, body) = get_json(&router, "/api/diagnostics").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["empty_span_id"], 1);
+        assert_eq!(body["non_positive_duration"], 1);
+        assert_eq!(body["missing_parents"], 1);
+        assert_eq!(body["dropped_forwards"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_operation_stats() {
+        let state = State::with_capacity(10);
+        for i in 1..=3 {
+            let mut root = span(i, 1, None);
+            root.end_time_unix_nano = START + i as u64 * 1_000_000;
+            if i == 3 {
+                root = with_error(root);
+            }
+            ingest(&state, "svc", vec![root]).await;
+        }
+        let router = app(state, None);
+
+        let (status, body) = get_json(&router, "/api/services/svc/stats").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({
+                "data": [{
+                    "name": "span-1",
+                    "count": 3,
+                    "p50": 2000,
+                    "p95": 3000,
+                    "p99": 3000,
+                    "errorCount": 1,
+                }],
+                "total": 1,
+            })
+        );
+
+        let (_, body) = get_json(&router, "/api/services/other/stats").await;
+        assert_eq!(body["total"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_remove() {
+        let state = State::with_capacity(10);
+        ingest(&state, "svc", vec![span(1, 1, None)]).await;
+        let router = app(state.clone(), None);
+
+        let uri = format!("/api/traces/{}", hex::encode(trace_id(1)));
+        let delete = || Request::delete(&uri).body(Default::default()).unwrap();
+        assert_eq!(send(&router, delete()).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send(&router, delete()).await.0, StatusCode::NOT_FOUND);
+
+        let state = state.read().await;
+        assert!(state.is_empty());
+        assert_eq!(state.evicted_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unstripped_base_path() {
+        let state = State::with_capacity(10);
+        ingest(&state, "svc", vec![span(1, 1, None)]).await;
+        let router = app(state, Some("/trace/"));
+
+        for uri in ["/trace/api/services", "/api/services"] {
+            let (status, body) = get_json(&router, uri).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["data"], json!(["svc"]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_compression() {
+        use std::io::Read;
+
+        let state = State::with_capacity(10);
+        ingest(&state, "svc", vec![span(1, 1, None), span(1, 2, Some(1))]).await;
+        let router = app(state, None);
+
+        let uri = format!("/api/traces/{}", hex::encode(trace_id(1)));
+        let request = Request::get(&uri)
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Default::default())
+            .unwrap();
+        let (status, headers, body) = send(&router, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+
+        let mut decoded = String::new();
+        (flate2::read::GzDecoder::new(&body[..]))
+            .read_to_string(&mut decoded)
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_str(&decoded).unwrap();
+        assert_eq!(json["data"][0]["spans"].as_array().unwrap().len(), 2);
+
+        // Not compressed unless accepted.
+        let request = Request::get(&uri).body(Default::default()).unwrap();
+        let (_, headers, _) = send(&router, request).await;
+        assert!(!headers.contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn test_auth_token() {
+        let router = UiBuilder::new(State::with_capacity(10))
+            .with_auth_token("secret")
+            .build();
+
+        let get = |auth: Option<&str>| {
+            let mut request = Request::get("/api/services");
+            if let Some(auth) = auth {
+                request = request.header(header::AUTHORIZATION, auth);
+            }
+            request.body(Default::default()).unwrap()
+        };
+        assert_eq!(send(&router, get(None)).await.0, StatusCode::UNAUTHORIZED);
+        let (status, _, body) = send(&router, get(Some("Bearer wrong"))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()["errors"][0]["code"],
+            401
+        );
+        assert_eq!(
+            send(&router, get(Some("Bearer secret"))).await.0,
+            StatusCode::OK
+        );
+
+        // The query parameter is only accepted by the stream.
+        let (status, _) = get_json(&router, "/api/services?access_token=secret").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(get_json(&router, "/healthz").await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_auth_token_stream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let router = UiBuilder::new(State::with_capacity(10))
+            .with_auth_token("secret")
+            .build();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        // Return the status line of the websocket handshake.
+        let handshake = |query: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let request = format!(
+                "GET /api/traces/stream{query} HTTP/1.1\r\n\
+                 Host: {addr}\r\n\
+                 Connection: Upgrade\r\n\
+                 Upgrade: websocket\r\n\
+                 Sec-WebSocket-Version: 13\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut buf = vec![0; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            let response = String::from_utf8_lossy(&buf[..n]).into_owned();
+            response.lines().next().unwrap().to_owned()
+        };
+
+        assert_eq!(handshake("").await, "HTTP/1.1 401 Unauthorized");
+        assert_eq!(
+            handshake("?access_token=wrong").await,
+            "HTTP/1.1 401 Unauthorized"
+        );
+        assert_eq!(
+            handshake("?access_token=secret").await,
+            "HTTP/1.1 101 Switching Protocols"
+        );
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_decode_trace_id() {
+        let id = trace_id(1);
+        assert_eq!(decode_trace_id(&hex::encode(&id)), Ok(id.clone()));
+        assert_eq!(
+            decode_trace_id(&URL_SAFE_NO_PAD.encode(&id)),
+            Ok(id.clone())
+        );
+        let padded = base64::engine::general_purpose::URL_SAFE.encode(&id);
+        assert_eq!(decode_trace_id(&padded), Ok(id));
+
+        let short = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(decode_trace_id("0102030405060708"), Ok(short.clone()));
+        assert_eq!(decode_trace_id(&URL_SAFE_NO_PAD.encode(&short)), Ok(short));
+
+        // Only decoded as hex with the length of a trace ID, even if the digits are valid hex.
+        let digits = format!("{}A", "0".repeat(21));
+        assert_eq!(decode_trace_id(&digits).unwrap().len(), 16);
+
+        for invalid in [
+            "",
+            "abcd",
+            &"ab".repeat(12),
+            &"ab".repeat(17),
+            "not-a-trace-id",
+        ] {
+            assert!(decode_trace_id(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        use futures_util::StreamExt;
+
+        let state = State::with_capacity(10);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = app(state.clone(), None);
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        let url = format!("ws://{addr}/api/traces/stream");
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        // Only the completed traces are streamed.
+        ingest(&state, "svc", vec![span(1, 2, Some(1))]).await;
+        ingest(&state, "svc", vec![span(2, 1, None)]).await;
+        let message = socket.next().await.unwrap().unwrap();
+        let entry: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        assert_eq!(entry["traceID"], hex::encode(trace_id(2)));
+        assert_eq!(entry["spans"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_min_spans() {
+        let state = State::with_capacity(10);
+        ingest(&state, "svc", vec![span(1, 1, None)]).await;
+        ingest(&state, "svc", vec![span(2, 1, None), span(2, 2, Some(1))]).await;
+        let router = app(state, None);
+
+        let (status, body) = get_json(&router, "/api/traces?minSpans=2").await;
+        assert_eq!(status, StatusCode::OK);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0]["traceID"], hex::encode(trace_id(2)));
+
+        let (_, body) = get_json(&router, "/api/traces?minSpans=1").await;
+        assert_eq!(body["data"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_otlp() {
+        use crate::proto::collector::trace::v1::ExportTraceServiceRequest;
+
+        let state = State::with_capacity(10);
+        ingest(&state, "svc", vec![span(1, 1, None), span(1, 2, Some(1))]).await;
+        let router = app(state, None);
+
+        let uri = format!("/api/traces/{}/otlp", hex::encode(trace_id(1)));
+        let request = Request::get(uri).body(Default::default()).unwrap();
+        let (status, headers, body) = send(&router, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/x-protobuf");
+
+        let decoded = ExportTraceServiceRequest::decode(&body[..]).unwrap();
+        let spans = (decoded.resource_spans.iter())
+            .flat_map(|r| &r.scope_spans)
+            .map(|s| s.spans.len())
+            .sum::<usize>();
+        assert_eq!(spans, 2);
+
+        let uri = format!("/api/traces/{}/otlp", hex::encode(trace_id(2)));
+        assert_eq!(get_json(&router, &uri).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_include_incomplete() {
+        let state = State::new(Config {
+            clock: clock(),
+            ..Default::default()
+        });
+        ingest(&state, "svc", vec![span(1, 1, None)]).await;
+        ingest(&state, "svc", vec![span(2, 2, Some(1))]).await;
+        let router = app(state, None);
+
+        let (_, body) = get_json(&router, "/api/traces").await;
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 1);
+        assert!(data[0].get("incomplete").is_none());
+
+        let (status, body) = get_json(&router, "/api/traces?includeIncomplete=true").await;
+        assert_eq!(status, StatusCode::OK);
+        let incomplete = (body["data"].as_array().unwrap().iter())
+            .map(|t| {
+                let id = t["traceID"].as_str().unwrap().to_owned();
+                (id, t.get("incomplete").is_some_and(|v| v == true))
+            })
+            .sorted()
+            .collect_vec();
+        assert_eq!(
+            incomplete,
+            [
+                (hex::encode(trace_id(1)), false),
+                (hex::encode(trace_id(2)), true)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_traces_by_ids() {
+        let state = State::with_capacity(10);
+        for i in 1..=3 {
+            ingest(&state, "svc", vec![span(i, 1, None)]).await;
+        }
+        let router = app(state, None);
+
+        let [id_1, id_3, id_4] = [1, 3, 4].map(|i| hex::encode(trace_id(i)));
+        let uri = format!("/api/traces?traceID={id_1}&traceID={id_3}&traceID={id_4}");
+        let (status, body) = get_json(&router, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        // Traces not found are omitted.
+        assert_eq!(body["total"], 2);
+        let ids = (body["data"].as_array().unwrap().iter())
+            .map(|t| t["traceID"].as_str().unwrap())
+            .collect_vec();
+        assert_eq!(ids, [id_1.as_str(), id_3.as_str()]);
+
+        let (status, _) = get_json(&router, "/api/traces?traceID=not-hex").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_services_counts() {
+        let state = State::new(Config {
+            clock: clock(),
+            ..Default::default()
+        });
+        ingest(&state, "frontend", vec![span(1, 1, None), span(2, 1, None)]).await;
+        // Incomplete, as the parent of the second span is missing.
+        ingest(
+            &state,
+            "backend",
+            vec![span(3, 1, None), span(3, 3, Some(2))],
+        )
+        .await;
+        let router = app(state, None);
+
+        let (status, body) = get_json(&router, "/api/services").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "data": ["backend", "frontend"], "total": 2 }));
+
+        // Only complete traces are counted.
+        let (status, body) = get_json(&router, "/api/services?counts=true").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({
+                "data": [
+                    { "service": "backend", "count": 0 },
+                    { "service": "frontend", "count": 2 },
+                ],
+                "total": 2,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_debug_memory() {
+        let state = State::with_capacity(10);
+        let mut large = span(2, 1, None);
+        large.attributes = vec![kv("key", &"x".repeat(4096))];
+        ingest(
+            &state,
+            "svc",
+            vec![span(1, 1, None), large, span(3, 1, None)],
+        )
+        .await;
+        let router = app(state, None);
+
+        let (status, body) = get_json(&router, "/api/debug/memory?limit=2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 3);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0]["traceID"], hex::encode(trace_id(2)));
+        let sizes = data
+            .iter()
+            .map(|t| t["size"].as_u64().unwrap())
+            .collect_vec();
+        assert!(sizes[0] > sizes[1]);
+        assert!(body["estimatedMemoryUsage"].as_u64().unwrap() > sizes[0]);
+    }
+
+    #[tokio::test]
+    async fn test_duration_histogram() {
+        let state = State::with_capacity(10);
+        for (i, millis) in [(1, 1), (2, 10), (3, 100)] {
+            let mut root = span(i, 1, None);
+            root.end_time_unix_nano = START + millis * 1_000_000;
+            ingest(&state, "svc", vec![root]).await;
+        }
+        let router = app(state, None);
+
+        let uri = "/api/trace-duration-histogram?service=svc&buckets=2";
+        let (status, body) = get_json(&router, uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({
+                "data": [
+                    { "start": 1000, "end": 10000, "count": 1 },
+                    { "start": 10000, "end": 100000, "count": 2 },
+                ],
+                "total": 2,
+            })
+        );
+
+        let (_, body) = get_json(&router, "/api/trace-duration-histogram?service=other").await;
+        assert_eq!(body, json!({ "data": [], "total": 0 }));
+    }
+
+    #[tokio::test]
+    async fn test_services_grouped() {
+        use crate::{proto::collector::trace::v1::ExportTraceServiceRequest, service::export};
+
+        let state = State::with_capacity(10);
+        for (i, environment) in [(1, Some("prod")), (2, Some("staging")), (3, None)] {
+            let mut resource_spans = resource_spans("svc", vec![span(i, 1, None)]);
+            if let Some(environment) = environment {
+                let attributes = &mut resource_spans.resource.as_mut().unwrap().attributes;
+                attributes.push(kv("deployment.environment.name", environment));
+            }
+            let request = ExportTraceServiceRequest {
+                resource_spans: vec![resource_spans],
+            };
+            export(&state, request).await.unwrap();
+        }
+        let router = app(state, None);
+
+        let (status, body) = get_json(&router, "/api/services/grouped").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({
+                "data": [
+                    { "service": "svc", "environment": null },
+                    { "service": "svc", "environment": "prod" },
+                    { "service": "svc", "environment": "staging" },
+                ],
+                "total": 3,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let state = State::new(Config {
+            clock: clock(),
+            ..Default::default()
+        });
+        ingest(
+            &state,
+            "svc",
+            vec![span(1, 2, Some(1)), span(1, 3, Some(2))],
+        )
+        .await;
+        let router = app(state.clone(), None);
+
+        let uri = format!("/api/traces/{}/status", hex::encode(trace_id(1)));
+        let (status, body) = get_json(&router, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({
+                "complete": false,
+                "span_count": 2,
+                "missing_parents": [hex::encode(span_id(1))],
+            })
+        );
+
+        ingest(&state, "svc", vec![span(1, 1, None)]).await;
+        let (_, body) = get_json(&router, &uri).await;
+        assert_eq!(body["complete"], true);
+        assert_eq!(body["missing_parents"], json!([]));
+
+        let uri = format!("/api/traces/{}/status", hex::encode(trace_id(2)));
+        assert_eq!(get_json(&router, &uri).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_rejection_envelope() {
+        let router = app(State::with_capacity(10), None);
+
+        for uri in [
+            "/api/traces?limit=abc",
+            "/api/trace-duration-histogram?buckets=-1",
+            // Not valid UTF-8 after decoding.
+            "/api/tag-values/%FF",
+        ] {
+            let (status, body) = get_json(&router, uri).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}");
+            assert_eq!(body["errors"][0]["code"], 400, "{uri}");
+            assert!(
+                !body["errors"][0]["msg"].as_str().unwrap().is_empty(),
+                "{uri}"
+            );
+        }
+    }
+
+    /// A state with spans of various attributes in multiple shards, optionally indexed.
+    async fn tagged_state(index_tags: bool) -> StateRef {
+        use crate::proto::common::v1::{any_value, AnyValue, KeyValue};
+
+        let state = State::new(Config {
+            index_tags,
+            shards: 2,
+            ..Default::default()
+        });
+        for (i, method) in [(1, "GET"), (2, "POST"), (3, "GET"), (4, "PUT")] {
+            let mut root = span(i, 1, None);
+            root.attributes = vec![
+                kv("http.method", method),
+                KeyValue {
+                    key: "retry".to_owned(),
+                    value: Some(AnyValue {
+                        value: Some(any_value::Value::IntValue(i as i64 % 2)),
+                    }),
+                },
+            ];
+            ingest(&state, "svc", vec![root]).await;
+        }
+        state
+    }
+
+    #[tokio::test]
+    async fn test_tag_keys() {
+        for index_tags in [false, true] {
+            let router = app(tagged_state(index_tags).await, None);
+
+            let (status, body) = get_json(&router, "/api/tag-keys").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(
+                body,
+                json!({ "data": ["http.method", "retry", "service.name"], "total": 3 }),
+                "{index_tags}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tag_values() {
+        for index_tags in [false, true] {
+            let router = app(tagged_state(index_tags).await, None);
+
+            let (status, body) = get_json(&router, "/api/tag-values/http.method").await;
+            assert_eq!(status, StatusCode::OK);
+            let expected = json!({ "data": ["GET", "POST", "PUT"], "total": 3 });
+            assert_eq!(body, expected, "{index_tags}");
+
+            // Rendered in the same way as the tags.
+            let (_, body) = get_json(&router, "/api/tag-values/retry").await;
+            assert_eq!(body["data"], json!(["0", "1"]), "{index_tags}");
+
+            let (_, body) = get_json(&router, "/api/tag-values/http.method?limit=2").await;
+            assert_eq!(body["total"], 2, "{index_tags}");
+            let (_, body) = get_json(&router, "/api/tag-values/unknown").await;
+            assert_eq!(body["total"], 0, "{index_tags}");
+        }
+    }
 }
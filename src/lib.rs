@@ -1,16 +1,42 @@
 #![warn(missing_docs)]
 #![doc = include_str!("../README.md")]
 
+mod clock;
+mod forward;
+mod http;
+mod index;
+mod intern;
 mod jaeger;
 mod limiter;
+mod otlp_json;
+mod perfetto;
+mod persist;
 /// The generated protobuf and gRPC code for OpenTelemetry trace service.
 pub mod proto;
+mod rate_limit;
+mod redact;
+mod serve;
 mod service;
+mod shard;
+mod snapshot;
 mod state;
+#[cfg(test)]
+mod test_util;
 mod trace;
+mod zipkin;
 
-pub use jaeger::ui::app as ui_app;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use http::ingest_app;
+pub use jaeger::ui::{app as ui_app, app_with_cors as ui_app_with_cors, UiBuilder};
+pub use persist::persist_periodically;
 pub use proto::collector::trace::v1::trace_service_server::{TraceService, TraceServiceServer};
-pub use service::TraceServiceImpl;
-pub use state::{Config, State, StateRef};
+pub use serve::serve_with_shutdown;
+pub use service::{IngestHook, TraceServiceImpl};
+pub use shard::trace_shard;
+pub use snapshot::{StateSnapshot, TraceSnapshot};
+pub use state::{
+    Config, ConfigError, Diagnostics, DurationBucket, EvictionPolicy, OperationStat, SpanListener,
+    State, StateRef, TimestampValidation,
+};
+pub use tower_http::cors::CorsLayer;
 pub use trace::*;
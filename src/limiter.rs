@@ -1,5 +1,9 @@
+use std::hash::Hash;
+
 use datasize::DataSize;
-use schnellru::Limiter;
+use schnellru::{Limiter, LruMap};
+
+use crate::trace::{Trace, TraceId};
 
 /// Limit both the number of elements and the memory usage of the map.
 pub(crate) struct MyLimiter {
@@ -7,45 +11,106 @@ pub(crate) struct MyLimiter {
     max_mem: usize,
 
     max_length: u32,
+
+    /// The total number of spans received in the traces, excluding placeholders.
+    spans: usize,
+
+    evicted: u64,
+
+    /// Whether a removal by [`remove_explicitly`] is in progress, which is not an eviction.
+    explicit: bool,
+
+    /// Whether the eviction is done by the owner instead of the map, e.g., for
+    /// [`EvictionPolicy::LargestFirst`](crate::EvictionPolicy::LargestFirst).
+    manual: bool,
+
+    /// IDs of the traces removed since the last call to [`MyLimiter::take_removed`].
+    removed: Vec<TraceId>,
 }
 
 impl MyLimiter {
-    pub fn new(max_mem: usize, max_length: u32) -> Self {
+    pub fn new(max_mem: usize, max_length: u32, manual: bool) -> Self {
         Self {
             current_mem: 0,
             max_mem,
             max_length,
+            spans: 0,
+            evicted: 0,
+            explicit: false,
+            manual,
+            removed: Vec::new(),
         }
     }
 
     pub fn estimated_memory_usage(&self) -> usize {
         self.current_mem
     }
+
+    pub fn evicted_count(&self) -> u64 {
+        self.evicted
+    }
+
+    /// Account for the size change of an existing entry that's updated in place.
+    pub fn on_resized(&mut self, delta: isize) {
+        self.current_mem = self.current_mem.saturating_add_signed(delta);
+    }
+
+    pub fn total_spans(&self) -> usize {
+        self.spans
+    }
+
+    /// Account for the spans added to an existing entry that's updated in place.
+    pub fn on_spans_added(&mut self, count: usize) {
+        self.spans += count;
+    }
+
+    /// Take the IDs of the traces removed since the last call.
+    pub fn take_removed(&mut self) -> Vec<TraceId> {
+        std::mem::take(&mut self.removed)
+    }
+
+    pub fn is_over_memory(&self) -> bool {
+        self.current_mem > self.max_mem
+    }
+
+    /// Check whether either limit is exceeded with the given number of entries.
+    pub fn is_over(&self, length: usize) -> bool {
+        length > self.max_length as usize || self.is_over_memory()
+    }
+}
+
+/// Remove a trace on purpose, e.g., by the user or the tail sampling, which is not counted as
+/// an eviction, unlike the removals by the map itself or with [`LruMap::remove`].
+pub(crate) fn remove_explicitly(
+    map: &mut LruMap<TraceId, Trace, MyLimiter>,
+    id: &(impl Hash + PartialEq<TraceId> + ?Sized),
+) -> Option<Trace> {
+    map.limiter_mut().explicit = true;
+    let removed = map.remove(id);
+    map.limiter_mut().explicit = false;
+    removed
 }
 
-impl<K, V> Limiter<K, V> for MyLimiter
-where
-    K: DataSize,
-    V: DataSize,
-{
-    type KeyToInsert<'a> = K;
+impl Limiter<TraceId, Trace> for MyLimiter {
+    type KeyToInsert<'a> = TraceId;
     type LinkType = u32;
 
     fn is_over_the_limit(&self, length: usize) -> bool {
-        length > self.max_length as usize || self.current_mem > self.max_mem
+        !self.manual && self.is_over(length)
     }
 
     fn on_insert(
         &mut self,
         _length: usize,
         key: Self::KeyToInsert<'_>,
-        value: V,
-    ) -> Option<(K, V)> {
+        value: Trace,
+    ) -> Option<(TraceId, Trace)> {
         if self.max_length > 0 {
             // Do not reject new inserts due to memory usage.
             // Instead, evict the oldest entry by telling `is_over_the_limit`.
-            let mem = K::estimate_heap_size(&key) + V::estimate_heap_size(&value);
+            let mem = key.estimate_heap_size() + value.estimate_heap_size();
             self.current_mem += mem;
+            self.spans += value.span_count();
 
             Some((key, value))
         } else {
@@ -56,22 +121,30 @@ where
     fn on_replace(
         &mut self,
         _length: usize,
-        _old_key: &mut K,
+        _old_key: &mut TraceId,
         _new_key: Self::KeyToInsert<'_>,
-        _old_value: &mut V,
-        _new_value: &mut V,
+        _old_value: &mut Trace,
+        _new_value: &mut Trace,
     ) -> bool {
         // We never call this.
         unreachable!()
     }
 
-    fn on_removed(&mut self, key: &mut K, value: &mut V) {
-        let mem = K::estimate_heap_size(&*key) + V::estimate_heap_size(value);
+    fn on_removed(&mut self, key: &mut TraceId, value: &mut Trace) {
+        let mem = key.estimate_heap_size() + value.estimate_heap_size();
         self.current_mem -= mem;
+        self.spans -= value.span_count();
+        if !self.explicit {
+            self.evicted += 1;
+        }
+        self.removed.push(key.clone());
+        tracing::trace!(freed = mem, current = self.current_mem, "trace removed");
     }
 
     fn on_cleared(&mut self) {
         self.current_mem = 0;
+        self.spans = 0;
+        self.removed.clear();
     }
 
     fn on_grow(&mut self, _new_memory_usage: usize) -> bool {
@@ -79,3 +152,31 @@ where
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use schnellru::LruMap;
+
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn test_evicted_count() {
+        let mut map = LruMap::new(MyLimiter::new(usize::MAX, 2, false));
+        for i in 1..=3 {
+            map.insert(trace_id(i), new_trace("svc", vec![span(i, 1, None)]));
+        }
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.limiter().evicted_count(), 1);
+        assert_eq!(map.limiter_mut().take_removed(), [trace_id(1)]);
+
+        // Explicit removals are not evictions.
+        assert!(remove_explicitly(&mut map, &trace_id(2)).is_some());
+        assert!(remove_explicitly(&mut map, &trace_id(2)).is_none());
+        assert_eq!(map.limiter().evicted_count(), 1);
+        assert_eq!(map.limiter_mut().take_removed(), [trace_id(2)]);
+
+        map.remove(&trace_id(3));
+        assert_eq!(map.limiter().evicted_count(), 2);
+    }
+}
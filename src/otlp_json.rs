@@ -0,0 +1,353 @@
+// https://opentelemetry.io/docs/specs/otlp/#json-protobuf-encoding
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::de::Error as _;
+use serde_json::{Map, Value};
+
+use crate::proto::{
+    collector::trace::v1::ExportTraceServiceRequest,
+    common::v1::{any_value, AnyValue, ArrayValue, InstrumentationScope, KeyValue, KeyValueList},
+    resource::v1::Resource,
+    trace::v1::{
+        span::{Event, Link, SpanKind},
+        status::StatusCode,
+        ResourceSpans, ScopeSpans, Span, Status,
+    },
+};
+
+type Result<T> = serde_json::Result<T>;
+
+const TRACE_ID_LEN: usize = 16;
+const SPAN_ID_LEN: usize = 8;
+
+/// Decode an OTLP/JSON encoded export request.
+///
+/// Both the `lowerCamelCase` and the original field names are accepted. Trace and span IDs
+/// can be either hex-encoded as required by OTLP, or base64-encoded as the standard protobuf
+/// JSON mapping.
+pub(crate) fn decode_export_request(body: &[u8]) -> Result<ExportTraceServiceRequest> {
+    let value: Value = serde_json::from_slice(body)?;
+    let obj = as_object(&value)?;
+
+    Ok(ExportTraceServiceRequest {
+        resource_spans: list(obj, "resourceSpans", resource_spans)?,
+    })
+}
+
+fn as_object(value: &Value) -> Result<&Map<String, Value>> {
+    value
+        .as_object()
+        .ok_or_else(|| serde_json::Error::custom(format!("expected object, got {value}")))
+}
+
+fn to_snake_case(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len() + 4);
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            snake.push('_');
+            snake.push(c.to_ascii_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+/// Get the field with the given `lowerCamelCase` name, or its original `snake_case` name.
+fn field<'a>(obj: &'a Map<String, Value>, name: &str) -> Option<&'a Value> {
+    obj.get(name)
+        .or_else(|| obj.get(&to_snake_case(name)))
+        .filter(|v| !v.is_null())
+}
+
+fn list<T>(
+    obj: &Map<String, Value>,
+    name: &str,
+    f: impl Fn(&Map<String, Value>) -> Result<T>,
+) -> Result<Vec<T>> {
+    match field(obj, name) {
+        None => Ok(Vec::new()),
+        Some(Value::Array(values)) => values.iter().map(|v| f(as_object(v)?)).collect(),
+        Some(other) => Err(serde_json::Error::custom(format!(
+            "expected array for `{name}`, got {other}"
+        ))),
+    }
+}
+
+fn string(obj: &Map<String, Value>, name: &str) -> Result<String> {
+    match field(obj, name) {
+        None => Ok(String::new()),
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(other) => Err(serde_json::Error::custom(format!(
+            "expected string for `{name}`, got {other}"
+        ))),
+    }
+}
+
+/// Parse an integer that's either a JSON number or a string, as 64-bit integers are
+/// encoded as decimal strings in the protobuf JSON mapping.
+fn integer<T: TryFrom<i128>>(obj: &Map<String, Value>, name: &str) -> Result<T> {
+    let value = match field(obj, name) {
+        None => 0,
+        Some(Value::Number(n)) => (n.as_i64().map(i128::from))
+            .or_else(|| n.as_u64().map(i128::from))
+            .ok_or_else(|| serde_json::Error::custom(format!("invalid integer for `{name}`")))?,
+        Some(Value::String(s)) => s
+            .parse()
+            .map_err(|_| serde_json::Error::custom(format!("invalid integer for `{name}`: {s}")))?,
+        Some(other) => {
+            return Err(serde_json::Error::custom(format!(
+                "expected integer for `{name}`, got {other}"
+            )))
+        }
+    };
+    T::try_from(value)
+        .map_err(|_| serde_json::Error::custom(format!("integer out of range for `{name}`")))
+}
+
+/// Parse an enum that's either a JSON number or the name of the variant.
+fn enumeration(
+    obj: &Map<String, Value>,
+    name: &str,
+    from_str_name: impl Fn(&str) -> Option<i32>,
+) -> Result<i32> {
+    match field(obj, name) {
+        Some(Value::String(s)) if s.parse::<i32>().is_err() => from_str_name(s)
+            .ok_or_else(|| serde_json::Error::custom(format!("invalid enum for `{name}`: {s}"))),
+        _ => integer(obj, name),
+    }
+}
+
+/// Decode a trace or span ID of the given length in bytes, hex or base64 encoded.
+fn id(obj: &Map<String, Value>, name: &str, len: usize) -> Result<Vec<u8>> {
+    let s = string(obj, name)?;
+    if s.is_empty() {
+        return Ok(Vec::new());
+    }
+    if s.len() == len * 2 {
+        if let Ok(id) = hex::decode(&s) {
+            return Ok(id);
+        }
+    }
+    let id = BASE64
+        .decode(&s)
+        .map_err(|e| serde_json::Error::custom(format!("invalid ID for `{name}`: {s}, {e}")))?;
+    if id.len() != len {
+        return Err(serde_json::Error::custom(format!(
+            "invalid ID for `{name}`: {s}, expected {len} bytes, got {}",
+            id.len()
+        )));
+    }
+    Ok(id)
+}
+
+fn any_value(obj: &Map<String, Value>) -> Result<AnyValue> {
+    let value = if let Some(v) = field(obj, "stringValue") {
+        let s = v.as_str().unwrap_or_default().to_owned();
+        any_value::Value::StringValue(s)
+    } else if let Some(v) = field(obj, "boolValue") {
+        any_value::Value::BoolValue(v.as_bool().unwrap_or_default())
+    } else if field(obj, "intValue").is_some() {
+        any_value::Value::IntValue(integer(obj, "intValue")?)
+    } else if let Some(v) = field(obj, "doubleValue") {
+        let d = match v {
+            Value::String(s) => s.parse().unwrap_or_default(),
+            v => v.as_f64().unwrap_or_default(),
+        };
+        any_value::Value::DoubleValue(d)
+    } else if let Some(v) = field(obj, "arrayValue") {
+        let values = list(as_object(v)?, "values", any_value)?;
+        any_value::Value::ArrayValue(ArrayValue { values })
+    } else if let Some(v) = field(obj, "kvlistValue") {
+        let values = list(as_object(v)?, "values", key_value)?;
+        any_value::Value::KvlistValue(KeyValueList { values })
+    } else if field(obj, "bytesValue").is_some() {
+        let s = string(obj, "bytesValue")?;
+        let b = (BASE64.decode(&s))
+            .map_err(|e| serde_json::Error::custom(format!("invalid bytes value: {e}")))?;
+        any_value::Value::BytesValue(b)
+    } else {
+        return Ok(AnyValue { value: None });
+    };
+
+    Ok(AnyValue { value: Some(value) })
+}
+
+fn key_value(obj: &Map<String, Value>) -> Result<KeyValue> {
+    Ok(KeyValue {
+        key: string(obj, "key")?,
+        value: field(obj, "value")
+            .map(|v| any_value(as_object(v)?))
+            .transpose()?,
+    })
+}
+
+fn resource_spans(obj: &Map<String, Value>) -> Result<ResourceSpans> {
+    let resource = field(obj, "resource")
+        .map(|v| {
+            let obj = as_object(v)?;
+            Ok::<_, serde_json::Error>(Resource {
+                attributes: list(obj, "attributes", key_value)?,
+                dropped_attributes_count: integer(obj, "droppedAttributesCount")?,
+            })
+        })
+        .transpose()?;
+
+    Ok(ResourceSpans {
+        resource,
+        scope_spans: list(obj, "scopeSpans", scope_spans)?,
+        schema_url: string(obj, "schemaUrl")?,
+    })
+}
+
+fn scope_spans(obj: &Map<String, Value>) -> Result<ScopeSpans> {
+    let scope = field(obj, "scope")
+        .map(|v| {
+            let obj = as_object(v)?;
+            Ok::<_, serde_json::Error>(InstrumentationScope {
+                name: string(obj, "name")?,
+                version: string(obj, "version")?,
+                attributes: list(obj, "attributes", key_value)?,
+                dropped_attributes_count: integer(obj, "droppedAttributesCount")?,
+            })
+        })
+        .transpose()?;
+
+    Ok(ScopeSpans {
+        scope,
+        spans: list(obj, "spans", span)?,
+        schema_url: string(obj, "schemaUrl")?,
+    })
+}
+
+fn span(obj: &Map<String, Value>) -> Result<Span> {
+    let status = field(obj, "status")
+        .map(|v| {
+            let obj = as_object(v)?;
+            Ok::<_, serde_json::Error>(Status {
+                message: string(obj, "message")?,
+                code: enumeration(obj, "code", |s| {
+                    StatusCode::from_str_name(s).map(|c| c as i32)
+                })?,
+            })
+        })
+        .transpose()?;
+
+    Ok(Span {
+        trace_id: id(obj, "traceId", TRACE_ID_LEN)?,
+        span_id: id(obj, "spanId", SPAN_ID_LEN)?,
+        trace_state: string(obj, "traceState")?,
+        parent_span_id: id(obj, "parentSpanId", SPAN_ID_LEN)?,
+        flags: integer(obj, "flags")?,
+        name: string(obj, "name")?,
+        kind: enumeration(obj, "kind", |s| {
+            SpanKind::from_str_name(s).map(|k| k as i32)
+        })?,
+        start_time_unix_nano: integer(obj, "startTimeUnixNano")?,
+        end_time_unix_nano: integer(obj, "endTimeUnixNano")?,
+        attributes: list(obj, "attributes", key_value)?,
+        dropped_attributes_count: integer(obj, "droppedAttributesCount")?,
+        events: list(obj, "events", event)?,
+        dropped_events_count: integer(obj, "droppedEventsCount")?,
+        links: list(obj, "links", link)?,
+        dropped_links_count: integer(obj, "droppedLinksCount")?,
+        status,
+    })
+}
+
+fn event(obj: &Map<String, Value>) -> Result<Event> {
+    Ok(Event {
+        time_unix_nano: integer(obj, "timeUnixNano")?,
+        name: string(obj, "name")?,
+        attributes: list(obj, "attributes", key_value)?,
+        dropped_attributes_count: integer(obj, "droppedAttributesCount")?,
+    })
+}
+
+fn link(obj: &Map<String, Value>) -> Result<Link> {
+    Ok(Link {
+        trace_id: id(obj, "traceId", TRACE_ID_LEN)?,
+        span_id: id(obj, "spanId", SPAN_ID_LEN)?,
+        trace_state: string(obj, "traceState")?,
+        attributes: list(obj, "attributes", key_value)?,
+        dropped_attributes_count: integer(obj, "droppedAttributesCount")?,
+        flags: integer(obj, "flags")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{kv, span_id, trace_id};
+
+    #[test]
+    fn test_decode() {
+        let body = r#"{
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{ "key": "service.name", "value": { "stringValue": "svc" } }]
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "lib", "version": "1.0" },
+                    "spans": [{
+                        "traceId": "AQEBAQEBAQEBAQEBAQEBAQ==",
+                        "spanId": "0202020202020202",
+                        "parentSpanId": "",
+                        "name": "op",
+                        "kind": "SPAN_KIND_SERVER",
+                        "flags": 257,
+                        "startTimeUnixNano": "1700000000000000000",
+                        "end_time_unix_nano": 1700000000001000000,
+                        "attributes": [
+                            { "key": "count", "value": { "intValue": "42" } },
+                            { "key": "ratio", "value": { "doubleValue": 0.5 } }
+                        ],
+                        "status": { "code": 2, "message": "boom" },
+                        "links": [{ "traceId": "", "spanId": "0303030303030303", "flags": 768 }]
+                    }]
+                }]
+            }]
+        }"#;
+
+        let request = decode_export_request(body.as_bytes()).unwrap();
+        let resource_spans = &request.resource_spans[0];
+        assert_eq!(
+            resource_spans.resource.as_ref().unwrap().attributes,
+            [kv("service.name", "svc")]
+        );
+        let scope_spans = &resource_spans.scope_spans[0];
+        assert_eq!(scope_spans.scope.as_ref().unwrap().version, "1.0");
+
+        let span = &scope_spans.spans[0];
+        assert_eq!(span.trace_id, trace_id(1));
+        assert_eq!(span.span_id, span_id(2));
+        assert!(span.parent_span_id.is_empty());
+        assert_eq!(span.kind, SpanKind::Server as i32);
+        assert_eq!(span.flags, 0x101);
+        assert_eq!(span.start_time_unix_nano, 1_700_000_000_000_000_000);
+        assert_eq!(span.end_time_unix_nano, 1_700_000_000_001_000_000);
+        assert_eq!(
+            span.attributes[0].value.as_ref().unwrap().value,
+            Some(any_value::Value::IntValue(42))
+        );
+        assert_eq!(
+            span.attributes[1].value.as_ref().unwrap().value,
+            Some(any_value::Value::DoubleValue(0.5))
+        );
+        assert_eq!(span.status.as_ref().unwrap().code(), StatusCode::Error);
+        assert_eq!(span.links[0].span_id, span_id(3));
+        assert_eq!(span.links[0].flags, 0x300);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(decode_export_request(b"[]").is_err());
+        assert!(decode_export_request(br#"{ "resourceSpans": {} }"#).is_err());
+        let body = r#"{ "resourceSpans": [{ "scopeSpans": [{ "spans": [{ "spanId": "?" }] }] }] }"#;
+        assert!(decode_export_request(body.as_bytes()).is_err());
+        // Valid base64, but not of the length of a span ID.
+        let body =
+            r#"{ "resourceSpans": [{ "scopeSpans": [{ "spans": [{ "spanId": "AQEBAQ==" }] }] }] }"#;
+        assert!(decode_export_request(body.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+// https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+
+use std::collections::BTreeMap;
+
+use itertools::Itertools;
+use serde_json::json;
+
+use crate::{jaeger::model::any_value_to_serde_value, trace::SpanValue};
+
+/// Convert the spans into the Chrome Trace Event Format, which can be opened in Perfetto
+/// or `chrome://tracing`.
+///
+/// Each service becomes a process. Spans are placed in threads (lanes) so that each child
+/// is nested under the span that encloses it, and overlapping spans go to separate lanes.
+pub(crate) fn spans_to_perfetto_json<'a>(
+    spans: impl Iterator<Item = &'a SpanValue>,
+) -> serde_json::Value {
+    let by_service: BTreeMap<&str, Vec<&SpanValue>> = spans
+        .into_group_map_by(|v| v.service_name())
+        .into_iter()
+        .collect();
+
+    let mut events = Vec::new();
+
+    for (pid, (service, spans)) in (1..).zip(by_service) {
+        events.push(json!({
+            "ph": "M",
+            "name": "process_name",
+            "pid": pid,
+            "args": { "name": service },
+        }));
+
+        // Each lane keeps a stack of the end times of its open spans.
+        let mut lanes: Vec<Vec<u64>> = Vec::new();
+
+        let sorted = spans.into_iter().sorted_by_key(|v| {
+            let span = &v.span;
+            // Outer spans first if they start at the same time.
+            (
+                span.start_time_unix_nano,
+                std::cmp::Reverse(span.end_time_unix_nano),
+            )
+        });
+
+        for v in sorted {
+            let span = &v.span;
+            let start = span.start_time_unix_nano;
+            let end = span.end_time_unix_nano.max(start);
+
+            // Find the first lane where the span is enclosed by the innermost open span, or
+            // where there's no open span at all.
+            let lane = lanes.iter_mut().position(|stack| {
+                while stack.last().is_some_and(|&open_end| open_end <= start) {
+                    stack.pop();
+                }
+                stack.last().is_none_or(|&open_end| end <= open_end)
+            });
+            let tid = match lane {
+                Some(i) => i,
+                None => {
+                    lanes.push(Vec::new());
+                    lanes.len() - 1
+                }
+            };
+            lanes[tid].push(end);
+
+            let mut args = (span.attributes.iter())
+                .map(|kv| {
+                    let value = (kv.value.clone().and_then(|v| v.value))
+                        .map(any_value_to_serde_value)
+                        .unwrap_or_default();
+                    (kv.key.clone(), value)
+                })
+                .collect::<serde_json::Map<_, _>>();
+            args.insert("span_id".to_owned(), hex::encode(&span.span_id).into());
+            if !span.parent_span_id.is_empty() {
+                args.insert(
+                    "parent_span_id".to_owned(),
+                    hex::encode(&span.parent_span_id).into(),
+                );
+            }
+
+            events.push(json!({
+                "ph": "X",
+                "name": span.name,
+                "pid": pid,
+                "tid": tid + 1,
+                "ts": start / 1000,
+                "dur": (end - start) / 1000,
+                "args": args,
+            }));
+        }
+    }
+
+    json!({
+        "traceEvents": events,
+        "displayTimeUnit": "ms",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::*;
+
+    #[test]
+    fn test_lanes() {
+        let ms = 1_000_000;
+        let with_time = |id, parent, start, end| {
+            let mut span = span(1, id, parent);
+            span.start_time_unix_nano = START + start * ms;
+            span.end_time_unix_nano = START + end * ms;
+            span
+        };
+        let spans = vec![
+            with_time(1, None, 0, 10),
+            with_time(2, Some(1), 1, 3),
+            with_time(3, Some(1), 2, 4),
+            with_time(4, Some(1), 5, 6),
+        ];
+        let json = new_trace("svc", spans).to_perfetto_json();
+        let events = json["traceEvents"].as_array().unwrap();
+
+        assert_eq!(events[0]["ph"], "M");
+        assert_eq!(events[0]["args"]["name"], "svc");
+
+        let lanes = (events[1..].iter())
+            .map(|e| (e["name"].as_str().unwrap(), e["tid"].as_u64().unwrap()))
+            .collect::<Vec<_>>();
+        // The overlapping span goes to a separate lane, while the others are nested.
+        assert_eq!(
+            lanes,
+            [("span-1", 1), ("span-2", 1), ("span-3", 2), ("span-4", 1)]
+        );
+        assert_eq!(events[2]["ts"], (START + ms) / 1000);
+        assert_eq!(events[2]["dur"], 2000);
+        assert_eq!(events[2]["args"]["parent_span_id"], hex::encode(span_id(1)));
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::time::MissedTickBehavior;
+
+use crate::{StateRef, StateSnapshot};
+
+/// Load the snapshot from the file, or `None` if the file doesn't exist.
+pub(crate) fn load(path: &Path) -> io::Result<Option<StateSnapshot>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let snapshot = serde_json::from_slice(&bytes)?;
+    Ok(Some(snapshot))
+}
+
+/// Save the snapshot to the file atomically, by writing to a temporary file first and then
+/// renaming it, so that a crash won't leave a partial file behind.
+pub(crate) fn save(path: &Path, snapshot: &StateSnapshot) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let bytes = serde_json::to_vec(snapshot)?;
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Write the snapshot of the state to [`Config::persist_path`] every `period`, forever.
+///
+/// Returns immediately if the path is not configured. Failures are logged and retried in
+/// the next period. Usually spawned in the background:
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use otlp_embedded::{persist_periodically, Config, State};
+/// # async fn run() {
+/// let state = State::new(Config {
+///     persist_path: Some("traces.snapshot".into()),
+///     ..Default::default()
+/// });
+/// tokio::spawn(persist_periodically(state.clone(), Duration::from_secs(10)));
+/// # }
+/// ```
+///
+/// [`Config::persist_path`]: crate::Config::persist_path
+pub async fn persist_periodically(state: StateRef, period: Duration) {
+    let Some(path) = state.read().await.persist_path().map(Path::to_owned) else {
+        return;
+    };
+
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes immediately, and there's nothing new to save yet.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let snapshot = state.read().await.export_snapshot();
+        let path_clone = path.clone();
+        let res = tokio::task::spawn_blocking(move || save(&path_clone, &snapshot)).await;
+
+        match res {
+            Ok(Ok(())) => tracing::debug!(path = %path.display(), "persisted state"),
+            Ok(Err(e)) => {
+                tracing::error!(path = %path.display(), error = %e, "failed to persist state")
+            }
+            Err(e) => tracing::error!(error = %e, "failed to persist state"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, Config, State};
+
+    /// A path in the temporary directory that's unique to the test.
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("otlp-embedded-{}-{name}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = temp_path("save");
+        assert_eq!(load(&path).unwrap(), None);
+
+        let snapshot = StateSnapshot::default();
+        save(&path, &snapshot).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(snapshot));
+        std::fs::write(&path, "not json").unwrap();
+        assert!(load(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_persist_periodically() {
+        let path = temp_path("periodically");
+        let config = || Config {
+            persist_path: Some(path.clone()),
+            ..Default::default()
+        };
+
+        let state = State::new(config());
+        ingest(&state, "svc", vec![span(1, 1, None)]).await;
+        let task = tokio::spawn(persist_periodically(state, Duration::from_millis(10)));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        task.abort();
+
+        // Loaded on creation.
+        let restored = State::new(config());
+        let restored = restored.read().await;
+        assert_eq!(restored.len(), 1);
+        assert!(restored.peek_by_id(&trace_id(1)).is_some());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex, time::SystemTime};
+
+/// The number of sources tracked, above which the idle ones are forgotten.
+const MAX_SOURCES: usize = 4096;
+
+/// Get the key of the source of an export request for rate limiting, which is the value of
+/// the configured header if present, or the IP address of the peer otherwise.
+pub(crate) fn source_key(header_value: Option<&str>, addr: Option<SocketAddr>) -> String {
+    match (header_value, addr) {
+        (Some(value), _) => value.to_owned(),
+        // The port differs for each connection of the same client, so it's ignored.
+        (None, Some(addr)) => addr.ip().to_string(),
+        (None, None) => "unknown".to_owned(),
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: SystemTime,
+}
+
+/// A token bucket rate limiter of the export requests, for each source.
+///
+/// The bucket of each source refills at the given rate per second, and holds at most one
+/// second worth of tokens, which bounds the burst.
+pub(crate) struct RateLimiter {
+    per_second: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> Self {
+        Self {
+            per_second: per_second as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token from the bucket of the source, returning `false` if there's none left.
+    pub fn try_acquire(&self, source: &str, now: SystemTime) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_SOURCES && !buckets.contains_key(source) {
+            // Buckets refilled to full are the same as new ones, so it's safe to forget them.
+            buckets.retain(|_, b| self.refill(b, now) < self.per_second);
+        }
+
+        let bucket = (buckets.entry(source.to_owned())).or_insert(Bucket {
+            tokens: self.per_second,
+            last: now,
+        });
+        if self.refill(bucket, now) < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Add the tokens accumulated since the last refill, returning the tokens available.
+    fn refill(&self, bucket: &mut Bucket, now: SystemTime) -> f64 {
+        // The clock may go backwards, in which case nothing is added.
+        let elapsed = now.duration_since(bucket.last).unwrap_or_default();
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * self.per_second).min(self.per_second);
+        bucket.last = bucket.last.max(now);
+        bucket.tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_source_key() {
+        let addr = "10.0.0.1:4317".parse().ok();
+        assert_eq!(source_key(Some("tenant"), addr), "tenant");
+        assert_eq!(source_key(None, addr), "10.0.0.1");
+        assert_eq!(source_key(None, None), "unknown");
+    }
+
+    #[test]
+    fn test_try_acquire() {
+        let limiter = RateLimiter::new(2);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        // The burst is bounded by one second worth of tokens.
+        assert!(limiter.try_acquire("a", now));
+        assert!(limiter.try_acquire("a", now));
+        assert!(!limiter.try_acquire("a", now));
+        assert!(limiter.try_acquire("b", now));
+
+        // A token is refilled every half second, up to the burst.
+        assert!(!limiter.try_acquire("a", now + Duration::from_millis(400)));
+        assert!(limiter.try_acquire("a", now + Duration::from_millis(500)));
+        let later = now + Duration::from_secs(60);
+        assert!(limiter.try_acquire("a", later));
+        assert!(limiter.try_acquire("a", later));
+        assert!(!limiter.try_acquire("a", later));
+
+        // Going backwards in time refills nothing.
+        assert!(!limiter.try_acquire("a", now));
+    }
+}
@@ -0,0 +1,74 @@
+use crate::proto::common::v1::{any_value, AnyValue, KeyValue};
+
+const REDACTED: &str = "***REDACTED***";
+
+/// Check if the key matches the pattern, where `*` matches any sequence of characters.
+pub(crate) fn glob_match(pattern: &str, key: &str) -> bool {
+    let Some((first, rest)) = pattern.split_once('*') else {
+        return pattern == key;
+    };
+    let Some(mut key) = key.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = rest.split('*').peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            // The last part must match the suffix.
+            return key.ends_with(part);
+        }
+        match key.find(part) {
+            Some(i) => key = &key[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Replace the values of the attributes whose keys match any of the patterns.
+pub(crate) fn redact(attributes: &mut [KeyValue], patterns: &[String]) {
+    if patterns.is_empty() {
+        return;
+    }
+
+    for kv in attributes {
+        if patterns.iter().any(|p| glob_match(p, &kv.key)) {
+            kv.value = Some(AnyValue {
+                value: Some(any_value::Value::StringValue(REDACTED.to_owned())),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::kv;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("password", "password"));
+        assert!(!glob_match("password", "password2"));
+        assert!(glob_match(
+            "http.request.header.*",
+            "http.request.header.cookie"
+        ));
+        assert!(!glob_match(
+            "http.request.header.*",
+            "http.response.header.cookie"
+        ));
+        assert!(glob_match("*token*", "auth.token.value"));
+        assert!(glob_match("*.secret", "db.secret"));
+        assert!(!glob_match("*.secret", "db.secret.name"));
+        assert!(glob_match("a*b*c", "abbc"));
+        assert!(!glob_match("a*a", "a"));
+    }
+
+    #[test]
+    fn test_redact() {
+        let mut attributes = vec![kv("user.email", "a@b.c"), kv("user.id", "42")];
+        redact(&mut attributes, &["*.email".to_owned()]);
+        assert_eq!(attributes[0], kv("user.email", REDACTED));
+        assert_eq!(attributes[1], kv("user.id", "42"));
+    }
+}
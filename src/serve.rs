@@ -0,0 +1,119 @@
+use std::{future::Future, net::SocketAddr};
+
+use tokio::{net::TcpListener, sync::watch};
+
+use crate::{jaeger::ui::app, StateRef, TraceServiceImpl};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Serve the Jaeger UI at `ui_addr` and the OTLP/gRPC trace service at `grpc_addr`, until
+/// the `shutdown` future completes.
+///
+/// On shutdown, both servers stop accepting new connections and finish the in-flight
+/// requests before this function returns. For example, to stop on `Ctrl-C`:
+///
+/// ```no_run
+/// # use otlp_embedded::{serve_with_shutdown, State};
+/// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// let state = State::with_capacity(100);
+/// let ui_addr = "0.0.0.0:10188".parse()?;
+/// let grpc_addr = "0.0.0.0:4317".parse()?;
+///
+/// serve_with_shutdown(state, ui_addr, grpc_addr, async {
+///     tokio::signal::ctrl_c().await.unwrap();
+/// })
+/// .await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// If either server fails, the other one is shut down as well and the error is returned.
+pub async fn serve_with_shutdown(
+    state: StateRef,
+    ui_addr: SocketAddr,
+    grpc_addr: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), BoxError> {
+    let (tx, rx) = watch::channel(false);
+    let signal = move || {
+        let mut rx = rx.clone();
+        async move {
+            // Also resolves if the sender is dropped.
+            let _ = rx.wait_for(|&stop| stop).await;
+        }
+    };
+
+    let tx_clone = tx.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        let _ = tx_clone.send(true);
+    });
+
+    let listener = TcpListener::bind(ui_addr).await?;
+    let ui = async {
+        let res = axum::serve(listener, app(state.clone(), None))
+            .with_graceful_shutdown(signal())
+            .await;
+        let _ = tx.send(true);
+        res.map_err(BoxError::from)
+    };
+
+    let grpc = async {
+        let res = tonic::transport::Server::builder()
+            .add_service(TraceServiceImpl::new(state.clone()).into_server())
+            .serve_with_shutdown(grpc_addr, signal())
+            .await;
+        let _ = tx.send(true);
+        res.map_err(BoxError::from)
+    };
+
+    let (ui, grpc) = tokio::join!(ui, grpc);
+    ui.and(grpc)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::State;
+
+    fn any_addr() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (tx, rx) = oneshot::channel();
+        let serve = tokio::spawn(serve_with_shutdown(
+            State::with_capacity(10),
+            any_addr(),
+            any_addr(),
+            async {
+                let _ = rx.await;
+            },
+        ));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!serve.is_finished());
+
+        tx.send(()).unwrap();
+        let res = tokio::time::timeout(Duration::from_secs(5), serve).await;
+        assert!(res.unwrap().unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_bind_error() {
+        let taken = TcpListener::bind(any_addr()).await.unwrap();
+        let res = serve_with_shutdown(
+            State::with_capacity(10),
+            taken.local_addr().unwrap(),
+            any_addr(),
+            std::future::pending(),
+        )
+        .await;
+        assert!(res.is_err());
+    }
+}
@@ -1,19 +1,72 @@
-use crate::proto::collector::trace::v1::{trace_service_server::TraceService, *};
+use crate::proto::collector::trace::v1::{
+    trace_service_server::{TraceService, TraceServiceServer},
+    *,
+};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tonic::{Request, Response, Status};
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    RwLock,
+};
+use tonic::{codec::CompressionEncoding, Request, Response, Status};
 
-use crate::State;
+use crate::{rate_limit::source_key, State};
+
+/// A hook invoked on each export request, before it's applied to the [`State`].
+pub type IngestHook = Arc<dyn Fn(&ExportTraceServiceRequest) + Send + Sync>;
 
 /// The implementation of [`TraceService`].
 pub struct TraceServiceImpl {
     state: Arc<RwLock<State>>,
+    hook: Option<IngestHook>,
+    queue: Option<mpsc::Sender<ExportTraceServiceRequest>>,
 }
 
 impl TraceServiceImpl {
     /// Create a new [`TraceServiceImpl`] with the given [`State`] reference.
     pub fn new(state: Arc<RwLock<State>>) -> Self {
-        Self { state }
+        Self {
+            state,
+            hook: None,
+            queue: None,
+        }
+    }
+
+    /// Create a new [`TraceServiceImpl`] with the given [`State`] reference, and a hook to
+    /// be invoked on each export request before it's applied, e.g., for custom metrics or
+    /// alerting on error spans.
+    ///
+    /// The hook is called synchronously on the request path without holding the lock of
+    /// the state. It should return quickly and never block, otherwise the export will be
+    /// delayed. Offload any heavy work to a separate task.
+    pub fn with_hook(state: Arc<RwLock<State>>, hook: IngestHook) -> Self {
+        Self {
+            state,
+            hook: Some(hook),
+            queue: None,
+        }
+    }
+
+    /// Queue the export requests to be applied by a background task, instead of applying
+    /// them on the request path, so that a slow state write won't stall the producers.
+    ///
+    /// Requests are acknowledged once queued, so failures on applying them are only logged.
+    /// If there are already `capacity` requests pending, new ones are rejected as a whole
+    /// with `partial_success`. The number of pending requests is exposed in the metrics.
+    ///
+    /// This must be called within a Tokio runtime.
+    pub fn with_ingest_queue(mut self, capacity: usize) -> Self {
+        let (tx, rx) = mpsc::channel(capacity);
+        tokio::spawn(drain_ingest_queue(self.state.clone(), tx.downgrade(), rx));
+        self.queue = Some(tx);
+        self
+    }
+
+    /// Wrap this into a [`TraceServiceServer`] that accepts requests compressed with
+    /// `gzip` or `zstd`, in addition to uncompressed ones.
+    pub fn into_server(self) -> TraceServiceServer<Self> {
+        TraceServiceServer::new(self)
+            .accept_compressed(CompressionEncoding::Gzip)
+            .accept_compressed(CompressionEncoding::Zstd)
     }
 }
 
@@ -23,15 +76,373 @@ impl TraceService for TraceServiceImpl {
         &self,
         request: Request<ExportTraceServiceRequest>,
     ) -> std::result::Result<Response<ExportTraceServiceResponse>, Status> {
+        let allowed = self.state.read().await.allow_export(|header| {
+            let value = header.and_then(|h| request.metadata().get(h));
+            source_key(value.and_then(|v| v.to_str().ok()), request.remote_addr())
+        });
+        if !allowed {
+            return Err(Status::resource_exhausted("export rate limit exceeded"));
+        }
+
         let request = request.into_inner();
+        if let Some(hook) = &self.hook {
+            hook(&request);
+        }
+
+        if let Some(queue) = &self.queue {
+            let response = match queue.try_send(request) {
+                Ok(()) => ExportTraceServiceResponse::default(),
+                Err(TrySendError::Full(request) | TrySendError::Closed(request)) => {
+                    let spans = span_count(&request);
+                    tracing::warn!(spans, "rejected export request as the ingest queue is full");
+                    ExportTraceServiceResponse {
+                        partial_success: Some(ExportTracePartialSuccess {
+                            rejected_spans: spans as i64,
+                            error_message: "ingest queue is full".to_owned(),
+                        }),
+                    }
+                }
+            };
+            return Ok(Response::new(response));
+        }
+
+        let response = export(&self.state, request)
+            .await
+            .map_err(|e| Status::resource_exhausted(e.to_string()))?;
+
+        Ok(Response::new(response))
+    }
+}
+
+/// Apply the queued export requests to the state, until all senders are dropped.
+async fn drain_ingest_queue(
+    state: Arc<RwLock<State>>,
+    weak_tx: mpsc::WeakSender<ExportTraceServiceRequest>,
+    mut rx: mpsc::Receiver<ExportTraceServiceRequest>,
+) {
+    // Only hold a weak sender in the state, otherwise the queue will never be closed.
+    state.write().await.set_ingest_queue(weak_tx);
+
+    while let Some(request) = rx.recv().await {
+        // Errors are already logged, and there's no client to respond to.
+        let _ = export(&state, request).await;
+    }
+}
+
+/// Get the number of spans in the export request.
+fn span_count(request: &ExportTraceServiceRequest) -> usize {
+    (request.resource_spans.iter())
+        .flat_map(|r| &r.scope_spans)
+        .map(|s| s.spans.len())
+        .sum()
+}
+
+/// The export request contains more spans than allowed by
+/// [`Config::max_request_spans`](crate::Config::max_request_spans).
+#[derive(Debug)]
+pub(crate) struct TooManySpans {
+    pub spans: usize,
+    pub max: u32,
+}
+
+impl std::fmt::Display for TooManySpans {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "request contains {} spans, exceeding the limit of {}",
+            self.spans, self.max
+        )
+    }
+}
+
+/// Apply the export request to the state. Shared by both the gRPC and the HTTP ingest.
+///
+/// Returns an error without applying anything if the request contains too many spans.
+///
+/// Only the read lock is taken, so that concurrent exports proceed in parallel, and only
+/// serialize on adding the spans to the same shard of the traces. See [`Config::shards`].
+///
+/// [`Config::shards`]: crate::Config::shards
+pub(crate) async fn export(
+    state: &RwLock<State>,
+    request: ExportTraceServiceRequest,
+) -> Result<ExportTraceServiceResponse, TooManySpans> {
+    let start = std::time::Instant::now();
+    let spans = span_count(&request);
+
+    let state = state.read().await;
+
+    let max = state.max_request_spans();
+    if max > 0 && spans > max as usize {
+        tracing::warn!(spans, max, "rejected export request with too many spans");
+        return Err(TooManySpans { spans, max });
+    }
+
+    if let Some(forwarder) = state.forwarder() {
+        forwarder.forward(request.clone());
+    }
+    let rejected_spans = (request.resource_spans.into_iter())
+        .map(|resource_spans| state.commit(state.prepare(resource_spans)))
+        .sum::<usize>();
+    drop(state);
+
+    tracing::debug!(
+        spans,
+        rejected_spans,
+        elapsed = ?start.elapsed(),
+        "applied export request"
+    );
+
+    // Tell the client about the dropped spans so that it won't assume full acceptance.
+    let partial_success = (rejected_spans > 0).then(|| ExportTracePartialSuccess {
+        rejected_spans: rejected_spans as i64,
+        error_message: format!(
+            "{rejected_spans} spans rejected due to empty span ID, implausible timestamp or \
+            exceeding the limit per trace"
+        ),
+    });
+
+    Ok(ExportTraceServiceResponse { partial_success })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
 
-        let mut state = self.state.write().await;
-        for resource_spans in request.resource_spans {
-            state.apply(resource_spans);
+    use super::*;
+    use crate::{test_util::*, Config, StateRef};
+
+    #[tokio::test]
+    async fn test_partial_success() {
+        let state = State::new(Config {
+            max_spans_per_trace: 1,
+            ..Default::default()
+        });
+
+        // All spans accepted.
+        let response = ingest(&state, "svc", vec![span(1, 1, None)]).await;
+        assert!(response.partial_success.is_none());
+
+        // The second span of the trace exceeds the limit.
+        let response = ingest(&state, "svc", vec![span(1, 2, Some(1))]).await;
+        assert_eq!(response.partial_success.unwrap().rejected_spans, 1);
+
+        // Spans without an ID are rejected as well.
+        let mut empty = span(2, 1, None);
+        empty.span_id.clear();
+        let response = ingest(&state, "svc", vec![empty, span(3, 1, None)]).await;
+        assert_eq!(response.partial_success.unwrap().rejected_spans, 1);
+        assert_eq!(state.read().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_max_request_spans() {
+        let state = State::new(Config {
+            max_request_spans: 2,
+            ..Default::default()
+        });
+        let service = TraceServiceImpl::new(state.clone());
+
+        let spans = vec![span(1, 1, None), span(1, 2, Some(1)), span(1, 3, Some(1))];
+        let status = (service.export(Request::new(request("svc", spans))).await).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert!(state.read().await.is_empty());
+
+        let spans = vec![span(1, 1, None), span(1, 2, Some(1))];
+        let response = service.export(Request::new(request("svc", spans))).await;
+        assert!(response.unwrap().into_inner().partial_success.is_none());
+        assert_eq!(state.read().await.total_spans(), 2);
+    }
+
+    /// Export the spans of `traces` traces from each of `tasks` tasks concurrently.
+    async fn export_concurrently(state: &StateRef, tasks: u8, traces: u8) {
+        let handles = (0..tasks)
+            .map(|task| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    for i in 0..traces {
+                        let spans = (1..=4)
+                            .map(|id| {
+                                let mut span = span(0, id, (id > 1).then_some(1));
+                                span.trace_id = [task, i].repeat(8);
+                                span
+                            })
+                            .collect();
+                        ingest(&state, "svc", spans).await;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.await.unwrap();
         }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_export() {
+        // No span is lost.
+        let state = State::new(Config {
+            shards: 4,
+            max_length: 1000,
+            ..Default::default()
+        });
+        export_concurrently(&state, 8, 50).await;
+        let state = state.read().await;
+        assert_eq!(state.len(), 400);
+        assert_eq!(state.total_spans(), 1600);
+        assert_eq!(state.spans_ingested(), 1600);
+        assert!(state.get_all_complete().all(|t| t.span_count() == 4));
+        drop(state);
+
+        // The limits still hold.
+        let state = State::new(Config {
+            shards: 4,
+            max_length: 20,
+            max_memory_usage: 1 << 16,
+            ..Default::default()
+        });
+        export_concurrently(&state, 8, 50).await;
+        let state = state.read().await;
+        assert!(state.len() <= 20);
+        assert!(state.estimated_memory_usage() <= 1 << 16);
+        // A trace may be evicted by others in the same shard before all spans are received,
+        // in which case the rest start a new trace.
+        assert!(state.evicted_count() as usize + state.len() >= 400);
+    }
+
+    #[tokio::test]
+    async fn test_hook() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let state = State::with_capacity(10);
+        let seen = Arc::new(AtomicUsize::new(0));
+        let seen_clone = seen.clone();
+        let service = TraceServiceImpl::with_hook(
+            state.clone(),
+            Arc::new(move |request| {
+                seen_clone.fetch_add(span_count(request), Ordering::Relaxed);
+            }),
+        );
+
+        let spans = vec![span(1, 1, None), span(1, 2, Some(1))];
+        service
+            .export(Request::new(request("svc", spans)))
+            .await
+            .unwrap();
+        assert_eq!(seen.load(Ordering::Relaxed), 2);
+        assert_eq!(state.read().await.total_spans(), 2);
+    }
+
+    /// A subscriber that records the messages of the events along with the fields.
+    #[derive(Default, Clone)]
+    struct Recorder(Arc<std::sync::Mutex<Vec<String>>>);
+
+    struct Line(String);
+
+    impl tracing::field::Visit for Line {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut line = Line(event.metadata().level().to_string());
+            event.record(&mut line);
+            self.0.lock().unwrap().push(line.0);
+        }
+        fn enter(&self, _: &tracing::span::Id) {}
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[tokio::test]
+    async fn test_logging() {
+        let recorder = Recorder::default();
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+
+        let state = State::new(Config {
+            max_request_spans: 1,
+            ..Default::default()
+        });
+        ingest(&state, "svc", vec![span(1, 1, None)]).await;
+        let spans = vec![span(1, 2, Some(1)), span(1, 3, Some(1))];
+        export(&state, request("svc", spans)).await.unwrap_err();
+
+        let logs = recorder.0.lock().unwrap();
+        assert!(logs.iter().any(|l| l.starts_with("DEBUG")
+            && l.contains("message=applied export request")
+            && l.contains("spans=1 rejected_spans=0")));
+        assert!(logs.iter().any(|l| l.starts_with("WARN")
+            && l.contains("message=rejected export request with too many spans")
+            && l.contains("spans=2 max=1")));
+    }
+
+    #[tokio::test]
+    async fn test_ingest_queue() {
+        let state = State::with_capacity(10);
+        let service = TraceServiceImpl::new(state.clone()).with_ingest_queue(1);
+        // Let the background task start.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // Not applied until the background task runs, which is on the same thread.
+        let export = |trace| {
+            let request = request("svc", vec![span(trace, 1, None)]);
+            service.export(Request::new(request))
+        };
+        let response = export(1).await.unwrap().into_inner();
+        assert!(response.partial_success.is_none());
+        assert_eq!(state.read().await.ingest_queue_depth(), 1);
+        assert!(state.read().await.is_empty());
+
+        let response = export(2).await.unwrap().into_inner();
+        assert_eq!(response.partial_success.unwrap().rejected_spans, 1);
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let state = state.read().await;
+        assert_eq!(state.ingest_queue_depth(), 0);
+        assert_eq!(state.len(), 1);
+        assert!(state.peek_by_id(&trace_id(1)).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let clock = clock();
+        let state = State::new(Config {
+            max_exports_per_second: 2,
+            rate_limit_header: Some("x-tenant".to_owned()),
+            clock: clock.clone(),
+            ..Default::default()
+        });
+        let service = TraceServiceImpl::new(state.clone());
+
+        let export = |tenant: &str, trace| {
+            let mut request = Request::new(request("svc", vec![span(trace, 1, None)]));
+            (request.metadata_mut()).insert("x-tenant", tenant.parse().unwrap());
+            service.export(request)
+        };
+        export("a", 1).await.unwrap();
+        export("a", 2).await.unwrap();
+        let status = export("a", 3).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+        // Each tenant has its own quota.
+        export("b", 4).await.unwrap();
+
+        // The quota refills over time.
+        clock.advance(Duration::from_millis(500));
+        export("a", 5).await.unwrap();
+        assert!(export("a", 6).await.is_err());
 
-        Ok(Response::new(ExportTraceServiceResponse {
-            partial_success: None,
-        }))
+        let state = state.read().await;
+        assert_eq!(state.len(), 4);
+        assert!(state.peek_by_id(&trace_id(3)).is_none());
     }
 }
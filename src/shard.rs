@@ -0,0 +1,61 @@
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Get the shard in `0..shards` for the trace ID, for distributing traces across multiple
+/// [`State`](crate::State)s or instances consistently. The same is used to split the traces
+/// within a state into [`Config::shards`](crate::Config::shards).
+///
+/// The result only depends on the bytes of the ID, computed with the 64-bit FNV-1a hash,
+/// so it's stable across processes, platforms and versions of this crate. Note that the
+/// 64-bit and 128-bit forms of the same ID map to different shards, see
+/// [`Config::normalize_trace_ids`](crate::Config::normalize_trace_ids).
+///
+/// # Panics
+///
+/// Panics if `shards` is `0`.
+pub fn trace_shard(trace_id: &[u8], shards: usize) -> usize {
+    assert!(shards > 0, "number of shards must be positive");
+
+    let hash = (trace_id.iter()).fold(FNV_OFFSET_BASIS, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(FNV_PRIME)
+    });
+    // Take the high bits, since the low bits of FNV are poorly mixed, e.g., the lowest bit
+    // only depends on the lowest bits of the bytes.
+    ((hash as u128 * shards as u128) >> 64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_shard() {
+        let id = [0x4b, 0xf9, 0x2f, 0x35, 0x77, 0xb3, 0x4d, 0xa6].repeat(2);
+        assert_eq!(trace_shard(&id, 8), trace_shard(&id.clone(), 8));
+        assert_eq!(trace_shard(&id, 1), 0);
+        assert!(trace_shard(&id, 3) < 3);
+
+        // Roughly uniform, even for similar IDs with power-of-two shards.
+        for shards in [4, 7, 16] {
+            let mut counts = vec![0; shards];
+            for i in 0..=u8::MAX {
+                for j in 0..16 {
+                    counts[trace_shard(&[i, j].repeat(8), shards)] += 1;
+                }
+            }
+            let expected = 256 * 16 / shards;
+            assert!(
+                counts
+                    .iter()
+                    .all(|&c| c > expected * 3 / 4 && c < expected * 5 / 4),
+                "{counts:?}"
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_trace_shard_zero() {
+        trace_shard(&[1; 16], 0);
+    }
+}
@@ -0,0 +1,34 @@
+use serde::{Deserialize, Serialize};
+
+use crate::proto::trace::v1::ResourceSpans;
+
+/// A serializable snapshot of a [`Trace`](crate::Trace).
+///
+/// Placeholders of the parent spans that are not received yet are not stored, as they can be
+/// recovered from the parent span IDs of the received spans.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceSnapshot {
+    /// The hex-encoded trace ID.
+    pub trace_id: String,
+
+    /// The end time of the trace, in nanoseconds since the Unix epoch.
+    pub end_time_unix_nano: u64,
+
+    /// The number of spans dropped due to
+    /// [`Config::max_spans_per_trace`](crate::Config::max_spans_per_trace).
+    pub dropped_span_count: usize,
+
+    /// The received spans, grouped by the resource and the instrumentation scope.
+    pub resource_spans: Vec<ResourceSpans>,
+}
+
+/// A serializable snapshot of a [`State`](crate::State), for persisting the traces across
+/// restarts.
+///
+/// Created by [`State::export_snapshot`](crate::State::export_snapshot) and restored by
+/// [`State::import_snapshot`](crate::State::import_snapshot).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    /// The traces, from the least recently used one.
+    pub traces: Vec<TraceSnapshot>,
+}
@@ -1,12 +1,43 @@
-use std::{collections::BTreeSet, sync::Arc};
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, SystemTime},
+};
 
 use crate::{
-    limiter::MyLimiter,
-    proto::trace::v1::ResourceSpans,
-    trace::{SpanValue, Trace, TraceId},
+    clock::{Clock, SystemClock},
+    forward::Forwarder,
+    index::{tag_value, TagIndex},
+    intern::ResourceInterner,
+    limiter::{remove_explicitly, MyLimiter},
+    persist,
+    proto::{
+        collector::trace::v1::ExportTraceServiceRequest,
+        common::v1::{any_value, AnyValue, KeyValue},
+        resource::v1::Resource,
+        trace::v1::{status::StatusCode, ResourceSpans, Span},
+    },
+    rate_limit::RateLimiter,
+    redact::{glob_match, redact},
+    shard::trace_shard,
+    snapshot::{StateSnapshot, TraceSnapshot},
+    trace::{SpanNode, SpanValue, Trace, TraceId},
 };
+use datasize::DataSize;
+use itertools::Itertools;
+use regex::Regex;
 use schnellru::LruMap;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, mpsc, RwLock};
+
+/// The capacity of the channel for notifying the completed traces. Slow subscribers will
+/// miss the oldest notifications if lagging behind more than this.
+const COMPLETED_CHANNEL_CAPACITY: usize = 1024;
 
 /// Configuration for the [`State`].
 ///
@@ -14,112 +45,2790 @@ use tokio::sync::RwLock;
 /// is reached, the oldest traces will be evicted.
 pub struct Config {
     /// The maximum number of traces to keep.
+    ///
+    /// Must be positive, as `0` makes the state reject all traces.
     pub max_length: u32,
 
     /// The maximum memory usage of the traces in bytes.
     ///
-    /// The memory usage is estimated and the actual value may be higher.
+    /// The memory usage is estimated and the actual value may be higher. Must be positive.
     pub max_memory_usage: usize,
+
+    /// The number of shards to split the traces into, each with its own lock, so that the
+    /// spans of different traces can be ingested in parallel. Must be positive.
+    ///
+    /// Sharding is opt-in. The default of `1` keeps all traces under a single lock, so that
+    /// concurrent exports are serialized on adding the spans. Set it to around the number of
+    /// threads exporting concurrently to reduce the contention.
+    ///
+    /// Traces are assigned to the shards by [`trace_shard`](crate::trace_shard) of their IDs.
+    /// [`Config::max_length`] and [`Config::max_memory_usage`] are split evenly among the
+    /// shards, so the limits still hold approximately, but the traces are evicted by the
+    /// order within each shard instead of a global one.
+    pub shards: usize,
+
+    /// Treat a trace as complete if it has not ended within this duration, even if
+    /// some of the spans (including the root span) are still missing.
+    ///
+    /// Without this, a trace whose root span is lost will never show up in the UI.
+    /// On the other hand, a shorter value may expose traces with spans that are
+    /// exported late in a partial state. The earliest-starting span will be used
+    /// as the root span if the actual one is missing.
+    pub force_complete_after: Duration,
+
+    /// Whether to use the earliest-starting span as a pseudo-root if the actual root span of
+    /// a trace is missing. Defaults to `true`.
+    ///
+    /// Without this, such traces have no service name or operation, and are excluded from
+    /// the service list and the search.
+    pub pseudo_root: bool,
+
+    /// Whether to add a `message` attribute with the name of each span event, unless it
+    /// already has one. Defaults to `true`.
+    ///
+    /// Tempo only displays the events with such attribute, while Jaeger already shows the
+    /// event name, so the attribute duplicates data if not viewed in Tempo.
+    pub add_event_message_attribute: bool,
+
+    /// Whether to left-pad 64-bit trace IDs with zeros to 128 bits on ingestion and lookup,
+    /// so that both forms refer to the same trace. Defaults to `true`.
+    ///
+    /// Some producers emit 64-bit trace IDs, while the UI and other tools may refer to them
+    /// in 128 bits, or the other way around.
+    pub normalize_trace_ids: bool,
+
+    /// The maximum number of attributes to keep for each span. `0` means unlimited.
+    ///
+    /// Exceeding attributes are dropped and counted in `dropped_attributes_count`.
+    pub max_span_attributes: u32,
+
+    /// The maximum number of events to keep for each span. `0` means unlimited.
+    ///
+    /// Exceeding events are dropped and counted in `dropped_events_count`.
+    pub max_span_events: u32,
+
+    /// The maximum number of links to keep for each span. `0` means unlimited.
+    ///
+    /// Exceeding links are dropped and counted in `dropped_links_count`.
+    pub max_span_links: u32,
+
+    /// The maximum number of spans to keep for each trace, not counting the parents that are
+    /// referenced but not received yet. `0` means unlimited.
+    ///
+    /// This bounds the memory usage of pathological traces, e.g., from an infinite recursion.
+    /// Exceeding spans are rejected and counted in [`Trace::dropped_span_count`].
+    pub max_spans_per_trace: u32,
+
+    /// The maximum number of events to keep across all spans of each trace. `0` means
+    /// unlimited.
+    ///
+    /// Unlike [`Config::max_span_events`], this bounds the traces with many spans each logging
+    /// a few events. The events received first are kept, and exceeding ones are dropped and
+    /// counted in `dropped_events_count` of their spans.
+    pub max_events_per_trace: u32,
+
+    /// The attribute keys whose values should be redacted before being stored, where `*`
+    /// matches any sequence of characters. For example, `http.request.header.*`.
+    ///
+    /// This applies to the attributes of resources, spans and span events.
+    pub redact_keys: Vec<String>,
+
+    /// The only span attribute keys to keep, where `*` matches any sequence of characters.
+    /// `None` or an empty list means keeping all attributes.
+    ///
+    /// Other attributes are dropped and counted in `dropped_attributes_count`, before
+    /// [`Config::max_span_attributes`] applies. Attributes of span events are not affected.
+    pub keep_attributes: Option<Vec<String>>,
+
+    /// Whether to apply [`Config::keep_attributes`] to the resource attributes as well.
+    /// Defaults to `false`.
+    ///
+    /// Note that the resource attributes identify the services, so `service.name` and
+    /// `service.instance.id` should be kept if this is enabled.
+    pub keep_resource_attributes: bool,
+
+    /// Rules to normalize the span names into operation names, as pairs of a regular
+    /// expression and its replacement, e.g., `(r"/user/\d+", "/user/{id}")`.
+    ///
+    /// All matches of each rule are replaced in order. The normalized names are used for
+    /// the operation list, the statistics and the search, reducing the cardinality, while
+    /// the spans keep the original names for display. Invalid rules are ignored.
+    pub operation_rules: Vec<(String, String)>,
+
+    /// The maximum age of the traces, measured from their end time. `None` means unlimited.
+    ///
+    /// Expired traces are hidden from the queries, and dropped lazily when accessed by ID
+    /// or explicitly by [`State::evict_expired`].
+    pub max_age: Option<Duration>,
+
+    /// The maximum number of spans in a single export request. `0` means unlimited.
+    ///
+    /// Requests exceeding the limit are rejected as a whole without being applied.
+    pub max_request_spans: u32,
+
+    /// The maximum size of a request body received over OTLP/HTTP in bytes. Defaults to 4 MiB,
+    /// the same as the default limit of gRPC messages.
+    ///
+    /// Requests exceeding the limit are rejected with `413 Payload Too Large` before being
+    /// decoded. The nesting depth of the protobuf messages is also bounded by the decoder.
+    pub max_request_body_size: usize,
+
+    /// The maximum number of export requests per second from each source, over both OTLP/gRPC
+    /// and OTLP/HTTP. `0` means unlimited, which is the default.
+    ///
+    /// Sources are identified by [`Config::rate_limit_header`] if configured, or the IP address
+    /// of the peer otherwise. Requests exceeding the rate are rejected with
+    /// `RESOURCE_EXHAUSTED` over gRPC, or `429 Too Many Requests` over HTTP. Bursts up to the
+    /// number of requests per second are allowed.
+    pub max_exports_per_second: u32,
+
+    /// The header identifying the source of export requests for
+    /// [`Config::max_exports_per_second`], e.g., a tenant ID or the client IP set by a proxy.
+    pub rate_limit_header: Option<String>,
+
+    /// The ratio of traces to keep, from `0.0` to `1.0`. Defaults to `1.0`, i.e., keep all.
+    ///
+    /// The decision is made consistently based on the trace ID, so that the spans of the same
+    /// trace are either all kept or all dropped. Forwarding is not affected.
+    pub sample_ratio: f64,
+
+    /// Whether to always keep the spans with an error status, even if their traces are not
+    /// sampled. Defaults to `true`.
+    ///
+    /// Once an error span is kept, the subsequent spans of the same trace are kept as well.
+    /// The spans received before are lost, though.
+    pub always_sample_errors: bool,
+
+    /// Whether to keep the traces containing an error span longer than the ordinary ones when
+    /// evicting. Defaults to `false`.
+    ///
+    /// Such traces are only evicted after all ordinary ones, so the limits still hold.
+    pub retain_errors: bool,
+
+    /// Keep the traces containing a span longer than this duration longer than the ordinary
+    /// ones when evicting, same as [`Config::retain_errors`]. `None` means disabled.
+    pub retain_slower_than: Option<Duration>,
+
+    /// Whether to make the sampling decision when a trace becomes complete, instead of for
+    /// each span. Defaults to `false`.
+    ///
+    /// With this, a trace is kept if it meets any of [`Config::keep_if_error`] and
+    /// [`Config::keep_if_slower_than`], or sampled with [`Config::sample_ratio`] otherwise.
+    /// [`Config::always_sample_errors`] is not used.
+    ///
+    /// All spans are kept until their traces complete, which costs more memory. Spans received
+    /// after a trace is dropped start a new trace, which will be decided again. Traces treated
+    /// as complete due to [`Config::force_complete_after`] are hidden from the queries if not
+    /// kept, and dropped by [`State::tail_sample`].
+    pub tail_sampling: bool,
+
+    /// Whether to keep the traces containing an error span with [`Config::tail_sampling`].
+    /// Defaults to `true`.
+    pub keep_if_error: bool,
+
+    /// Keep the traces lasting longer than this duration with [`Config::tail_sampling`].
+    /// `None` means disabled.
+    pub keep_if_slower_than: Option<Duration>,
+
+    /// How to handle the spans with implausible timestamps. Defaults to
+    /// [`TimestampValidation::Off`].
+    pub timestamp_validation: TimestampValidation,
+
+    /// The policy to choose the traces to evict when either limit is reached. Defaults to
+    /// [`EvictionPolicy::Lru`].
+    pub eviction_policy: EvictionPolicy,
+
+    /// Whether to maintain an index of the attributes of spans and resources, so that the
+    /// traces can be searched by tags without scanning all spans. Defaults to `false`.
+    ///
+    /// The index is accounted in the memory usage. Values longer than 128 bytes are not
+    /// indexed, and won't be found by [`State::get_all_complete_with_tags`].
+    pub index_tags: bool,
+
+    /// The OTLP/gRPC endpoint to forward all export requests to, e.g., `http://localhost:4317`.
+    /// `None` means no forwarding.
+    ///
+    /// Requests are forwarded as received, before any redaction or truncation. Forwarding is
+    /// done in the background with timeouts and a few retries, and failures are logged without
+    /// affecting the local ingestion. Requests are dropped if too many are pending, which is
+    /// counted in [`Diagnostics::dropped_forwards`]. An invalid endpoint disables forwarding
+    /// with an error logged.
+    pub forward_endpoint: Option<String>,
+
+    /// The file to persist the traces to, so that they survive restarts. `None` means no
+    /// persistence.
+    ///
+    /// The traces are loaded from the file when creating the [`State`], if it exists. To save
+    /// them periodically, spawn [`persist_periodically`](crate::persist_periodically).
+    pub persist_path: Option<PathBuf>,
+
+    /// The source of the current time. Defaults to [`SystemClock`].
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Config {
+    /// The default maximum number of traces to keep.
+    pub const DEFAULT_MAX_LENGTH: u32 = 100;
+
+    /// The default maximum memory usage of the traces in bytes, which is 128 MiB.
+    pub const DEFAULT_MAX_MEMORY_USAGE: usize = 1 << 27;
+
+    /// The default duration after which a trace is treated as complete, which is 1 minute.
+    pub const DEFAULT_FORCE_COMPLETE_AFTER: Duration = Duration::from_secs(60);
+}
+
+/// How to handle the spans with implausible timestamps, used by
+/// [`Config::timestamp_validation`].
+///
+/// A timestamp is implausible if the span starts before 2000 or after 2100, which usually
+/// means a buggy SDK sends it in milliseconds or seconds instead of nanoseconds. Such spans
+/// appear to start in 1970 with absurd durations. They're counted in
+/// [`Diagnostics::implausible_timestamp`] unless the validation is off.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimestampValidation {
+    /// Accept all spans without checking.
+    #[default]
+    Off,
+    /// Reject the spans with implausible timestamps.
+    Reject,
+    /// Accept the spans with implausible timestamps, but add a warning attribute
+    /// `otlp_embedded.warning` to them.
+    Flag,
+}
+
+/// The policy to choose the traces to evict, used by [`Config::eviction_policy`].
+///
+/// Traces retained by [`Config::retain_errors`] or [`Config::retain_slower_than`] are only
+/// evicted after all ordinary ones under any policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evict the least recently used trace first, where receiving a span or getting the trace
+    /// by ID counts as a use.
+    ///
+    /// Traces that are still being received or looked at are kept.
+    #[default]
+    Lru,
+
+    /// Evict the trace that's first received first, regardless of the later uses.
+    ///
+    /// This keeps the most recent traces, but a long-running trace may be evicted while still
+    /// receiving spans, leaving the later spans in a new partial trace.
+    Fifo,
+
+    /// Evict the largest trace in terms of the estimated memory usage first.
+    ///
+    /// This reclaims the most memory with the fewest traces evicted, and protects the small
+    /// traces from being flushed by a few huge ones. However, each eviction scans all traces,
+    /// and a small trace may stay until evicted due to [`Config::max_length`].
+    LargestFirst,
+}
+
+/// An error of invalid [`Config`], returned by [`Config::validate`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// [`Config::max_length`] is `0`.
+    ZeroMaxLength,
+    /// [`Config::max_memory_usage`] is `0`.
+    ZeroMaxMemoryUsage,
+    /// [`Config::shards`] is `0`.
+    ZeroShards,
+    /// [`Config::sample_ratio`] is not within `0.0..=1.0`.
+    InvalidSampleRatio(f64),
+    /// [`Config::forward_endpoint`] is not a valid URI.
+    InvalidForwardEndpoint(String),
+    /// A pattern in [`Config::operation_rules`] is not a valid regular expression.
+    InvalidOperationRule(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ZeroMaxLength => write!(f, "`max_length` must be positive"),
+            Self::ZeroMaxMemoryUsage => write!(f, "`max_memory_usage` must be positive"),
+            Self::ZeroShards => write!(f, "`shards` must be positive"),
+            Self::InvalidSampleRatio(r) => {
+                write!(f, "`sample_ratio` must be within 0.0 and 1.0, got {r}")
+            }
+            Self::InvalidForwardEndpoint(e) => write!(f, "invalid `forward_endpoint`: {e}"),
+            Self::InvalidOperationRule(e) => write!(f, "invalid `operation_rules`: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Check whether the configuration makes sense.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_length == 0 {
+            return Err(ConfigError::ZeroMaxLength);
+        }
+        if self.max_memory_usage == 0 {
+            return Err(ConfigError::ZeroMaxMemoryUsage);
+        }
+        if self.shards == 0 {
+            return Err(ConfigError::ZeroShards);
+        }
+        if !(0.0..=1.0).contains(&self.sample_ratio) {
+            return Err(ConfigError::InvalidSampleRatio(self.sample_ratio));
+        }
+        for (pattern, _) in &self.operation_rules {
+            Regex::new(pattern)
+                .map_err(|e| ConfigError::InvalidOperationRule(format!("{pattern}: {e}")))?;
+        }
+        if let Some(endpoint) = &self.forward_endpoint {
+            Forwarder::new(endpoint)
+                .map_err(|e| ConfigError::InvalidForwardEndpoint(format!("{endpoint}: {e}")))?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_length: Self::DEFAULT_MAX_LENGTH,
+            max_memory_usage: Self::DEFAULT_MAX_MEMORY_USAGE,
+            shards: 1,
+            force_complete_after: Self::DEFAULT_FORCE_COMPLETE_AFTER,
+            pseudo_root: true,
+            add_event_message_attribute: true,
+            normalize_trace_ids: true,
+            max_span_attributes: 0,
+            max_span_events: 0,
+            max_span_links: 0,
+            max_spans_per_trace: 0,
+            max_events_per_trace: 0,
+            redact_keys: Vec::new(),
+            keep_attributes: None,
+            keep_resource_attributes: false,
+            operation_rules: Vec::new(),
+            max_age: None,
+            max_request_spans: 0,
+            max_request_body_size: 4 << 20,
+            max_exports_per_second: 0,
+            rate_limit_header: None,
+            sample_ratio: 1.0,
+            always_sample_errors: true,
+            retain_errors: false,
+            retain_slower_than: None,
+            tail_sampling: false,
+            keep_if_error: true,
+            keep_if_slower_than: None,
+            timestamp_validation: TimestampValidation::Off,
+            eviction_policy: EvictionPolicy::Lru,
+            index_tags: false,
+            forward_endpoint: None,
+            persist_path: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+/// Truncate the items to at most `max` if it's not `0`, and add the number of dropped items
+/// to `dropped`.
+fn truncate<T>(items: &mut Vec<T>, max: u32, dropped: &mut u32) {
+    if max > 0 && items.len() > max as usize {
+        *dropped += (items.len() - max as usize) as u32;
+        items.truncate(max as usize);
+    }
+}
+
+fn is_error(span: &Span) -> bool {
+    (span.status.as_ref()).is_some_and(|s| s.code() == StatusCode::Error)
+}
+
+/// The criteria of [`Config::tail_sampling`].
+#[derive(Debug, Clone, Copy)]
+struct TailSampler {
+    sample_ratio: f64,
+    keep_if_error: bool,
+    keep_if_slower_than: Option<Duration>,
+}
+
+impl TailSampler {
+    /// Check whether the trace is complete and should be discarded.
+    fn discard(sampler: Option<Self>, trace: &Trace) -> bool {
+        sampler.is_some_and(|sampler| trace.is_complete() && !sampler.keep(trace))
+    }
+
+    /// Check whether the trace should be kept.
+    ///
+    /// The result never changes from `true` to `false` as more spans are received, so it's
+    /// fine to decide before the trace is actually complete.
+    fn keep(&self, trace: &Trace) -> bool {
+        (trace.iter_valid().next()).is_some_and(|v| is_sampled(&v.span.trace_id, self.sample_ratio))
+            || (self.keep_if_error && trace.iter_valid().any(|v| is_error(&v.span)))
+            || (self.keep_if_slower_than)
+                .is_some_and(|threshold| trace.duration().is_some_and(|d| d > threshold))
+    }
+}
+
+/// Notify the subscribers if the trace becomes complete for the first time.
+fn notify_if_completed(trace: &mut Trace, id: &[u8], tx: &broadcast::Sender<Vec<u8>>) {
+    if trace.is_complete() && !trace.notified {
+        trace.notified = true;
+        // There may be no subscribers.
+        let _ = tx.send(id.to_vec());
+    }
+}
+
+/// Check whether the timestamp in nanoseconds is between 2000 and 2100.
+fn is_plausible_timestamp(unix_nano: u64) -> bool {
+    const YEAR_2000: u64 = 946_684_800_000_000_000;
+    const YEAR_2100: u64 = 4_102_444_800_000_000_000;
+    (YEAR_2000..YEAR_2100).contains(&unix_nano)
+}
+
+/// Check whether the trace is sampled with the given ratio.
+///
+/// Same as the `TraceIdRatioBased` sampler of OpenTelemetry, the lower 8 bytes of the trace ID,
+/// which are supposed to be random, are compared against the ratio.
+fn is_sampled(trace_id: &[u8], ratio: f64) -> bool {
+    if ratio >= 1.0 {
+        return true;
+    }
+    let lower = trace_id
+        .iter()
+        .rev()
+        .take(8)
+        .rev()
+        .fold(0u64, |acc, b| (acc << 8) | *b as u64);
+    (lower >> 1) < (ratio.max(0.0) * (1u64 << 63) as f64) as u64
+}
+
+/// Diagnostics of the ingested spans, for debugging why a trace looks incomplete or broken.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Diagnostics {
+    /// The number of spans dropped due to an empty span ID.
+    pub empty_span_id: u64,
+    /// The number of spans whose end time is not after the start time.
+    pub non_positive_duration: u64,
+    /// The number of spans with implausible timestamps, if [`Config::timestamp_validation`]
+    /// is enabled.
+    pub implausible_timestamp: u64,
+    /// The number of spans referenced as parents but not received yet, in the current traces.
+    pub missing_parents: usize,
+    /// The number of export requests dropped without being forwarded to
+    /// [`Config::forward_endpoint`], due to a full queue or failures after retries.
+    pub dropped_forwards: u64,
+}
+
+/// The spans prepared by [`State::prepare`] to be added by [`State::commit`].
+#[derive(Default)]
+pub(crate) struct Prepared {
+    values: Vec<SpanValue>,
+    /// The number of spans already rejected.
+    rejected: usize,
+    implausible_timestamp: u64,
+    non_positive_duration: u64,
+}
+
+/// Statistics of an operation, computed from the root spans of the traces.
+#[derive(Debug, Clone)]
+pub struct OperationStat {
+    /// The name of the operation.
+    pub name: String,
+    /// The number of traces.
+    pub count: usize,
+    /// The 50th percentile of the root span duration.
+    pub p50: Duration,
+    /// The 95th percentile of the root span duration.
+    pub p95: Duration,
+    /// The 99th percentile of the root span duration.
+    pub p99: Duration,
+    /// The number of traces whose root span has an error status.
+    pub error_count: usize,
+}
+
+/// A bucket of [`State::duration_histogram`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DurationBucket {
+    /// The inclusive lower bound of the durations.
+    pub start: Duration,
+    /// The exclusive upper bound of the durations, or inclusive for the last bucket.
+    pub end: Duration,
+    /// The number of traces in the bucket.
+    pub count: usize,
+}
+
+/// Get the percentile of the sorted durations with the nearest-rank method.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Keep only the attributes whose keys match any of the patterns if not empty, and add the
+/// number of dropped attributes to `dropped`.
+fn keep(attributes: &mut Vec<KeyValue>, patterns: &[String], dropped: &mut u32) {
+    if patterns.is_empty() {
+        return;
+    }
+
+    let len = attributes.len();
+    attributes.retain(|kv| patterns.iter().any(|p| glob_match(p, &kv.key)));
+    *dropped += (len - attributes.len()) as u32;
+}
+
+/// A subset of the traces with its own lock, chosen by [`trace_shard`] of the trace ID, so
+/// that spans of different traces can be added concurrently.
+struct Shard {
+    traces: LruMap<TraceId, Trace, MyLimiter>,
+    /// IDs of the traces to retain longer, in the order of being pinned.
+    pinned: Vec<TraceId>,
+    tag_index: Option<TagIndex>,
+    resources: ResourceInterner,
+}
+
+impl Shard {
+    /// Evict the largest traces until within the limits, for [`EvictionPolicy::LargestFirst`].
+    /// The map evicts by itself for the other policies.
+    fn evict_largest(&mut self) {
+        while self.traces.limiter().is_over(self.traces.len()) {
+            let largest = (self.traces.iter())
+                .max_by_key(|(id, trace)| (!self.pinned.contains(id), trace.heap_size))
+                .map(|(id, _)| id.clone());
+            let Some(id) = largest else {
+                break;
+            };
+            self.traces.remove(&id);
+        }
+    }
+
+    /// Remove the traces removed from the map from the tag index as well.
+    fn sync_index(&mut self) {
+        for id in self.traces.limiter_mut().take_removed() {
+            self.unindex(&id);
+        }
+    }
+
+    fn unindex(&mut self, id: &[u8]) {
+        if let Some(index) = &mut self.tag_index {
+            let size = index.remove(id);
+            self.traces.limiter_mut().on_resized(-(size as isize));
+        }
+    }
+
+    /// Promote the pinned traces to the most recent, so that the ordinary ones will be
+    /// evicted first. IDs of the traces that are already gone are dropped.
+    fn promote_pinned(&mut self) {
+        let traces = &mut self.traces;
+        self.pinned.retain(|id| traces.get(id).is_some());
+    }
+}
+
+/// Index the tags of a span accepted by the trace, returning the increment of the estimated
+/// memory usage.
+fn index_tags(
+    index: &mut Option<TagIndex>,
+    id: &[u8],
+    tags: Option<(Vec<KeyValue>, Arc<Resource>)>,
+) -> isize {
+    match (index, tags) {
+        (Some(index), Some((attributes, resource))) => {
+            (index.insert(id, &attributes) + index.insert(id, &resource.attributes)) as isize
+        }
+        _ => 0,
+    }
+}
+
+/// Lock the shard. A listener panicking with the lock held leaves no partial update behind,
+/// so the poisoning is ignored.
+fn lock(shard: &Mutex<Shard>) -> MutexGuard<'_, Shard> {
+    shard.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The counters of the ingestion, which are updated concurrently.
+#[derive(Default)]
+struct Counters {
+    spans_ingested: AtomicU64,
+    empty_span_id: AtomicU64,
+    non_positive_duration: AtomicU64,
+    implausible_timestamp: AtomicU64,
 }
 
 /// In-memory state that maintains the most recent traces.
 ///
 /// Old traces that are no longer updated or accessed will be evicted
 /// when the capacity is reached.
+///
+/// The traces are split into [`Config::shards`], each with its own lock. Ingestion only
+/// takes the read lock of the [`StateRef`], so that concurrent exports of different traces
+/// proceed in parallel.
 pub struct State {
-    traces: LruMap<TraceId, Trace, MyLimiter>,
+    shards: Box<[Mutex<Shard>]>,
+    force_complete_after: Duration,
+    pseudo_root: bool,
+    add_event_message_attribute: bool,
+    normalize_trace_ids: bool,
+    max_span_attributes: u32,
+    max_span_events: u32,
+    max_span_links: u32,
+    max_spans_per_trace: u32,
+    max_events_per_trace: u32,
+    redact_keys: Vec<String>,
+    keep_attributes: Vec<String>,
+    keep_resource_attributes: bool,
+    operation_rules: Vec<(Regex, String)>,
+    max_age: Option<Duration>,
+    max_request_spans: u32,
+    max_request_body_size: usize,
+    rate_limiter: Option<RateLimiter>,
+    rate_limit_header: Option<String>,
+    sample_ratio: f64,
+    always_sample_errors: bool,
+    retain_errors: bool,
+    retain_slower_than: Option<Duration>,
+    tail_sampler: Option<TailSampler>,
+    timestamp_validation: TimestampValidation,
+    eviction_policy: EvictionPolicy,
+    forwarder: Option<Forwarder>,
+    persist_path: Option<PathBuf>,
+    clock: Arc<dyn Clock>,
+    counters: Counters,
+    completed_tx: broadcast::Sender<Vec<u8>>,
+    /// The queue of export requests pending to be applied, if enabled by
+    /// [`TraceServiceImpl::with_ingest_queue`](crate::TraceServiceImpl::with_ingest_queue).
+    ingest_queue: Option<mpsc::WeakSender<ExportTraceServiceRequest>>,
+    span_listeners: Vec<SpanListener>,
 }
 
+/// A listener invoked on each span added to the [`State`], along with the resource it belongs
+/// to. See [`State::add_span_listener`].
+pub type SpanListener = Box<dyn Fn(&Span, &Resource) + Send + Sync>;
+
 /// A reference to the [`State`].
 pub type StateRef = Arc<RwLock<State>>;
 
 impl State {
+    /// Create a new [`State`] with the given configuration, after validating it with
+    /// [`Config::validate`].
+    pub fn try_new(config: Config) -> Result<StateRef, ConfigError> {
+        config.validate()?;
+        Ok(Self::new(config))
+    }
+
     /// Create a new [`State`] with the given configuration.
+    ///
+    /// The configuration is not validated. Use [`State::try_new`] to reject the invalid ones.
     pub fn new(
         Config {
             max_length,
             max_memory_usage,
+            shards,
+            force_complete_after,
+            pseudo_root,
+            add_event_message_attribute,
+            normalize_trace_ids,
+            max_span_attributes,
+            max_span_events,
+            max_span_links,
+            max_spans_per_trace,
+            max_events_per_trace,
+            redact_keys,
+            keep_attributes,
+            keep_resource_attributes,
+            operation_rules,
+            max_age,
+            max_request_spans,
+            max_request_body_size,
+            max_exports_per_second,
+            rate_limit_header,
+            sample_ratio,
+            always_sample_errors,
+            retain_errors,
+            retain_slower_than,
+            tail_sampling,
+            keep_if_error,
+            keep_if_slower_than,
+            timestamp_validation,
+            eviction_policy,
+            index_tags,
+            forward_endpoint,
+            persist_path,
+            clock,
         }: Config,
     ) -> StateRef {
-        let this = Self {
-            traces: LruMap::new(MyLimiter::new(max_memory_usage, max_length)),
+        let operation_rules = (operation_rules.into_iter())
+            .filter_map(|(pattern, replacement)| {
+                Regex::new(&pattern)
+                    .inspect_err(|e| {
+                        tracing::error!(pattern, error = %e, "invalid operation rule, ignored");
+                    })
+                    .ok()
+                    .map(|regex| (regex, replacement))
+            })
+            .collect();
+
+        let forwarder = forward_endpoint.and_then(|endpoint| {
+            Forwarder::new(&endpoint)
+                .inspect_err(|e| {
+                    let msg = "invalid forward endpoint, forwarding disabled";
+                    tracing::error!(endpoint, error = %e, msg);
+                })
+                .ok()
+        });
+
+        // Not validated, so fall back to a single shard instead of panicking.
+        let shards = shards.max(1);
+        let shards = (0..shards)
+            .map(|_| {
+                Mutex::new(Shard {
+                    traces: LruMap::new(MyLimiter::new(
+                        max_memory_usage.div_ceil(shards),
+                        max_length.div_ceil(shards as u32),
+                        eviction_policy == EvictionPolicy::LargestFirst,
+                    )),
+                    pinned: Vec::new(),
+                    tag_index: index_tags.then(TagIndex::default),
+                    resources: ResourceInterner::default(),
+                })
+            })
+            .collect();
+
+        let mut this = Self {
+            shards,
+            force_complete_after,
+            pseudo_root,
+            add_event_message_attribute,
+            normalize_trace_ids,
+            max_span_attributes,
+            max_span_events,
+            max_span_links,
+            max_spans_per_trace,
+            max_events_per_trace,
+            redact_keys,
+            keep_attributes: keep_attributes.unwrap_or_default(),
+            keep_resource_attributes,
+            operation_rules,
+            max_age,
+            max_request_spans,
+            max_request_body_size,
+            rate_limiter: (max_exports_per_second > 0)
+                .then(|| RateLimiter::new(max_exports_per_second)),
+            rate_limit_header,
+            sample_ratio,
+            always_sample_errors,
+            retain_errors,
+            retain_slower_than,
+            tail_sampler: tail_sampling.then_some(TailSampler {
+                sample_ratio,
+                keep_if_error,
+                keep_if_slower_than,
+            }),
+            timestamp_validation,
+            eviction_policy,
+            forwarder,
+            persist_path,
+            clock,
+            counters: Counters::default(),
+            completed_tx: broadcast::channel(COMPLETED_CHANNEL_CAPACITY).0,
+            ingest_queue: None,
+            span_listeners: Vec::new(),
         };
 
+        if let Some(path) = this.persist_path.clone() {
+            match persist::load(&path) {
+                Ok(Some(snapshot)) => {
+                    this.import_snapshot(snapshot);
+                    tracing::info!(path = %path.display(), traces = this.len(), "loaded state");
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::error!(path = %path.display(), error = %e, "failed to load state")
+                }
+            }
+        }
+
         Arc::new(RwLock::new(this))
     }
 
-    fn add_value(&mut self, value: SpanValue) {
-        // Use a pair of `remove` and `insert` to maintain the memory usage correctly.
-        let mut trace = self.traces.remove(&value.span.trace_id).unwrap_or_default();
-        let id = value.span.trace_id.clone();
-        trace.add_value(value);
+    /// Create a new [`State`] that keeps at most `max_length` traces, with the
+    /// default memory limit of [`Config::DEFAULT_MAX_MEMORY_USAGE`].
+    ///
+    /// ```
+    /// use otlp_embedded::State;
+    ///
+    /// let state = State::with_capacity(100);
+    /// assert_eq!(state.blocking_read().len(), 0);
+    /// ```
+    pub fn with_capacity(max_length: u32) -> StateRef {
+        Self::new(Config {
+            max_length,
+            ..Default::default()
+        })
+    }
 
-        self.traces.insert(id, trace);
+    /// Lock the shard of the trace, whose ID must be normalized.
+    fn shard(&self, id: &[u8]) -> MutexGuard<'_, Shard> {
+        lock(&self.shards[trace_shard(id, self.shards.len())])
     }
 
-    pub(crate) fn apply(&mut self, resource_spans: ResourceSpans) {
-        let ResourceSpans {
-            resource,
-            scope_spans,
-            schema_url: _,
-        } = resource_spans;
+    /// Lock each shard in turn, holding one lock at a time if each guard is dropped before
+    /// the next one.
+    fn each_shard(&self) -> impl Iterator<Item = MutexGuard<'_, Shard>> + '_ {
+        self.shards.iter().map(lock)
+    }
+
+    /// Collect the results of `f` on all traces, shard by shard.
+    fn collect_traces<T>(&self, mut f: impl FnMut(&TraceId, &Trace) -> Option<T>) -> Vec<T> {
+        let mut results = Vec::new();
+        for shard in self.each_shard() {
+            results.extend((shard.traces.iter()).filter_map(|(id, trace)| f(id, trace)));
+        }
+        results
+    }
+
+    /// Check whether the span should be kept according to the sampling configuration.
+    fn should_sample(&self, span: &Span) -> bool {
+        // Decided later when the trace completes.
+        if self.tail_sampler.is_some() {
+            return true;
+        }
+        if is_sampled(&span.trace_id, self.sample_ratio) {
+            return true;
+        }
+        if !self.always_sample_errors {
+            return false;
+        }
+        // Also keep the rest of the trace if some error span has been kept.
+        is_error(span)
+            || self
+                .shard(&span.trace_id)
+                .traces
+                .peek(&span.trace_id)
+                .is_some()
+    }
+
+    fn add_value(&self, mut value: SpanValue) -> bool {
+        // Spans without an ID cannot be placed in the tree. Reject them before touching the map
+        // so that we don't leave an empty trace behind.
+        if value.span.span_id.is_empty() {
+            self.counters.empty_span_id.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        let id = value.span.trace_id.clone();
+        let pin = self.should_pin(&value.span);
+        let mut shard = self.shard(&id);
+        let shard = &mut *shard;
+
+        // Identical resources are shared among traces, which are accounted once for each trace.
+        value.resource = shard.resources.intern(value.resource);
+
+        for listener in &self.span_listeners {
+            listener(&value.span, &value.resource);
+        }
+
+        // The tags are only indexed once the span is accepted by the trace, so keep a copy.
+        let tags = (shard.tag_index.is_some())
+            .then(|| (value.span.attributes.clone(), value.resource.clone()));
+        let mut accepted = true;
+
+        let trace = match self.eviction_policy {
+            EvictionPolicy::Lru => shard.traces.get(&id),
+            EvictionPolicy::Fifo | EvictionPolicy::LargestFirst => shard.traces.peek_mut(&id),
+        };
 
-        let resource = Arc::new(resource.unwrap_or_default());
+        if let Some(trace) = trace {
+            // Update the trace in place and only account for the change of the memory usage.
+            let (dropped, spans) = (trace.dropped, trace.span_count());
+            let delta = trace.add_value(value);
+            accepted = trace.dropped == dropped;
+            let added = trace.span_count() - spans;
+            let discard = TailSampler::discard(self.tail_sampler, trace);
+            if !discard {
+                notify_if_completed(trace, &id, &self.completed_tx);
+            }
+            let indexed = match accepted {
+                true => index_tags(&mut shard.tag_index, &id, tags),
+                false => 0,
+            };
+            shard.traces.limiter_mut().on_resized(delta + indexed);
+            shard.traces.limiter_mut().on_spans_added(added);
 
-        for span in scope_spans.into_iter().flat_map(|s| s.spans) {
-            let value = SpanValue {
-                span,
-                resource: resource.clone(),
+            if discard {
+                remove_explicitly(&mut shard.traces, &id);
+            }
+            // The map won't check the limit for in-place updates, so evict the oldest traces
+            // by ourselves if needed.
+            else if shard.traces.limiter().is_over_memory()
+                && self.eviction_policy != EvictionPolicy::LargestFirst
+            {
+                shard.promote_pinned();
+                while shard.traces.limiter().is_over_memory() && shard.traces.pop_oldest().is_some()
+                {
+                }
+            }
+        } else {
+            let mut trace = Trace {
+                pseudo_root: self.pseudo_root,
+                event_message: self.add_event_message_attribute,
+                max_spans: self.max_spans_per_trace,
+                max_events: self.max_events_per_trace,
+                ..Default::default()
             };
-            self.add_value(value);
+            trace.add_value(value);
+            if !TailSampler::discard(self.tail_sampler, &trace) {
+                notify_if_completed(&mut trace, &id, &self.completed_tx);
+                let indexed = index_tags(&mut shard.tag_index, &id, tags);
+                shard.traces.limiter_mut().on_resized(indexed);
+                shard.promote_pinned();
+                shard.traces.insert(id.clone(), trace);
+            }
         }
+
+        if self.eviction_policy == EvictionPolicy::LargestFirst {
+            shard.evict_largest();
+        }
+
+        shard.sync_index();
+        // The trace may be rejected by the map if `max_length` is 0, or discarded by the tail
+        // sampling.
+        if shard.tag_index.is_some() && shard.traces.peek(&id).is_none() {
+            shard.unindex(&id);
+        }
+
+        if pin && !shard.pinned.contains(&id) {
+            shard.pinned.push(id);
+        }
+
+        accepted
     }
 
-    /// Get the number of traces in the state.
-    #[allow(clippy::len_without_is_empty)]
-    pub fn len(&self) -> usize {
-        self.traces.len()
+    /// Drop the traces that are treated as complete due to [`Config::force_complete_after`]
+    /// but not kept by [`Config::tail_sampling`], returning the number of traces dropped.
+    ///
+    /// Such traces are already hidden from the queries, so this only reclaims the memory.
+    /// Call it periodically if the tail sampling is enabled.
+    pub fn tail_sample(&mut self) -> usize {
+        let Some(sampler) = self.tail_sampler else {
+            return 0;
+        };
+        let deadline = self.force_complete_deadline();
+        let mut count = 0;
+
+        for mut shard in self.each_shard() {
+            let dropped = (shard.traces.iter())
+                .filter(|(_, t)| !t.is_complete() && t.end_time < deadline && !sampler.keep(t))
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+            for id in &dropped {
+                remove_explicitly(&mut shard.traces, id);
+            }
+            shard.sync_index();
+            count += dropped.len();
+        }
+
+        count
     }
 
-    /// Get the estimated memory usage of the state in bytes.
-    pub fn estimated_memory_usage(&self) -> usize {
-        self.traces.limiter().estimated_memory_usage()
+    /// Register a listener to be invoked on each span added, before it's merged into the trace.
+    ///
+    /// This includes the spans added by [`State::merge`] and [`State::import_snapshot`], but
+    /// not the ones rejected by sampling or the limits before reaching the state. Listeners
+    /// are called synchronously with the lock of the shard held, possibly from multiple
+    /// exports at the same time, so they must be cheap and never block, e.g., by sending to a
+    /// channel without waiting.
+    pub fn add_span_listener(&mut self, listener: SpanListener) {
+        self.span_listeners.push(listener);
     }
 
-    /// Get a trace by its ID.
+    /// Subscribe to the IDs of the traces that become complete.
     ///
-    /// The trace will be promoted to the most recent.
-    pub fn get_by_id(&mut self, id: &[u8]) -> Option<Trace> {
-        self.traces.get(id).cloned()
+    /// Each trace is notified at most once, when it becomes complete for the first time.
+    /// Traces treated as complete due to [`Config::force_complete_after`] are not notified.
+    pub fn subscribe(&self) -> broadcast::Receiver<Vec<u8>> {
+        self.completed_tx.subscribe()
     }
 
-    /// Get an iterator over all traces that are complete.
-    pub fn get_all_complete(&self) -> impl Iterator<Item = Trace> + '_ {
-        self.traces.iter().filter_map(|(_, trace)| {
-            if trace.is_complete() {
-                Some(trace.clone())
+    /// Check whether the trace of the span should be retained longer.
+    fn should_pin(&self, span: &Span) -> bool {
+        let slow = self.retain_slower_than.is_some_and(|threshold| {
+            let nanos = (span.end_time_unix_nano).saturating_sub(span.start_time_unix_nano);
+            Duration::from_nanos(nanos) > threshold
+        });
+        slow || (self.retain_errors && is_error(span))
+    }
+
+    /// Sample, filter and validate the given spans without modifying the state, so that it
+    /// can be done under a read lock, in parallel with other exports. The result should be
+    /// applied with [`State::commit`].
+    pub(crate) fn prepare(&self, mut resource_spans: ResourceSpans) -> Prepared {
+        if let Some(resource) = &mut resource_spans.resource {
+            if self.keep_resource_attributes {
+                keep(
+                    &mut resource.attributes,
+                    &self.keep_attributes,
+                    &mut resource.dropped_attributes_count,
+                );
+            }
+            redact(&mut resource.attributes, &self.redact_keys);
+        }
+        let mut prepared = Prepared::default();
+
+        for mut value in SpanValue::from_resource_spans(resource_spans) {
+            let span = &mut value.span;
+            if let Cow::Owned(id) = self.normalize_trace_id(&span.trace_id) {
+                span.trace_id = id;
+            }
+            if !self.should_sample(span) {
+                continue;
+            }
+            value.operation = self.normalize_operation(&span.name);
+
+            keep(
+                &mut span.attributes,
+                &self.keep_attributes,
+                &mut span.dropped_attributes_count,
+            );
+            truncate(
+                &mut span.attributes,
+                self.max_span_attributes,
+                &mut span.dropped_attributes_count,
+            );
+            truncate(
+                &mut span.events,
+                self.max_span_events,
+                &mut span.dropped_events_count,
+            );
+            truncate(
+                &mut span.links,
+                self.max_span_links,
+                &mut span.dropped_links_count,
+            );
+            redact(&mut span.attributes, &self.redact_keys);
+            for event in &mut span.events {
+                redact(&mut event.attributes, &self.redact_keys);
+            }
+
+            if self.timestamp_validation != TimestampValidation::Off
+                && !is_plausible_timestamp(span.start_time_unix_nano)
+            {
+                prepared.implausible_timestamp += 1;
+                if self.timestamp_validation == TimestampValidation::Reject {
+                    prepared.rejected += 1;
+                    continue;
+                }
+                span.attributes.push(KeyValue {
+                    key: "otlp_embedded.warning".to_owned(),
+                    value: Some(AnyValue {
+                        value: Some(any_value::Value::StringValue(
+                            "implausible start time, maybe not in nanoseconds".to_owned(),
+                        )),
+                    }),
+                });
+            }
+
+            if span.end_time_unix_nano <= span.start_time_unix_nano {
+                prepared.non_positive_duration += 1;
+            }
+
+            prepared.values.push(value);
+        }
+
+        prepared
+    }
+
+    /// Add the spans prepared by [`State::prepare`] to the state, returning the number of
+    /// spans that were rejected.
+    ///
+    /// Only the shards of the traces are locked, so that it can be done under a read lock as
+    /// well, in parallel with other exports.
+    pub(crate) fn commit(&self, prepared: Prepared) -> usize {
+        let Prepared {
+            values,
+            mut rejected,
+            implausible_timestamp,
+            non_positive_duration,
+        } = prepared;
+        (self.counters.implausible_timestamp).fetch_add(implausible_timestamp, Ordering::Relaxed);
+        (self.counters.non_positive_duration).fetch_add(non_positive_duration, Ordering::Relaxed);
+
+        for value in values {
+            if self.add_value(value) {
+                self.counters.spans_ingested.fetch_add(1, Ordering::Relaxed);
             } else {
-                None
+                rejected += 1;
             }
-        })
+        }
+
+        if rejected > 0 {
+            tracing::warn!(
+                rejected,
+                "rejected invalid spans or spans exceeding the limit per trace"
+            );
+        }
+        rejected
     }
 
-    /// Get a set of all services.
-    pub fn get_all_services(&self) -> BTreeSet<&str> {
-        self.traces
-            .iter()
-            .filter_map(|(_, t)| t.root_span())
-            .map(|v| v.service_name())
-            .collect()
+    /// Merge all traces from another state into this one, returning the number of spans
+    /// rejected.
+    ///
+    /// The spans are added in the same way as they're ingested, so the limits of this state
+    /// apply, and traces with the same ID are merged span by span. Traces are added from the
+    /// least recently used one of each shard, so that the recency in `other` is preserved.
+    /// Sampling is not applied again.
+    pub fn merge(&mut self, other: State) -> usize {
+        let mut rejected = 0;
+
+        for shard in other.shards.into_vec() {
+            let mut shard = shard.into_inner().unwrap_or_else(PoisonError::into_inner);
+            while let Some((_, trace)) = shard.traces.pop_oldest() {
+                for node in trace.spans.into_values() {
+                    let SpanNode::Value(value) = node else {
+                        continue;
+                    };
+                    if self.add_value(value) {
+                        self.counters.spans_ingested.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        rejected += 1;
+                    }
+                }
+            }
+        }
+
+        rejected
     }
 
-    /// Get a set of all operations for the given service.
-    pub fn get_operations(&self, service_name: &str) -> BTreeSet<&str> {
-        self.traces
-            .iter()
-            .filter_map(|(_, t)| t.root_span())
-            .filter(|v| v.service_name() == service_name)
-            .map(|v| v.operation())
-            .collect()
+    pub(crate) fn persist_path(&self) -> Option<&Path> {
+        self.persist_path.as_deref()
+    }
+
+    /// Export all traces into a snapshot, which can be serialized and persisted.
+    pub fn export_snapshot(&self) -> StateSnapshot {
+        let mut traces = Vec::new();
+        for shard in self.each_shard() {
+            let start = traces.len();
+            traces.extend((shard.traces.iter()).map(|(id, trace)| {
+                TraceSnapshot {
+                    trace_id: hex::encode(id),
+                    end_time_unix_nano: (trace.end_time.duration_since(SystemTime::UNIX_EPOCH))
+                        .unwrap_or_default()
+                        .as_nanos() as u64,
+                    dropped_span_count: trace.dropped,
+                    resource_spans: trace.to_otlp_request().resource_spans,
+                }
+            }));
+            // The map iterates from the most recently used one.
+            traces[start..].reverse();
+        }
+
+        StateSnapshot { traces }
+    }
+
+    /// Import the traces from a snapshot into this state, returning the number of spans
+    /// rejected.
+    ///
+    /// Same as [`State::merge`], the limits of this state apply and traces with the same ID
+    /// are merged span by span.
+    pub fn import_snapshot(&mut self, snapshot: StateSnapshot) -> usize {
+        let mut rejected = 0;
+
+        for trace in snapshot.traces {
+            let values =
+                (trace.resource_spans.into_iter()).flat_map(SpanValue::from_resource_spans);
+            for mut value in values {
+                value.operation = self.normalize_operation(&value.span.name);
+                if self.add_value(value) {
+                    self.counters.spans_ingested.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    rejected += 1;
+                }
+            }
+
+            let Ok(id) = hex::decode(&trace.trace_id) else {
+                tracing::warn!(trace_id = trace.trace_id, "invalid trace ID in snapshot");
+                continue;
+            };
+            let id = self.normalize_trace_id(&id);
+            if let Some(t) = self.shard(&id).traces.peek_mut(&*id) {
+                t.end_time = (t.end_time)
+                    .max(SystemTime::UNIX_EPOCH + Duration::from_nanos(trace.end_time_unix_nano));
+                t.dropped += trace.dropped_span_count;
+            }
+        }
+
+        rejected
+    }
+
+    /// Get the number of traces in the state.
+    pub fn len(&self) -> usize {
+        self.each_shard().map(|shard| shard.traces.len()).sum()
+    }
+
+    /// Check whether there's no trace in the state.
+    pub fn is_empty(&self) -> bool {
+        self.each_shard().all(|shard| shard.traces.is_empty())
+    }
+
+    /// Get the total number of spans received in all traces, not counting the parents that are
+    /// referenced but not received yet.
+    pub fn total_spans(&self) -> usize {
+        (self.each_shard())
+            .map(|shard| shard.traces.limiter().total_spans())
+            .sum()
+    }
+
+    /// Get the estimated memory usage of the state in bytes.
+    pub fn estimated_memory_usage(&self) -> usize {
+        (self.each_shard())
+            .map(|shard| shard.traces.limiter().estimated_memory_usage())
+            .sum()
+    }
+
+    /// Get the IDs of the `n` largest traces and their estimated memory usage in bytes, sorted
+    /// from the largest one.
+    ///
+    /// The sizes are accounted in the same way as [`Config::max_memory_usage`], which helps to
+    /// find the traces causing the eviction.
+    pub fn heaviest_traces(&self, n: usize) -> Vec<(Vec<u8>, usize)> {
+        let mut traces = self.collect_traces(|id, trace| {
+            Some((
+                id.clone(),
+                id.estimate_heap_size() + trace.estimate_heap_size(),
+            ))
+        });
+        traces.sort_unstable_by_key(|(_, size)| Reverse(*size));
+        traces.truncate(n);
+        traces
+    }
+
+    /// Remove all traces, returning the number of traces removed.
+    ///
+    /// Cleared traces are not counted as evicted.
+    pub fn clear(&mut self) -> usize {
+        let mut len = 0;
+        for mut shard in self.each_shard() {
+            len += shard.traces.len();
+            shard.traces.clear();
+            shard.pinned.clear();
+            if let Some(index) = &mut shard.tag_index {
+                index.clear();
+            }
+        }
+        len
+    }
+
+    /// Get the maximum number of spans in a single export request, `0` for unlimited.
+    pub(crate) fn max_request_spans(&self) -> u32 {
+        self.max_request_spans
+    }
+
+    /// Check whether an export request is allowed by [`Config::max_exports_per_second`],
+    /// taking from the quota of its source if so.
+    ///
+    /// The key of the source is only computed if rate limiting is enabled, given the
+    /// configured [`Config::rate_limit_header`].
+    pub(crate) fn allow_export(&self, source: impl FnOnce(Option<&str>) -> String) -> bool {
+        let Some(limiter) = &self.rate_limiter else {
+            return true;
+        };
+        let source = source(self.rate_limit_header.as_deref());
+        let allowed = limiter.try_acquire(&source, self.clock.now());
+        if !allowed {
+            tracing::debug!(source, "rejected export request exceeding the rate limit");
+        }
+        allowed
+    }
+
+    /// Get the maximum size of a request body received over OTLP/HTTP in bytes.
+    pub(crate) fn max_request_body_size(&self) -> usize {
+        self.max_request_body_size
+    }
+
+    /// Get the forwarder if forwarding is enabled.
+    pub(crate) fn forwarder(&self) -> Option<&Forwarder> {
+        self.forwarder.as_ref()
+    }
+
+    /// Get the number of export requests pending in the ingest queue, or `0` if not enabled.
+    pub(crate) fn ingest_queue_depth(&self) -> usize {
+        (self.ingest_queue.as_ref())
+            .and_then(|queue| queue.upgrade())
+            .map_or(0, |queue| queue.max_capacity() - queue.capacity())
+    }
+
+    pub(crate) fn set_ingest_queue(&mut self, queue: mpsc::WeakSender<ExportTraceServiceRequest>) {
+        self.ingest_queue = Some(queue);
+    }
+
+    /// Get the total number of spans ingested.
+    pub(crate) fn spans_ingested(&self) -> u64 {
+        self.counters.spans_ingested.load(Ordering::Relaxed)
+    }
+
+    /// Get the diagnostics of the ingested spans.
+    pub fn diagnostics(&self) -> Diagnostics {
+        let missing_parents = (self.collect_traces(|_, trace| Some(trace.missing_span_count())))
+            .into_iter()
+            .sum();
+
+        Diagnostics {
+            empty_span_id: self.counters.empty_span_id.load(Ordering::Relaxed),
+            non_positive_duration: self.counters.non_positive_duration.load(Ordering::Relaxed),
+            implausible_timestamp: self.counters.implausible_timestamp.load(Ordering::Relaxed),
+            missing_parents,
+            dropped_forwards: self.forwarder.as_ref().map_or(0, |f| f.dropped_count()),
+        }
+    }
+
+    /// Get the total number of traces evicted due to the capacity or the age.
+    ///
+    /// Traces removed explicitly are not counted.
+    pub fn evicted_count(&self) -> u64 {
+        (self.each_shard())
+            .map(|shard| shard.traces.limiter().evicted_count())
+            .sum()
+    }
+
+    /// Get a trace by its ID.
+    ///
+    /// The trace will be promoted to the most recent, if [`EvictionPolicy::Lru`] is used.
+    pub fn get_by_id(&self, id: &[u8]) -> Option<Trace> {
+        let id = self.normalize_trace_id(id);
+        let id = &*id;
+        let expired_before = self.expired_before();
+        let mut shard = self.shard(id);
+        let trace = match self.eviction_policy {
+            EvictionPolicy::Lru => shard.traces.get(id)?,
+            EvictionPolicy::Fifo | EvictionPolicy::LargestFirst => shard.traces.peek(id)?,
+        };
+
+        if trace.end_time < expired_before {
+            shard.traces.remove(id);
+            shard.sync_index();
+            return None;
+        }
+        Some(trace.clone())
+    }
+
+    /// Remove a trace by its ID, returning whether it existed.
+    ///
+    /// Removed traces are not counted as evicted.
+    pub fn remove_by_id(&self, id: &[u8]) -> bool {
+        let id = self.normalize_trace_id(id);
+        let id = &*id;
+        let mut shard = self.shard(id);
+        let removed = remove_explicitly(&mut shard.traces, id).is_some();
+        shard.sync_index();
+        removed
+    }
+
+    /// Get a trace by its ID without promoting it to the most recent.
+    pub fn peek_by_id(&self, id: &[u8]) -> Option<Trace> {
+        let id = self.normalize_trace_id(id);
+        let expired_before = self.expired_before();
+        (self.shard(&id).traces.peek(&*id))
+            .filter(|trace| trace.end_time >= expired_before)
+            .cloned()
+    }
+
+    /// Normalize the span name with [`Config::operation_rules`], or `None` if unchanged.
+    fn normalize_operation(&self, name: &str) -> Option<String> {
+        let mut operation = Cow::Borrowed(name);
+        for (regex, replacement) in &self.operation_rules {
+            if let Cow::Owned(replaced) = regex.replace_all(&operation, replacement.as_str()) {
+                operation = Cow::Owned(replaced);
+            }
+        }
+        match operation {
+            Cow::Borrowed(_) => None,
+            Cow::Owned(operation) => Some(operation),
+        }
+    }
+
+    /// Left-pad the 64-bit trace ID to 128 bits if [`Config::normalize_trace_ids`] is enabled.
+    fn normalize_trace_id<'a>(&self, id: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.normalize_trace_ids && id.len() == 8 {
+            Cow::Owned([[0; 8].as_slice(), id].concat())
+        } else {
+            Cow::Borrowed(id)
+        }
+    }
+
+    /// Get the time before which the traces are considered expired.
+    fn expired_before(&self) -> SystemTime {
+        self.max_age
+            .and_then(|max_age| self.clock.now().checked_sub(max_age))
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
+    /// Drop all traces older than [`Config::max_age`], returning the number of traces dropped.
+    pub fn evict_expired(&mut self) -> usize {
+        let expired_before = self.expired_before();
+        let mut count = 0;
+
+        for mut shard in self.each_shard() {
+            let expired = (shard.traces.iter())
+                .filter(|(_, trace)| trace.end_time < expired_before)
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+            for id in &expired {
+                shard.traces.remove(id);
+            }
+            shard.sync_index();
+            count += expired.len();
+        }
+        count
+    }
+
+    /// Get an iterator over all traces with their IDs, including the incomplete ones.
+    ///
+    /// The traces are cloned shard by shard, so that the locks are not held while iterating.
+    pub fn iter_traces(&self) -> impl Iterator<Item = (Vec<u8>, Trace)> + '_ {
+        (self.shards.iter()).flat_map(|shard| {
+            (lock(shard).traces.iter())
+                .map(|(id, trace)| (id.clone(), trace.clone()))
+                .collect_vec()
+        })
+    }
+
+    /// Get an iterator over all traces, including the incomplete ones.
+    pub fn get_all(&self) -> impl Iterator<Item = Trace> + '_ {
+        self.iter_traces().map(|(_, trace)| trace)
+    }
+
+    /// Get an iterator over all traces that are complete.
+    ///
+    /// Traces that have ended for longer than [`Config::force_complete_after`] are also
+    /// considered complete, while those older than [`Config::max_age`] are skipped.
+    pub fn get_all_complete(&self) -> impl Iterator<Item = Trace> + '_ {
+        let is_complete = self.complete_filter();
+
+        (self.shards.iter()).flat_map(move |shard| {
+            (lock(shard).traces.iter())
+                .filter(|(_, trace)| is_complete(trace))
+                .map(|(_, trace)| trace.clone())
+                .collect_vec()
+        })
+    }
+
+    /// Get all complete traces that have all the given tags, i.e., attributes of any span or
+    /// resource with the given key and value, in the same sense as [`State::get_all_complete`].
+    ///
+    /// The tag index is used if enabled by [`Config::index_tags`], otherwise all spans are
+    /// scanned.
+    pub fn get_all_complete_with_tags(&self, tags: &[(String, String)]) -> Vec<Trace> {
+        self.traces_with_tags(tags, self.complete_filter())
+    }
+
+    /// Get all traces that have all the given tags, including the incomplete ones, same as
+    /// [`State::get_all_complete_with_tags`]. Traces older than [`Config::max_age`] are still
+    /// skipped.
+    pub fn get_all_with_tags(&self, tags: &[(String, String)]) -> Vec<Trace> {
+        let expired_before = self.expired_before();
+        self.traces_with_tags(tags, move |trace| trace.end_time >= expired_before)
+    }
+
+    /// Get the distinct attribute keys of all spans and resources.
+    ///
+    /// The tag index is used if enabled by [`Config::index_tags`], where the keys with only
+    /// long values are not included. Otherwise, all spans are scanned, and each shared
+    /// resource only once.
+    pub fn all_attribute_keys(&self) -> BTreeSet<String> {
+        let mut keys = BTreeSet::new();
+
+        for shard in self.each_shard() {
+            if let Some(index) = &shard.tag_index {
+                keys.extend(index.keys().map(str::to_owned));
+                continue;
+            }
+
+            let mut resources = HashSet::new();
+            for (_, trace) in shard.traces.iter() {
+                for value in trace.iter_valid() {
+                    let resource = (resources.insert(Arc::as_ptr(&value.resource)))
+                        .then_some(&value.resource.attributes);
+                    for kv in value
+                        .span
+                        .attributes
+                        .iter()
+                        .chain(resource.into_iter().flatten())
+                    {
+                        if !keys.contains(&kv.key) {
+                            keys.insert(kv.key.clone());
+                        }
+                    }
+                }
+            }
+        }
+        keys
+    }
+
+    /// Get at most `limit` distinct values of the attribute with the given key of all spans
+    /// and resources, rendered in the same way as the tags in Jaeger, sorted.
+    ///
+    /// If there are more values than `limit`, which ones are returned is unspecified. The tag
+    /// index is used if enabled by [`Config::index_tags`], where long values are not included.
+    pub fn attribute_values(&self, key: &str, limit: usize) -> Vec<String> {
+        let mut values = BTreeSet::new();
+
+        'outer: for shard in self.each_shard() {
+            if let Some(index) = &shard.tag_index {
+                for value in index.values(key) {
+                    if values.len() >= limit {
+                        break 'outer;
+                    }
+                    values.insert(value.to_owned());
+                }
+                continue;
+            }
+
+            let mut resources = HashSet::new();
+            for (_, trace) in shard.traces.iter() {
+                for value in trace.iter_valid() {
+                    let resource = (resources.insert(Arc::as_ptr(&value.resource)))
+                        .then_some(&value.resource.attributes);
+                    for kv in value
+                        .span
+                        .attributes
+                        .iter()
+                        .chain(resource.into_iter().flatten())
+                    {
+                        if values.len() >= limit {
+                            break 'outer;
+                        }
+                        if kv.key == key {
+                            values.extend(kv.value.as_ref().and_then(tag_value));
+                        }
+                    }
+                }
+            }
+        }
+        values.into_iter().collect()
+    }
+
+    fn traces_with_tags(
+        &self,
+        tags: &[(String, String)],
+        filter: impl Fn(&Trace) -> bool,
+    ) -> Vec<Trace> {
+        let mut traces = Vec::new();
+
+        for shard in self.each_shard() {
+            match &shard.tag_index {
+                Some(index) if !tags.is_empty() => traces.extend(
+                    (index.get_all(tags).iter())
+                        .filter_map(|id| shard.traces.peek(id))
+                        .filter(|trace| filter(trace))
+                        .cloned(),
+                ),
+                _ => traces.extend(
+                    (shard.traces.iter())
+                        .map(|(_, trace)| trace)
+                        .filter(|trace| filter(trace))
+                        .filter(|trace| tags.iter().all(|(k, v)| trace.has_tag(k, v)))
+                        .cloned(),
+                ),
+            }
+        }
+        traces
+    }
+
+    /// Get a filter of the traces that are complete and not expired.
+    fn complete_filter(&self) -> impl Fn(&Trace) -> bool {
+        let deadline = self.force_complete_deadline();
+        let expired_before = self.expired_before();
+        let tail_sampler = self.tail_sampler;
+
+        move |trace| {
+            trace.end_time >= expired_before
+                && (trace.is_complete() || trace.end_time < deadline)
+                // Complete traces are already decided when they complete.
+                && tail_sampler.is_none_or(|s| trace.is_complete() || s.keep(trace))
+        }
+    }
+
+    /// Get the time before which the traces that have ended are treated as complete.
+    fn force_complete_deadline(&self) -> SystemTime {
+        (self.clock.now())
+            .checked_sub(self.force_complete_after)
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
+    /// Get all complete traces whose root span belongs to the given service, with the most
+    /// recently ended first.
+    pub fn traces_for_service(&self, service_name: &str) -> Vec<Trace> {
+        let mut traces = (self.get_all_complete())
+            .filter(|t| t.service_name() == Some(service_name))
+            .collect::<Vec<_>>();
+        traces.sort_by_key(|t| Reverse(t.end_time));
+        traces
+    }
+
+    /// Get a set of all services.
+    pub fn get_all_services(&self) -> BTreeSet<String> {
+        (self.collect_traces(|_, t| Some(t.root_span()?.service_name().to_owned())))
+            .into_iter()
+            .collect()
+    }
+
+    /// Get the number of complete traces of each service, by the service of the root span, in
+    /// the same sense as [`State::get_all_complete`].
+    pub fn get_services_with_counts(&self) -> BTreeMap<String, usize> {
+        let is_complete = self.complete_filter();
+        let mut counts = BTreeMap::new();
+
+        for shard in self.each_shard() {
+            for (_, trace) in shard.traces.iter() {
+                if let Some(root) = trace.root_span().filter(|_| is_complete(trace)) {
+                    *counts.entry(root.service_name().to_owned()).or_default() += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    /// Get a set of all services along with their deployment environments, so that services
+    /// with the same name in different environments are told apart.
+    ///
+    /// The environment is taken from the `deployment.environment.name` or the
+    /// `deployment.environment` resource attribute, or `None` if neither is set.
+    pub fn get_all_services_grouped(&self) -> BTreeSet<(String, Option<String>)> {
+        (self.collect_traces(|_, t| {
+            let root = t.root_span()?;
+            Some((
+                root.service_name().to_owned(),
+                root.deployment_environment().map(str::to_owned),
+            ))
+        }))
+        .into_iter()
+        .collect()
+    }
+
+    /// Get the statistics of each operation for the given service, computed from the root
+    /// spans of the complete traces, sorted by the operation name.
+    pub fn operation_stats(&self, service_name: &str) -> Vec<OperationStat> {
+        let mut by_operation: BTreeMap<String, (Vec<Duration>, usize)> = BTreeMap::new();
+
+        for trace in self.get_all_complete() {
+            let Some(root) = trace.root_span() else {
+                continue;
+            };
+            if root.service_name() != service_name {
+                continue;
+            }
+            let span = &root.span;
+            let duration = Duration::from_nanos(
+                (span.end_time_unix_nano).saturating_sub(span.start_time_unix_nano),
+            );
+
+            let (durations, errors) =
+                (by_operation.entry(root.operation().to_owned())).or_default();
+            durations.push(duration);
+            *errors += is_error(span) as usize;
+        }
+
+        by_operation
+            .into_iter()
+            .map(|(name, (mut durations, error_count))| {
+                durations.sort_unstable();
+                OperationStat {
+                    name,
+                    count: durations.len(),
+                    p50: percentile(&durations, 0.50),
+                    p95: percentile(&durations, 0.95),
+                    p99: percentile(&durations, 0.99),
+                    error_count,
+                }
+            })
+            .collect()
+    }
+
+    /// Get the histogram of the root span durations of the complete traces, optionally only
+    /// for the given service.
+    ///
+    /// The buckets are log-scale between the shortest and the longest duration, since
+    /// latencies are usually long-tailed. Returns no bucket if there's no trace, or a single
+    /// one if all durations are the same.
+    pub fn duration_histogram(
+        &self,
+        service_name: Option<&str>,
+        buckets: usize,
+    ) -> Vec<DurationBucket> {
+        let durations = (self.get_all_complete())
+            .filter_map(|trace| {
+                let root = trace.root_span()?;
+                if service_name.is_some_and(|s| root.service_name() != s) {
+                    return None;
+                }
+                let span = &root.span;
+                Some((span.end_time_unix_nano).saturating_sub(span.start_time_unix_nano))
+            })
+            .collect_vec();
+
+        let (Some(&min), Some(&max)) = (durations.iter().min(), durations.iter().max()) else {
+            return Vec::new();
+        };
+        if min == max || buckets <= 1 {
+            return vec![DurationBucket {
+                start: Duration::from_nanos(min),
+                end: Duration::from_nanos(max),
+                count: durations.len(),
+            }];
+        }
+
+        // Zero durations cannot be scaled, so the first bucket starts from 1ns instead.
+        let ratio = (max as f64 / min.max(1) as f64).powf(1.0 / buckets as f64);
+        let mut bounds = (0..buckets)
+            .map(|i| (min.max(1) as f64 * ratio.powi(i as i32)).round() as u64)
+            .collect_vec();
+        bounds[0] = min;
+        bounds.push(max);
+
+        let mut counts = vec![0; buckets];
+        for duration in durations {
+            // Count the inner bounds not greater than the duration, which is the bucket index.
+            counts[bounds[1..buckets].partition_point(|&b| b <= duration)] += 1;
+        }
+
+        (bounds.into_iter().tuple_windows().zip(counts))
+            .map(|((start, end), count)| DurationBucket {
+                start: Duration::from_nanos(start),
+                end: Duration::from_nanos(end),
+                count,
+            })
+            .collect()
+    }
+
+    /// Get a set of all operations for the given service.
+    pub fn get_operations(&self, service_name: &str) -> BTreeSet<String> {
+        (self.collect_traces(|_, t| {
+            (t.root_span())
+                .filter(|v| v.service_name() == service_name)
+                .map(|v| v.operation().to_owned())
+        }))
+        .into_iter()
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{proto::trace::v1::span::Event, test_util::*};
+
+    #[test]
+    fn test_with_capacity() {
+        let state = State::with_capacity(2);
+        let mut state = state.try_write().unwrap();
+
+        for i in 1..=3 {
+            assert_eq!(add(&mut state, "svc", vec![span(i, 1, None)]), 0);
+        }
+        assert_eq!(state.len(), 2);
+        assert!(state.peek_by_id(&trace_id(1)).is_none());
+        assert!(state.peek_by_id(&trace_id(3)).is_some());
+    }
+
+    #[test]
+    fn test_force_complete_after() {
+        let clock = clock();
+        let state = State::new(Config {
+            clock: clock.clone(),
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+
+        // The root span is never received.
+        add(&mut state, "svc", vec![span(1, 2, Some(1))]);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(state.get_all_complete().count(), 0);
+
+        clock.advance(Duration::from_secs(31));
+        let traces = state.get_all_complete().collect_vec();
+        assert_eq!(traces.len(), 1);
+        // The earliest-starting span is used as the root.
+        assert_eq!(traces[0].operation(), Some("span-2"));
+    }
+
+    #[test]
+    fn test_peek_by_id() {
+        let state = State::with_capacity(2);
+        let mut state = state.try_write().unwrap();
+        add(&mut state, "svc", vec![span(1, 1, None)]);
+        add(&mut state, "svc", vec![span(2, 1, None)]);
+
+        // Peeking doesn't promote the trace, so it's still the least recently used.
+        assert!(state.peek_by_id(&trace_id(1)).is_some());
+        add(&mut state, "svc", vec![span(3, 1, None)]);
+        assert!(state.peek_by_id(&trace_id(1)).is_none());
+
+        // While getting does.
+        assert!(state.get_by_id(&trace_id(2)).is_some());
+        add(&mut state, "svc", vec![span(4, 1, None)]);
+        assert!(state.peek_by_id(&trace_id(2)).is_some());
+        assert!(state.peek_by_id(&trace_id(3)).is_none());
+    }
+
+    #[test]
+    fn test_memory_usage_on_update() {
+        let state = State::with_capacity(10);
+        let mut state = state.try_write().unwrap();
+
+        for i in 1..=20 {
+            let mut span = span(i % 3, i, Some(0));
+            span.attributes = vec![kv("key", &"x".repeat(i as usize * 10))];
+            add(&mut state, "svc", vec![span]);
+
+            let sizes = state.heaviest_traces(usize::MAX);
+            let total = sizes.iter().map(|(_, size)| size).sum::<usize>();
+            assert_eq!(state.estimated_memory_usage(), total);
+        }
+
+        state.clear();
+        assert_eq!(state.estimated_memory_usage(), 0);
+    }
+
+    #[test]
+    fn test_span_limits() {
+        let state = State::new(Config {
+            max_span_attributes: 2,
+            max_span_events: 1,
+            max_span_links: 1,
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+
+        let mut span = span(1, 1, None);
+        span.attributes = (0..5).map(|i| kv(&format!("key{i}"), "value")).collect();
+        span.events = vec![Default::default(); 3];
+        span.links = vec![Default::default(); 2];
+        add(&mut state, "svc", vec![span]);
+
+        let trace = state.peek_by_id(&trace_id(1)).unwrap();
+        let span = &trace.iter_valid().next().unwrap().span;
+        assert_eq!(span.attributes.len(), 2);
+        assert_eq!(span.dropped_attributes_count, 3);
+        assert_eq!(span.events.len(), 1);
+        assert_eq!(span.dropped_events_count, 2);
+        assert_eq!(span.links.len(), 1);
+        assert_eq!(span.dropped_links_count, 1);
+    }
+
+    #[test]
+    fn test_iter_all_traces() {
+        let state = State::new(Config {
+            clock: clock(),
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+        add(&mut state, "svc", vec![span(1, 1, None)]);
+        add(&mut state, "svc", vec![span(2, 2, Some(1))]);
+
+        let ids = (state.iter_traces())
+            .map(|(id, _)| id.to_vec())
+            .sorted()
+            .collect_vec();
+        assert_eq!(ids, [trace_id(1), trace_id(2)]);
+        assert_eq!(state.get_all().count(), 2);
+        assert_eq!(state.get_all_complete().count(), 1);
+    }
+
+    #[test]
+    fn test_max_age() {
+        let clock = clock();
+        let state = State::new(Config {
+            max_age: Some(Duration::from_secs(10)),
+            clock: clock.clone(),
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+        add(&mut state, "svc", vec![span(1, 1, None)]);
+        add(&mut state, "svc", vec![span(2, 1, None)]);
+        assert_eq!(state.get_all_complete().count(), 2);
+
+        clock.advance(Duration::from_secs(11));
+        assert_eq!(state.get_all_complete().count(), 0);
+        assert!(state.peek_by_id(&trace_id(1)).is_none());
+
+        // Dropped lazily when accessed by ID, or explicitly.
+        assert!(state.get_by_id(&trace_id(1)).is_none());
+        assert_eq!(state.len(), 1);
+        assert_eq!(state.evict_expired(), 1);
+        assert!(state.is_empty());
+        assert_eq!(state.evicted_count(), 2);
+    }
+
+    #[test]
+    fn test_injected_clock() {
+        let clock = clock();
+        let state = State::new(Config {
+            force_complete_after: Duration::from_secs(1),
+            clock: clock.clone(),
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+        add(&mut state, "svc", vec![span(1, 2, Some(1))]);
+
+        // The deadline follows the injected clock instead of the system time.
+        clock.set(SystemTime::UNIX_EPOCH);
+        assert_eq!(state.get_all_complete().count(), 0);
+        clock.set(SystemTime::UNIX_EPOCH + Duration::from_nanos(START) + Duration::from_secs(2));
+        assert_eq!(state.get_all_complete().count(), 1);
+    }
+
+    #[test]
+    fn test_is_sampled() {
+        assert!(is_sampled(&trace_id(0xf0), 1.0));
+        assert!(is_sampled(&trace_id(0x10), 0.5));
+        assert!(!is_sampled(&trace_id(0xf0), 0.5));
+        assert!(!is_sampled(&trace_id(0x01), 0.0));
+    }
+
+    #[test]
+    fn test_sample_ratio() {
+        let state = State::new(Config {
+            sample_ratio: 0.5,
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+
+        add(&mut state, "svc", vec![span(0x10, 1, None)]);
+        add(&mut state, "svc", vec![span(0xf0, 1, None)]);
+        assert!(state.peek_by_id(&trace_id(0x10)).is_some());
+        assert!(state.peek_by_id(&trace_id(0xf0)).is_none());
+
+        // Errors are always kept, along with the subsequent spans of the same trace.
+        add(&mut state, "svc", vec![with_error(span(0xf0, 2, Some(1)))]);
+        add(&mut state, "svc", vec![span(0xf0, 3, Some(1))]);
+        let trace = state.peek_by_id(&trace_id(0xf0)).unwrap();
+        assert_eq!(trace.span_count(), 2);
+    }
+
+    #[test]
+    fn test_sample_ratio_without_errors() {
+        let state = State::new(Config {
+            sample_ratio: 0.5,
+            always_sample_errors: false,
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+
+        add(&mut state, "svc", vec![with_error(span(0xf0, 1, None))]);
+        assert!(state.peek_by_id(&trace_id(0xf0)).is_none());
+    }
+
+    #[test]
+    fn test_retain_errors() {
+        let state = State::new(Config {
+            max_length: 2,
+            retain_errors: true,
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+
+        add(&mut state, "svc", vec![with_error(span(1, 1, None))]);
+        for i in 2..=4 {
+            add(&mut state, "svc", vec![span(i, 1, None)]);
+        }
+        assert!(state.peek_by_id(&trace_id(1)).is_some());
+        assert!(state.peek_by_id(&trace_id(3)).is_none());
+        assert!(state.peek_by_id(&trace_id(4)).is_some());
+    }
+
+    #[test]
+    fn test_retain_slower_than() {
+        let state = State::new(Config {
+            max_length: 2,
+            retain_slower_than: Some(Duration::from_secs(1)),
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+
+        let mut slow = span(1, 1, None);
+        slow.end_time_unix_nano = START + 2_000_000_000;
+        add(&mut state, "svc", vec![slow]);
+        for i in 2..=4 {
+            add(&mut state, "svc", vec![span(i, 1, None)]);
+        }
+        assert!(state.peek_by_id(&trace_id(1)).is_some());
+        assert!(state.peek_by_id(&trace_id(3)).is_none());
+
+        // Errors are not retained unless configured.
+        add(&mut state, "svc", vec![with_error(span(5, 1, None))]);
+        add(&mut state, "svc", vec![span(6, 1, None)]);
+        assert!(state.peek_by_id(&trace_id(5)).is_none());
+        assert!(state.peek_by_id(&trace_id(1)).is_some());
+    }
+
+    #[test]
+    fn test_pseudo_root() {
+        for pseudo_root in [true, false] {
+            let state = State::new(Config {
+                pseudo_root,
+                ..Default::default()
+            });
+            let mut state = state.try_write().unwrap();
+            let mut early = span(1, 2, Some(1));
+            early.start_time_unix_nano -= 1;
+            add(&mut state, "svc", vec![span(1, 3, Some(1)), early]);
+
+            let trace = state.peek_by_id(&trace_id(1)).unwrap();
+            if pseudo_root {
+                assert_eq!(trace.operation(), Some("span-2"));
+                assert_eq!(trace.service_name(), Some("svc"));
+            } else {
+                assert_eq!(trace.operation(), None);
+                assert_eq!(trace.service_name(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate_config() {
+        assert_eq!(Config::default().validate(), Ok(()));
+
+        let invalid = |config: Config| config.validate().unwrap_err();
+        assert_eq!(
+            invalid(Config {
+                max_length: 0,
+                ..Default::default()
+            }),
+            ConfigError::ZeroMaxLength
+        );
+        assert_eq!(
+            invalid(Config {
+                max_memory_usage: 0,
+                ..Default::default()
+            }),
+            ConfigError::ZeroMaxMemoryUsage
+        );
+        assert_eq!(
+            invalid(Config {
+                shards: 0,
+                ..Default::default()
+            }),
+            ConfigError::ZeroShards
+        );
+        assert_eq!(
+            invalid(Config {
+                sample_ratio: 1.5,
+                ..Default::default()
+            }),
+            ConfigError::InvalidSampleRatio(1.5)
+        );
+        let error = invalid(Config {
+            forward_endpoint: Some("not a uri".to_owned()),
+            ..Default::default()
+        });
+        assert!(matches!(error, ConfigError::InvalidForwardEndpoint(_)));
+        assert!(error
+            .to_string()
+            .starts_with("invalid `forward_endpoint`: not a uri"));
+    }
+
+    #[test]
+    fn test_traces_for_service() {
+        let state = State::new(Config {
+            clock: clock(),
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+        let mut late = span(2, 1, None);
+        late.end_time_unix_nano += 1;
+        add(&mut state, "a", vec![span(1, 1, None)]);
+        add(&mut state, "a", vec![late]);
+        add(&mut state, "b", vec![span(3, 1, None)]);
+        // Incomplete traces are excluded.
+        add(&mut state, "a", vec![span(4, 2, Some(1))]);
+
+        let ids = (state.traces_for_service("a").iter())
+            .map(|t| t.id().to_vec())
+            .collect_vec();
+        assert_eq!(ids, [trace_id(2), trace_id(1)]);
+        assert!(state.traces_for_service("c").is_empty());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let state = State::with_capacity(10);
+        let mut state = state.try_write().unwrap();
+        let mut rx = state.subscribe();
+
+        add(&mut state, "svc", vec![span(1, 2, Some(1))]);
+        assert!(rx.try_recv().is_err());
+
+        // Notified once the root arrives, but only for the first time.
+        add(&mut state, "svc", vec![span(1, 1, None)]);
+        add(&mut state, "svc", vec![span(1, 3, Some(1))]);
+        add(&mut state, "svc", vec![span(2, 1, None)]);
+        assert_eq!(rx.try_recv().unwrap(), trace_id(1));
+        assert_eq!(rx.try_recv().unwrap(), trace_id(2));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_index_tags() {
+        let state = State::new(Config {
+            max_length: 2,
+            index_tags: true,
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+        let with_tag = |trace, value| {
+            let mut span = span(trace, 1, None);
+            span.attributes = vec![kv("user", value)];
+            span
+        };
+
+        add(&mut state, "svc", vec![with_tag(1, "alice")]);
+        let unindexed_usage = {
+            let state = State::new(Config::default());
+            let mut state = state.try_write().unwrap();
+            add(&mut state, "svc", vec![with_tag(1, "alice")]);
+            state.estimated_memory_usage()
+        };
+        // The index is accounted in the memory usage.
+        assert!(state.estimated_memory_usage() > unindexed_usage);
+
+        add(&mut state, "svc", vec![with_tag(2, "bob")]);
+        let found = |state: &State, value: &str| {
+            (state
+                .get_all_complete_with_tags(&[("user".to_owned(), value.to_owned())])
+                .iter())
+            .map(|t| t.id().to_vec())
+            .collect_vec()
+        };
+        assert_eq!(found(&state, "alice"), [trace_id(1)]);
+        assert_eq!(found(&state, "bob"), [trace_id(2)]);
+
+        // Evicted traces are removed from the index.
+        add(&mut state, "svc", vec![with_tag(3, "carol")]);
+        assert!(found(&state, "alice").is_empty());
+        assert_eq!(found(&state, "carol"), [trace_id(3)]);
+    }
+
+    #[test]
+    fn test_index_rejected_span() {
+        let state = State::new(Config {
+            max_spans_per_trace: 1,
+            index_tags: true,
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+
+        let mut child = span(1, 2, Some(1));
+        child.attributes = vec![kv("user", "alice")];
+        add(&mut state, "svc", vec![span(1, 1, None)]);
+        assert_eq!(add(&mut state, "svc", vec![child]), 1);
+
+        // The tag is only on the rejected span, so it's not indexed.
+        let tags = [("user".to_owned(), "alice".to_owned())];
+        assert!(state.get_all_complete_with_tags(&tags).is_empty());
+        assert!(!state.all_attribute_keys().contains("user"));
+        assert!(state.attribute_values("user", 10).is_empty());
+    }
+
+    #[test]
+    fn test_max_spans_per_trace() {
+        let state = State::new(Config {
+            max_spans_per_trace: 2,
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+
+        // The placeholder of the missing root is not counted.
+        let spans = vec![
+            span(1, 2, Some(1)),
+            span(1, 3, Some(1)),
+            span(1, 4, Some(1)),
+        ];
+        assert_eq!(add(&mut state, "svc", spans), 1);
+        assert_eq!(add(&mut state, "svc", vec![span(1, 1, None)]), 1);
+
+        let trace = state.peek_by_id(&trace_id(1)).unwrap();
+        assert_eq!(trace.span_count(), 2);
+        assert_eq!(trace.dropped_span_count(), 2);
+
+        // Other traces are not affected.
+        assert_eq!(add(&mut state, "svc", vec![span(2, 1, None)]), 0);
+    }
+
+    /// Take the state out of the reference, which must be the only one.
+    fn into_inner(state: StateRef) -> State {
+        Arc::into_inner(state).unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_merge() {
+        let state = State::with_capacity(3);
+        let other = State::with_capacity(10);
+        {
+            let mut state = state.try_write().unwrap();
+            add(&mut state, "svc", vec![span(1, 1, None)]);
+            add(&mut state, "svc", vec![span(2, 1, None)]);
+
+            let mut other = other.try_write().unwrap();
+            add(&mut other, "svc", vec![span(3, 1, None)]);
+            add(&mut other, "svc", vec![span(1, 2, Some(1))]);
+        }
+
+        let mut state = into_inner(state);
+        assert_eq!(state.merge(into_inner(other)), 0);
+
+        // Traces with the same ID are merged, and the recency in the other state is kept.
+        assert_eq!(state.len(), 3);
+        assert_eq!(state.peek_by_id(&trace_id(1)).unwrap().span_count(), 2);
+        assert_eq!(state.total_spans(), 4);
+        add(&mut state, "svc", vec![span(4, 1, None)]);
+        assert!(state.peek_by_id(&trace_id(2)).is_none());
+        assert!(state.peek_by_id(&trace_id(3)).is_some());
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let state = State::new(Config {
+            max_spans_per_trace: 1,
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+        add(
+            &mut state,
+            "svc",
+            vec![span(1, 1, None), span(1, 2, Some(1))],
+        );
+        add(&mut state, "svc", vec![span(2, 2, Some(1))]);
+        add(&mut state, "svc", vec![span(3, 1, None)]);
+
+        let snapshot = state.export_snapshot();
+        let ids = (snapshot.traces.iter())
+            .map(|t| t.trace_id.clone())
+            .collect_vec();
+        assert_eq!(ids, [1, 2, 3].map(|i| hex::encode(trace_id(i))));
+
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let decoded: StateSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, snapshot);
+
+        let restored = State::with_capacity(10);
+        let mut restored = restored.try_write().unwrap();
+        assert_eq!(restored.import_snapshot(decoded), 0);
+        assert_eq!(restored.len(), 3);
+        for i in 1..=3 {
+            let (a, b) = (
+                state.peek_by_id(&trace_id(i)),
+                restored.peek_by_id(&trace_id(i)),
+            );
+            let (a, b) = (a.unwrap(), b.unwrap());
+            assert_eq!(a.to_jaeger(), b.to_jaeger());
+            assert_eq!(a.end_time, b.end_time);
+            assert_eq!(a.dropped_span_count(), b.dropped_span_count());
+            assert_eq!(a.is_complete(), b.is_complete());
+        }
+        assert_eq!(restored.export_snapshot(), snapshot);
+    }
+
+    #[test]
+    fn test_eviction_policies() {
+        let survivors = |eviction_policy| {
+            let state = State::new(Config {
+                max_length: 3,
+                eviction_policy,
+                ..Default::default()
+            });
+            let mut state = state.try_write().unwrap();
+
+            let mut big = span(3, 1, None);
+            big.attributes = vec![kv("big", &"x".repeat(10_000))];
+            add(&mut state, "svc", vec![span(1, 1, None)]);
+            add(&mut state, "svc", vec![span(2, 1, None)]);
+            add(&mut state, "svc", vec![big]);
+            // Use the first trace again.
+            add(&mut state, "svc", vec![span(1, 2, Some(1))]);
+            add(&mut state, "svc", vec![span(4, 1, None)]);
+
+            assert_eq!(state.evicted_count(), 1);
+            (1..=4)
+                .filter(|&i| state.peek_by_id(&trace_id(i)).is_some())
+                .collect_vec()
+        };
+
+        assert_eq!(survivors(EvictionPolicy::Lru), [1, 3, 4]);
+        assert_eq!(survivors(EvictionPolicy::Fifo), [2, 3, 4]);
+        assert_eq!(survivors(EvictionPolicy::LargestFirst), [1, 2, 4]);
+    }
+
+    #[test]
+    fn test_timestamp_validation() {
+        let in_millis = || {
+            let mut span = span(1, 1, None);
+            span.start_time_unix_nano = START / 1_000_000;
+            span.end_time_unix_nano = START / 1_000_000 + 1;
+            span
+        };
+
+        for validation in [
+            TimestampValidation::Off,
+            TimestampValidation::Reject,
+            TimestampValidation::Flag,
+        ] {
+            let state = State::new(Config {
+                timestamp_validation: validation,
+                ..Default::default()
+            });
+            let mut state = state.try_write().unwrap();
+            let rejected = add(&mut state, "svc", vec![in_millis(), span(2, 1, None)]);
+
+            let trace = state.peek_by_id(&trace_id(1));
+            let flagged = trace.as_ref().is_some_and(|t| {
+                let span = &t.iter_valid().next().unwrap().span;
+                span.attributes
+                    .iter()
+                    .any(|kv| kv.key == "otlp_embedded.warning")
+            });
+            let implausible = state.diagnostics().implausible_timestamp;
+            match validation {
+                TimestampValidation::Off => {
+                    assert_eq!((rejected, implausible, flagged), (0, 0, false));
+                }
+                TimestampValidation::Reject => {
+                    assert_eq!((rejected, implausible), (1, 1));
+                    assert!(trace.is_none());
+                }
+                TimestampValidation::Flag => {
+                    assert_eq!((rejected, implausible, flagged), (0, 1, true));
+                }
+            }
+            assert!(state.peek_by_id(&trace_id(2)).is_some());
+        }
+
+        assert!(is_plausible_timestamp(START));
+        assert!(!is_plausible_timestamp(START / 1_000_000_000));
+    }
+
+    #[test]
+    fn test_total_spans() {
+        let state = State::with_capacity(2);
+        let mut state = state.try_write().unwrap();
+        assert!(state.is_empty());
+        assert_eq!(state.total_spans(), 0);
+
+        // Placeholders are not counted.
+        add(
+            &mut state,
+            "svc",
+            vec![span(1, 2, Some(1)), span(1, 3, Some(1))],
+        );
+        assert_eq!(state.total_spans(), 2);
+        add(&mut state, "svc", vec![span(1, 1, None)]);
+        add(&mut state, "svc", vec![span(2, 1, None)]);
+        assert!(!state.is_empty());
+        assert_eq!(state.total_spans(), 4);
+
+        // Evicted and removed traces are not counted.
+        add(&mut state, "svc", vec![span(3, 1, None)]);
+        assert_eq!(state.total_spans(), 2);
+        assert!(state.remove_by_id(&trace_id(3)));
+        assert_eq!(state.total_spans(), 1);
+
+        state.clear();
+        assert!(state.is_empty());
+        assert_eq!(state.total_spans(), 0);
+    }
+
+    #[test]
+    fn test_tail_sampling() {
+        let clock = clock();
+        let state = State::new(Config {
+            clock: clock.clone(),
+            tail_sampling: true,
+            sample_ratio: 0.0,
+            keep_if_slower_than: Some(Duration::from_secs(1)),
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+
+        // Kept in memory until complete, then dropped.
+        add(&mut state, "svc", vec![span(1, 2, Some(1))]);
+        assert!(state.peek_by_id(&trace_id(1)).is_some());
+        add(&mut state, "svc", vec![span(1, 1, None)]);
+        assert!(state.peek_by_id(&trace_id(1)).is_none());
+
+        // An error anywhere in the trace keeps it.
+        add(&mut state, "svc", vec![with_error(span(2, 2, Some(1)))]);
+        add(&mut state, "svc", vec![span(2, 1, None)]);
+        assert!(state.peek_by_id(&trace_id(2)).is_some());
+
+        // So does a slow one.
+        let mut slow = span(3, 1, None);
+        slow.end_time_unix_nano = START + 2_000_000_000;
+        add(&mut state, "svc", vec![slow]);
+        assert!(state.peek_by_id(&trace_id(3)).is_some());
+
+        // A force-completed trace is hidden if not kept, until dropped explicitly.
+        add(&mut state, "svc", vec![span(4, 2, Some(1))]);
+        clock.advance(Duration::from_secs(120));
+        let ids = (state.get_all_complete())
+            .map(|t| t.id().to_vec())
+            .sorted()
+            .collect_vec();
+        assert_eq!(ids, [trace_id(2), trace_id(3)]);
+        assert_eq!(state.tail_sample(), 1);
+        assert!(state.peek_by_id(&trace_id(4)).is_none());
+        assert_eq!(state.len(), 2);
+    }
+
+    #[test]
+    fn test_keep() {
+        let mut attributes = vec![kv("http.method", "GET"), kv("user.id", "1"), kv("db", "x")];
+        let mut dropped = 1;
+        keep(
+            &mut attributes,
+            &["http.*".to_owned(), "db".to_owned()],
+            &mut dropped,
+        );
+        assert_eq!(attributes, [kv("http.method", "GET"), kv("db", "x")]);
+        assert_eq!(dropped, 2);
+
+        // Nothing is dropped without patterns.
+        keep(&mut attributes, &[], &mut dropped);
+        assert_eq!(attributes.len(), 2);
+    }
+
+    #[test]
+    fn test_keep_attributes() {
+        for keep_resource_attributes in [false, true] {
+            let state = State::new(Config {
+                keep_attributes: Some(vec!["service.*".to_owned(), "http.*".to_owned()]),
+                keep_resource_attributes,
+                ..Default::default()
+            });
+            let state = state.try_read().unwrap();
+
+            let mut resource_spans = resource_spans("svc", vec![]);
+            (resource_spans.resource.as_mut().unwrap().attributes).push(kv("host.name", "h"));
+            let mut span = span(1, 1, None);
+            span.attributes = vec![kv("http.method", "GET"), kv("user.id", "1")];
+            resource_spans.scope_spans[0].spans = vec![span];
+            let prepared = state.prepare(resource_spans);
+            state.commit(prepared);
+
+            let trace = state.peek_by_id(&trace_id(1)).unwrap();
+            let value = trace.iter_valid().next().unwrap();
+            assert_eq!(value.span.attributes, [kv("http.method", "GET")]);
+            assert_eq!(value.span.dropped_attributes_count, 1);
+            let resource_attributes = value.resource.attributes.len();
+            assert_eq!(
+                resource_attributes,
+                if keep_resource_attributes { 1 } else { 2 }
+            );
+            assert_eq!(value.service_name(), "svc");
+        }
+    }
+
+    #[test]
+    fn test_redact_keys() {
+        let state = State::new(Config {
+            redact_keys: vec!["authorization".to_owned(), "*.token".to_owned()],
+            ..Default::default()
+        });
+        let state = state.try_read().unwrap();
+
+        let mut span = span(1, 1, None);
+        span.attributes = vec![kv("authorization", "Bearer secret"), kv("user.id", "1")];
+        span.events = vec![Event {
+            name: "login".to_owned(),
+            attributes: vec![kv("session.token", "secret")],
+            ..Default::default()
+        }];
+        let mut resource_spans = resource_spans("svc", vec![span]);
+        (resource_spans.resource.as_mut().unwrap().attributes).push(kv("api.token", "secret"));
+        let prepared = state.prepare(resource_spans);
+        state.commit(prepared);
+
+        let trace = state.peek_by_id(&trace_id(1)).unwrap();
+        let value = trace.iter_valid().next().unwrap();
+        let get = |attributes: &[KeyValue], key: &str| {
+            let kv = attributes.iter().find(|kv| kv.key == key).unwrap();
+            kv.value.as_ref().and_then(tag_value).unwrap()
+        };
+        assert_eq!(
+            get(&value.span.attributes, "authorization"),
+            "***REDACTED***"
+        );
+        assert_eq!(get(&value.span.attributes, "user.id"), "1");
+        let event = &value.span.events[0].attributes;
+        assert_eq!(get(event, "session.token"), "***REDACTED***");
+        assert_eq!(
+            get(&value.resource.attributes, "api.token"),
+            "***REDACTED***"
+        );
+        assert_eq!(get(&value.resource.attributes, "service.name"), "svc");
+    }
+
+    #[test]
+    fn test_event_message_attribute() {
+        let mut with_event = span(1, 1, None);
+        with_event.events = vec![Event {
+            time_unix_nano: START,
+            name: "e".to_owned(),
+            ..Default::default()
+        }];
+
+        for enabled in [true, false] {
+            let state = State::new(Config {
+                add_event_message_attribute: enabled,
+                ..Default::default()
+            });
+            let mut state = state.try_write().unwrap();
+            add(&mut state, "svc", vec![with_event.clone()]);
+
+            let trace = state.peek_by_id(&trace_id(1)).unwrap();
+            let event = &trace.iter_valid().next().unwrap().span.events[0];
+            let expected = if enabled {
+                vec![kv("message", "e")]
+            } else {
+                vec![]
+            };
+            assert_eq!(event.attributes, expected);
+        }
+    }
+
+    #[test]
+    fn test_normalize_trace_ids() {
+        let short = vec![1; 8];
+        let long = [[0; 8].as_slice(), &short].concat();
+        let mut root = span(1, 1, None);
+        root.trace_id = short.clone();
+        let mut child = span(1, 2, Some(1));
+        child.trace_id = long.clone();
+
+        let state = State::with_capacity(10);
+        let mut state = state.try_write().unwrap();
+        add(&mut state, "svc", vec![root.clone(), child.clone()]);
+        assert_eq!(state.len(), 1);
+        for id in [&short, &long] {
+            let trace = state.peek_by_id(id).unwrap();
+            assert_eq!(trace.span_count(), 2);
+            assert!(trace.iter_valid().all(|v| v.span.trace_id == long));
+        }
+        assert_eq!(state.get_by_id(&short).unwrap().span_count(), 2);
+
+        let state = State::new(Config {
+            normalize_trace_ids: false,
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+        add(&mut state, "svc", vec![root, child]);
+        assert_eq!(state.len(), 2);
+        assert_eq!(state.peek_by_id(&short).unwrap().span_count(), 1);
+        assert_eq!(state.peek_by_id(&long).unwrap().span_count(), 1);
+    }
+
+    #[test]
+    fn test_shards() {
+        let state = State::new(Config {
+            shards: 4,
+            max_length: 8,
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+
+        // The limit is split among the shards, each keeping the 2 most recent traces.
+        for i in 1..=40 {
+            add(&mut state, "svc", vec![span(i, 1, None)]);
+        }
+        assert_eq!(state.len(), 8);
+        assert_eq!(state.evicted_count(), 32);
+        let mut kept = (state.get_all())
+            .map(|t| t.root_span().unwrap().span.trace_id[0])
+            .collect_vec();
+        kept.sort_unstable();
+        let mut expected = (0..4)
+            .flat_map(|shard| {
+                (1..=40u8)
+                    .filter(move |&i| trace_shard(&trace_id(i), 4) == shard)
+                    .rev()
+                    .take(2)
+            })
+            .collect_vec();
+        expected.sort_unstable();
+        assert_eq!(kept, expected);
+
+        // Trace IDs are normalized before choosing the shard.
+        let short = vec![41; 8];
+        let mut root = span(41, 1, None);
+        root.trace_id = short.clone();
+        let mut child = span(41, 2, Some(1));
+        child.trace_id = [[0; 8].as_slice(), &short].concat();
+        add(&mut state, "svc", vec![root, child]);
+        assert_eq!(state.get_by_id(&short).unwrap().span_count(), 2);
+    }
+
+    #[test]
+    fn test_operation_rules() {
+        let rules = vec![
+            (r"/user/\d+".to_owned(), "/user/{id}".to_owned()),
+            ("(".to_owned(), "invalid".to_owned()),
+        ];
+        let error = Config {
+            operation_rules: rules.clone(),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert!(matches!(error, ConfigError::InvalidOperationRule(_)));
+
+        // The invalid rule is ignored.
+        let state = State::new(Config {
+            operation_rules: rules,
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+        for (i, name) in [(1, "GET /user/1"), (2, "GET /user/2"), (3, "health")] {
+            let mut root = span(i, 1, None);
+            root.name = name.to_owned();
+            add(&mut state, "svc", vec![root]);
+        }
+
+        assert_eq!(
+            state.get_operations("svc"),
+            BTreeSet::from(["GET /user/{id}".to_owned(), "health".to_owned()])
+        );
+        let stats = state.operation_stats("svc");
+        assert_eq!(
+            (stats[0].name.as_str(), stats[0].count),
+            ("GET /user/{id}", 2)
+        );
+
+        // The span keeps the raw name for display.
+        let trace = state.peek_by_id(&trace_id(2)).unwrap();
+        assert_eq!(trace.operation(), Some("GET /user/{id}"));
+        assert_eq!(trace.root_span().unwrap().span.name, "GET /user/2");
+    }
+
+    #[test]
+    fn test_max_events_per_trace() {
+        let state = State::new(Config {
+            max_events_per_trace: 3,
+            ..Default::default()
+        });
+        let mut state = state.try_write().unwrap();
+
+        let with_events = |mut span: Span| {
+            span.events = (0..2)
+                .map(|i| Event {
+                    time_unix_nano: START + i,
+                    name: format!("e{i}"),
+                    ..Default::default()
+                })
+                .collect();
+            span
+        };
+        for id in 1..=3 {
+            let span = with_events(span(1, id, (id > 1).then_some(1)));
+            add(&mut state, "svc", vec![span]);
+        }
+        add(&mut state, "svc", vec![with_events(span(2, 1, None))]);
+
+        // The events received first are kept.
+        let trace = state.peek_by_id(&trace_id(1)).unwrap();
+        let events = (trace.iter_valid())
+            .map(|v| {
+                (
+                    v.span.span_id[0],
+                    v.span.events.len(),
+                    v.span.dropped_events_count,
+                )
+            })
+            .sorted()
+            .collect_vec();
+        assert_eq!(events, [(1, 2, 0), (2, 1, 1), (3, 0, 2)]);
+
+        // Other traces are not affected.
+        let trace = state.peek_by_id(&trace_id(2)).unwrap();
+        assert_eq!(trace.iter_valid().next().unwrap().span.events.len(), 2);
+    }
+
+    #[test]
+    fn test_span_listener() {
+        let state = State::with_capacity(10);
+        let mut state = state.try_write().unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_clone = seen.clone();
+        state.add_span_listener(Box::new(move |span, resource| {
+            let service = resource.attributes[0].value.clone();
+            seen_clone
+                .lock()
+                .unwrap()
+                .push((span.name.clone(), service));
+        }));
+
+        // Not invoked for the rejected spans.
+        let mut empty = span(1, 2, Some(1));
+        empty.span_id.clear();
+        add(&mut state, "a", vec![span(1, 1, None), empty]);
+
+        let other = State::with_capacity(10);
+        add(&mut other.try_write().unwrap(), "b", vec![span(2, 1, None)]);
+        state.merge(into_inner(other));
+
+        let service = |name: &str| kv("service.name", name).value;
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                ("span-1".to_owned(), service("a")),
+                ("span-1".to_owned(), service("b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duration_histogram() {
+        let state = State::with_capacity(10);
+        let mut state = state.try_write().unwrap();
+        assert!(state.duration_histogram(None, 10).is_empty());
+
+        let ms = Duration::from_millis;
+        for (i, millis) in [(1, 1), (2, 10), (3, 100), (4, 100)] {
+            let mut root = span(i, 1, None);
+            root.end_time_unix_nano = START + millis * 1_000_000;
+            add(&mut state, "a", vec![root]);
+        }
+        add(&mut state, "b", vec![span(5, 1, None)]);
+
+        // Log-scale bounds of 1ms, 10ms and 100ms.
+        assert_eq!(
+            state.duration_histogram(Some("a"), 2),
+            [
+                DurationBucket {
+                    start: ms(1),
+                    end: ms(10),
+                    count: 1,
+                },
+                DurationBucket {
+                    start: ms(10),
+                    end: ms(100),
+                    count: 3,
+                },
+            ]
+        );
+        let all = state.duration_histogram(None, 1);
+        assert_eq!((all.len(), all[0].count), (1, 5));
+        let single = state.duration_histogram(Some("b"), 10);
+        assert_eq!(
+            (single.len(), single[0].start, single[0].end),
+            (1, ms(1), ms(1))
+        );
+        assert!(state.duration_histogram(Some("c"), 10).is_empty());
+    }
+
+    #[test]
+    fn test_intern_resources() {
+        let state = State::with_capacity(10);
+        let mut state = state.try_write().unwrap();
+        add(&mut state, "svc", vec![span(1, 1, None)]);
+        add(&mut state, "svc", vec![span(2, 1, None)]);
+        add(&mut state, "other", vec![span(3, 1, None)]);
+
+        let resource = |trace| {
+            let trace = state.peek_by_id(&trace_id(trace)).unwrap();
+            let resource = trace.iter_valid().next().unwrap().resource.clone();
+            resource
+        };
+        assert!(Arc::ptr_eq(&resource(1), &resource(2)));
+        assert!(!Arc::ptr_eq(&resource(1), &resource(3)));
     }
 }
@@ -0,0 +1,145 @@
+//! Helpers to build the OTLP requests and drive the state in tests.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{HeaderMap, Request, StatusCode},
+    Router,
+};
+use serde_json::Value;
+use tower::ServiceExt;
+
+use crate::{
+    proto::{
+        collector::trace::v1::{ExportTraceServiceRequest, ExportTraceServiceResponse},
+        common::v1::{any_value, AnyValue, KeyValue},
+        resource::v1::Resource,
+        trace::v1::{
+            status::StatusCode as SpanStatusCode, ResourceSpans, ScopeSpans, Span, Status,
+        },
+    },
+    service::export,
+    MockClock, SpanValue, State, StateRef, Trace,
+};
+
+/// The start time of the spans built by [`span`], which is a plausible one in 2023.
+pub(crate) const START: u64 = 1_700_000_000_000_000_000;
+
+/// A mock clock at [`START`].
+pub(crate) fn clock() -> Arc<MockClock> {
+    Arc::new(MockClock::new(
+        SystemTime::UNIX_EPOCH + Duration::from_nanos(START),
+    ))
+}
+
+/// The 128-bit trace ID filled with the given byte.
+pub(crate) fn trace_id(n: u8) -> Vec<u8> {
+    vec![n; 16]
+}
+
+/// The 64-bit span ID filled with the given byte.
+pub(crate) fn span_id(n: u8) -> Vec<u8> {
+    vec![n; 8]
+}
+
+/// A string attribute.
+pub(crate) fn kv(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_owned(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::StringValue(value.to_owned())),
+        }),
+    }
+}
+
+/// A span named `span-<id>` lasting 1ms from [`START`], with an optional parent.
+pub(crate) fn span(trace: u8, id: u8, parent: Option<u8>) -> Span {
+    Span {
+        trace_id: trace_id(trace),
+        span_id: span_id(id),
+        parent_span_id: parent.map(span_id).unwrap_or_default(),
+        name: format!("span-{id}"),
+        start_time_unix_nano: START,
+        end_time_unix_nano: START + 1_000_000,
+        ..Default::default()
+    }
+}
+
+/// The span with an error status.
+pub(crate) fn with_error(mut span: Span) -> Span {
+    span.status = Some(Status {
+        code: SpanStatusCode::Error as i32,
+        message: String::new(),
+    });
+    span
+}
+
+/// The spans of the given service in a single scope.
+pub(crate) fn resource_spans(service: &str, spans: Vec<Span>) -> ResourceSpans {
+    ResourceSpans {
+        resource: Some(Resource {
+            attributes: vec![kv("service.name", service)],
+            dropped_attributes_count: 0,
+        }),
+        scope_spans: vec![ScopeSpans {
+            scope: None,
+            spans,
+            schema_url: String::new(),
+        }],
+        schema_url: String::new(),
+    }
+}
+
+/// An export request of the spans of the given service.
+pub(crate) fn request(service: &str, spans: Vec<Span>) -> ExportTraceServiceRequest {
+    ExportTraceServiceRequest {
+        resource_spans: vec![resource_spans(service, spans)],
+    }
+}
+
+/// A trace of the spans of the given service, built without a state.
+pub(crate) fn new_trace(service: &str, spans: Vec<Span>) -> Trace {
+    let mut trace = Trace::default();
+    for value in SpanValue::from_resource_spans(resource_spans(service, spans)) {
+        trace.add_value(value);
+    }
+    trace
+}
+
+/// Add the spans of the given service to the state in the same way as an export, returning
+/// the number of spans rejected.
+pub(crate) fn add(state: &mut State, service: &str, spans: Vec<Span>) -> usize {
+    let prepared = state.prepare(resource_spans(service, spans));
+    state.commit(prepared)
+}
+
+/// Export the spans of the given service to the state.
+pub(crate) async fn ingest(
+    state: &StateRef,
+    service: &str,
+    spans: Vec<Span>,
+) -> ExportTraceServiceResponse {
+    export(state, request(service, spans))
+        .await
+        .expect("export rejected")
+}
+
+/// Send the request to the app, returning the status, the headers and the body.
+pub(crate) async fn send(app: &Router, request: Request<Body>) -> (StatusCode, HeaderMap, Vec<u8>) {
+    let response = app.clone().oneshot(request).await.unwrap();
+    let (parts, body) = response.into_parts();
+    let body = to_bytes(body, usize::MAX).await.unwrap();
+    (parts.status, parts.headers, body.to_vec())
+}
+
+/// Send a `GET` request to the app, returning the status and the body parsed as JSON, or
+/// `null` if it's not JSON.
+pub(crate) async fn get_json(app: &Router, uri: &str) -> (StatusCode, Value) {
+    let request = Request::get(uri).body(Body::empty()).unwrap();
+    let (status, _, body) = send(app, request).await;
+    (status, serde_json::from_slice(&body).unwrap_or_default())
+}
@@ -1,46 +1,92 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    cmp::Reverse,
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
     sync::Arc,
     time::{Duration, SystemTime},
 };
 
 use crate::proto::{
-    common::v1::{any_value, AnyValue, KeyValue},
+    collector::trace::v1::ExportTraceServiceRequest,
+    common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue},
     resource::v1::Resource,
-    trace::v1::Span,
+    trace::v1::{ResourceSpans, ScopeSpans, Span},
 };
 use datasize::DataSize;
 use itertools::Itertools;
+use serde::{ser::SerializeMap, Serialize, Serializer};
 use serde_json::json;
 
+use crate::index::tag_value;
 use crate::jaeger::model::{span_to_jaeger_json, JaegerProcess};
+use crate::perfetto::spans_to_perfetto_json;
+use crate::zipkin::span_to_zipkin_json;
 
 pub(crate) type TraceId = Vec<u8>;
 pub(crate) type SpanId = Vec<u8>;
 
-#[derive(Debug, Clone, DataSize)]
+#[derive(Debug, Clone)]
 pub(crate) struct SpanValue {
     pub span: Span,
     pub resource: Arc<Resource>,
+    pub resource_schema_url: Arc<String>,
+    pub scope: Arc<InstrumentationScope>,
+    pub scope_schema_url: Arc<String>,
+    /// The operation name normalized by [`Config::operation_rules`], if different from the
+    /// span name.
+    ///
+    /// [`Config::operation_rules`]: crate::Config::operation_rules
+    pub operation: Option<String>,
+}
+
+fn find_string<'a>(attr: &'a [KeyValue], key: &'static str) -> Option<&'a str> {
+    attr.iter().find(|a| a.key == key).and_then(|kv| {
+        if let Some(AnyValue {
+            value: Some(any_value::Value::StringValue(str)),
+        }) = &kv.value
+        {
+            Some(str.as_str())
+        } else {
+            None
+        }
+    })
 }
 
 fn extract_string<'a>(attr: &'a [KeyValue], key: &'static str) -> &'a str {
-    attr.iter()
-        .find(|a| a.key == key)
-        .and_then(|kv| {
-            if let Some(AnyValue {
-                value: Some(any_value::Value::StringValue(str)),
-            }) = &kv.value
-            {
-                Some(str.as_str())
-            } else {
-                None
-            }
-        })
-        .unwrap_or("unknown")
+    find_string(attr, key).unwrap_or("unknown")
 }
 
 impl SpanValue {
+    /// Split the resource spans into the values of each span, sharing the resource and the
+    /// scope.
+    pub(crate) fn from_resource_spans(
+        resource_spans: ResourceSpans,
+    ) -> impl Iterator<Item = SpanValue> {
+        let ResourceSpans {
+            resource,
+            scope_spans,
+            schema_url,
+        } = resource_spans;
+
+        let resource = Arc::new(resource.unwrap_or_default());
+        let resource_schema_url = Arc::new(schema_url);
+
+        scope_spans.into_iter().flat_map(move |s| {
+            let resource = resource.clone();
+            let resource_schema_url = resource_schema_url.clone();
+            let scope = Arc::new(s.scope.unwrap_or_default());
+            let scope_schema_url = Arc::new(s.schema_url);
+
+            (s.spans.into_iter()).map(move |span| SpanValue {
+                span,
+                resource: resource.clone(),
+                resource_schema_url: resource_schema_url.clone(),
+                scope: scope.clone(),
+                scope_schema_url: scope_schema_url.clone(),
+                operation: None,
+            })
+        })
+    }
+
     pub fn service_name(&self) -> &str {
         extract_string(&self.resource.attributes, "service.name")
     }
@@ -49,8 +95,27 @@ impl SpanValue {
         extract_string(&self.resource.attributes, "service.instance.id")
     }
 
+    /// Get the operation name, which is normalized from the span name if configured.
     pub fn operation(&self) -> &str {
-        self.span.name.as_str()
+        self.operation.as_deref().unwrap_or(&self.span.name)
+    }
+
+    /// Get the deployment environment of the resource, if any.
+    pub fn deployment_environment(&self) -> Option<&str> {
+        // The key is renamed in the later versions of the semantic conventions.
+        find_string(&self.resource.attributes, "deployment.environment.name")
+            .or_else(|| find_string(&self.resource.attributes, "deployment.environment"))
+    }
+}
+
+// The resource and the scope are usually shared by many spans, so they're accounted by the
+// trace once for each allocation instead. See `add_shared`.
+impl DataSize for SpanValue {
+    const IS_DYNAMIC: bool = true;
+    const STATIC_HEAP_SIZE: usize = 0;
+
+    fn estimate_heap_size(&self) -> usize {
+        self.span.estimate_heap_size() + self.operation.estimate_heap_size()
     }
 }
 
@@ -60,12 +125,76 @@ pub(crate) enum SpanNode {
     Value(SpanValue),
 }
 
+/// Get the estimated heap size of the span table with the given capacity, excluding the entries.
+fn table_heap_size(capacity: usize) -> usize {
+    HashMap::<SpanId, SpanNode>::with_capacity(capacity).estimate_heap_size()
+}
+
+/// Account the shared values of the span if not yet, returning the increment of the estimated
+/// heap size.
+///
+/// The values are identified by their addresses, which won't be reused as long as the trace
+/// holds the span.
+fn add_shared(shared: &mut HashSet<usize>, value: &SpanValue) -> usize {
+    fn add<T: DataSize + ?Sized>(shared: &mut HashSet<usize>, arc: &Arc<T>) -> usize {
+        if shared.insert(Arc::as_ptr(arc) as *const () as usize) {
+            arc.estimate_heap_size() + std::mem::size_of::<usize>()
+        } else {
+            0
+        }
+    }
+
+    add(shared, &value.resource)
+        + add(shared, &value.resource_schema_url)
+        + add(shared, &value.scope)
+        + add(shared, &value.scope_schema_url)
+}
+
+/// Drop the events of the span beyond the budget, counting them in `dropped_events_count`.
+/// Returns the number of events kept.
+fn truncate_events(span: &mut Span, budget: usize) -> usize {
+    if span.events.len() > budget {
+        span.dropped_events_count += (span.events.len() - budget) as u32;
+        span.events.truncate(budget);
+    }
+    span.events.len()
+}
+
 /// A trace that consists of multiple spans in a tree structure.
-// TODO: should we cache the size?
-#[derive(Debug, Clone, DataSize)]
+#[derive(Debug, Clone)]
 pub struct Trace {
     pub(crate) spans: HashMap<SpanId, SpanNode>,
     pub(crate) end_time: SystemTime,
+    /// Whether to fall back to a pseudo-root if the actual root span is missing.
+    pub(crate) pseudo_root: bool,
+    /// Whether to add a `message` attribute from the name of each span event.
+    pub(crate) event_message: bool,
+    /// The maximum number of spans to keep, excluding placeholders. `0` means unlimited.
+    pub(crate) max_spans: u32,
+    /// The number of spans dropped due to `max_spans`.
+    pub(crate) dropped: usize,
+    /// The maximum number of events to keep across all spans. `0` means unlimited.
+    pub(crate) max_events: u32,
+    /// The number of events kept across all spans.
+    pub(crate) events: usize,
+    /// The number of placeholders, i.e., spans referenced as parents but not received yet.
+    pub(crate) missing: usize,
+    /// Whether the completion of the trace has been notified to the subscribers.
+    pub(crate) notified: bool,
+    /// The addresses of the shared resources, scopes and schema URLs already accounted.
+    pub(crate) shared: HashSet<usize>,
+    /// The estimated heap size, accumulated from the changes returned by `add_value`.
+    pub(crate) heap_size: usize,
+}
+
+// Computing the size from scratch requires visiting all spans, so it's cached instead.
+impl DataSize for Trace {
+    const IS_DYNAMIC: bool = true;
+    const STATIC_HEAP_SIZE: usize = 0;
+
+    fn estimate_heap_size(&self) -> usize {
+        self.heap_size
+    }
 }
 
 impl Default for Trace {
@@ -73,30 +202,65 @@ impl Default for Trace {
         Self {
             spans: Default::default(),
             end_time: SystemTime::UNIX_EPOCH,
+            pseudo_root: true,
+            event_message: true,
+            max_spans: 0,
+            dropped: 0,
+            max_events: 0,
+            events: 0,
+            missing: 0,
+            notified: false,
+            shared: HashSet::new(),
+            heap_size: 0,
         }
     }
 }
 
 impl Trace {
-    pub(crate) fn add_value(&mut self, mut value: SpanValue) {
+    /// Add a span to the trace, returning the change of the estimated heap size, which may be
+    /// negative if some attributes are overwritten with smaller values.
+    ///
+    /// The change is computed from the touched entries only, so that the cost doesn't
+    /// grow with the size of the trace.
+    pub(crate) fn add_value(&mut self, mut value: SpanValue) -> isize {
         let span_id = &value.span.span_id;
         let parent_id = &value.span.parent_span_id;
 
         if span_id.is_empty() {
-            return;
+            return 0;
+        }
+
+        // Only new spans are limited, updates to the received ones are still applied.
+        if self.max_spans > 0
+            && self.spans.len() - self.missing >= self.max_spans as usize
+            && !matches!(self.spans.get(span_id), Some(SpanNode::Value(_)))
+        {
+            self.dropped += 1;
+            return 0;
         }
 
+        let capacity = self.spans.capacity();
+        let mut size = 0isize;
+        let mut shared = 0;
+
         // If there's a parent and not recorded yet, add a placeholder.
         if !parent_id.is_empty() {
-            self.spans
-                .entry(parent_id.clone())
-                .or_insert(SpanNode::Placeholder);
+            if let Entry::Vacant(v) = self.spans.entry(parent_id.clone()) {
+                size += v.key().estimate_heap_size() as isize;
+                v.insert(SpanNode::Placeholder);
+                self.missing += 1;
+            }
         }
 
         // Add a `message` attribute from the event name. Otherwise, it won't be displayed in Tempo.
         for event in &mut value.span.events {
             const MESSAGE: &str = "message";
 
+            // Skip if disabled, or already present. It's added when the span is re-applied
+            // from another trace, e.g., by merging, or may be provided by the instrumentation.
+            if !self.event_message || event.attributes.iter().any(|a| a.key == MESSAGE) {
+                continue;
+            }
             event.attributes.push(KeyValue {
                 key: MESSAGE.to_string(),
                 value: Some(AnyValue {
@@ -108,29 +272,77 @@ impl Trace {
         self.end_time = (self.end_time)
             .max(SystemTime::UNIX_EPOCH + Duration::from_nanos(value.span.end_time_unix_nano as _));
 
+        // Events beyond the limit of the trace are dropped, keeping the ones received first.
+        let mut budget = match self.max_events {
+            0 => usize::MAX,
+            max => (max as usize).saturating_sub(self.events),
+        };
+
         match self.spans.entry(span_id.clone()) {
             Entry::Occupied(o) => {
                 let o = o.into_mut();
+                let old_size = o.estimate_heap_size();
                 match o {
-                    SpanNode::Placeholder => *o = SpanNode::Value(value),
+                    SpanNode::Placeholder => {
+                        self.events += truncate_events(&mut value.span, budget);
+                        shared += add_shared(&mut self.shared, &value);
+                        *o = SpanNode::Value(value);
+                        self.missing -= 1;
+                    }
+                    // Exact re-delivery due to exporter retries, nothing to update.
+                    SpanNode::Value(o) if o.span == value.span => {}
                     SpanNode::Value(o) => {
-                        // Update the span with the new value.
-                        o.span.attributes.extend(value.span.attributes);
-                        o.span.events.extend(value.span.events);
+                        // Update the span with the new value. Attributes with the same key are
+                        // overwritten, and events with the same time and name are skipped.
+                        for attr in value.span.attributes {
+                            match o.span.attributes.iter_mut().find(|a| a.key == attr.key) {
+                                Some(a) => *a = attr,
+                                None => o.span.attributes.push(attr),
+                            }
+                        }
+                        for event in value.span.events {
+                            if o.span.events.iter().any(|e| {
+                                e.time_unix_nano == event.time_unix_nano && e.name == event.name
+                            }) {
+                                continue;
+                            }
+                            if budget > 0 {
+                                o.span.events.push(event);
+                                self.events += 1;
+                                budget -= 1;
+                            } else {
+                                o.span.dropped_events_count += 1;
+                            }
+                        }
                         o.span.start_time_unix_nano =
                             (o.span.start_time_unix_nano).min(value.span.start_time_unix_nano);
                         o.span.end_time_unix_nano =
                             (o.span.end_time_unix_nano).max(value.span.end_time_unix_nano);
                     }
                 }
+                size += o.estimate_heap_size() as isize - old_size as isize;
             }
             Entry::Vacant(v) => {
-                v.insert(SpanNode::Value(value));
+                self.events += truncate_events(&mut value.span, budget);
+                shared += add_shared(&mut self.shared, &value);
+                size += v.key().estimate_heap_size() as isize;
+                size += v.insert(SpanNode::Value(value)).estimate_heap_size() as isize;
             }
         }
+
+        size += shared as isize;
+
+        // The table itself may also grow, which is rare.
+        if self.spans.capacity() != capacity {
+            size += table_heap_size(self.spans.capacity()) as isize
+                - table_heap_size(capacity) as isize;
+        }
+
+        self.heap_size = self.heap_size.saturating_add_signed(size);
+        size
     }
 
-    fn iter_valid(&self) -> impl Iterator<Item = &SpanValue> {
+    pub(crate) fn iter_valid(&self) -> impl Iterator<Item = &SpanValue> {
         self.spans.values().filter_map(|node| match node {
             SpanNode::Placeholder => None,
             SpanNode::Value(value) => Some(value),
@@ -141,7 +353,33 @@ impl Trace {
     pub fn is_complete(&self) -> bool {
         // Since all new non-root values recorded will add a placeholder for the parent.
         // If there's no placeholder, it means the trace is complete.
-        self.spans.values().all(|v| matches!(v, SpanNode::Value(_)))
+        self.missing == 0
+    }
+
+    /// Get the number of spans referenced as parents but not received yet.
+    pub(crate) fn missing_span_count(&self) -> usize {
+        self.missing
+    }
+
+    /// Get the IDs of the spans referenced as parents but not received yet, in no particular
+    /// order.
+    pub fn missing_span_ids(&self) -> Vec<SpanId> {
+        (self.spans.iter())
+            .filter(|(_, node)| matches!(node, SpanNode::Placeholder))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Check whether any span or resource in the trace has the attribute with the given key
+    /// and value.
+    pub(crate) fn has_tag(&self, key: &str, value: &str) -> bool {
+        self.iter_valid().any(|v| {
+            (v.span.attributes.iter())
+                .chain(&v.resource.attributes)
+                .any(|kv| {
+                    kv.key == key && kv.value.as_ref().and_then(tag_value).as_deref() == Some(value)
+                })
+        })
     }
 
     /// Get the trace ID.
@@ -154,6 +392,37 @@ impl Trace {
         hex::encode(self.id())
     }
 
+    /// Get the number of spans received in this trace.
+    pub fn span_count(&self) -> usize {
+        self.spans.len() - self.missing
+    }
+
+    /// Get the number of spans dropped due to [`Config::max_spans_per_trace`].
+    ///
+    /// [`Config::max_spans_per_trace`]: crate::Config::max_spans_per_trace
+    pub fn dropped_span_count(&self) -> usize {
+        self.dropped
+    }
+
+    /// Get the duration of the trace, from the earliest start to the latest end of all
+    /// spans received.
+    ///
+    /// Returns `None` if there's no span received.
+    pub fn duration(&self) -> Option<Duration> {
+        self.time_range()
+            .map(|(start, end)| Duration::from_nanos(end.saturating_sub(start)))
+    }
+
+    /// Get the earliest start and the latest end time of all spans in nanoseconds.
+    fn time_range(&self) -> Option<(u64, u64)> {
+        let start = self
+            .iter_valid()
+            .map(|v| v.span.start_time_unix_nano)
+            .min()?;
+        let end = self.iter_valid().map(|v| v.span.end_time_unix_nano).max()?;
+        Some((start, end))
+    }
+
     /// Convert the trace into a JSON value that can be directly imported into Grafana Tempo
     /// as a batch.
     pub fn to_tempo_batch(&self) -> serde_json::Value {
@@ -162,7 +431,9 @@ impl Trace {
             .map(|v| {
                 json!({
                     "resource": &*v.resource,
+                    "schemaUrl": &*v.resource_schema_url,
                     "instrumentationLibrarySpans": [{
+                        "schemaUrl": &*v.scope_schema_url,
                         "spans": [v.span]
                     }]
                 })
@@ -184,57 +455,686 @@ impl Trace {
         })
     }
 
-    pub(crate) fn to_jaeger(&self) -> serde_json::Value {
-        let mut processes = HashMap::new();
+    /// Convert the trace into a lightweight JSON summary, which contains the trace ID,
+    /// the number of spans, the services involved, the root operation and the time range.
+    pub fn to_jaeger_entry(&self) -> serde_json::Value {
+        if self.iter_valid().next().is_none() {
+            return json!({});
+        }
 
-        let entries = self
+        let services = self
             .iter_valid()
+            .counts_by(|v| v.service_name())
+            .into_iter()
+            .sorted()
+            .map(|(name, count)| {
+                json!({
+                    "name": name,
+                    "numberOfSpans": count,
+                })
+            })
+            .collect_vec();
+
+        let (start_time, end_time) = self.time_range().unwrap_or_default();
+
+        json!({
+            "traceID": self.hex_id(),
+            "spans": self.span_count(),
+            "services": services,
+            "operationName": self.operation(),
+            "startTime": start_time / 1000,
+            "duration": end_time.saturating_sub(start_time) / 1000,
+        })
+    }
+
+    /// Convert the trace into a JSON value of Zipkin v2 span list, which can be directly
+    /// imported into Zipkin.
+    pub fn to_zipkin_json(&self) -> serde_json::Value {
+        self.iter_valid()
+            .map(span_to_zipkin_json)
+            .collect_vec()
+            .into()
+    }
+
+    /// Convert the trace into a JSON value of the Chrome Trace Event Format, which can be
+    /// opened in Perfetto or `chrome://tracing` for flame-graph analysis.
+    pub fn to_perfetto_json(&self) -> serde_json::Value {
+        spans_to_perfetto_json(self.iter_valid())
+    }
+
+    /// Convert the trace back into an OTLP export request, which can be forwarded to another
+    /// collector or backend.
+    ///
+    /// Spans are grouped by the resource and the instrumentation scope they were reported with.
+    pub fn to_otlp_request(&self) -> ExportTraceServiceRequest {
+        type ScopeGroup<'a> = (&'a SpanValue, Vec<Span>);
+        let mut groups: Vec<(&SpanValue, Vec<ScopeGroup<'_>>)> = Vec::new();
+
+        // Each group is keyed by its first span, which carries the resource or the scope
+        // along with the schema URL.
+        for v in self.iter_valid() {
+            let pos = (groups.iter()).position(|(r, _)| Arc::ptr_eq(&r.resource, &v.resource));
+            let i = match pos {
+                Some(i) => i,
+                None => {
+                    groups.push((v, Vec::new()));
+                    groups.len() - 1
+                }
+            };
+            let scopes = &mut groups[i].1;
+            match scopes
+                .iter_mut()
+                .find(|(s, _)| Arc::ptr_eq(&s.scope, &v.scope))
+            {
+                Some((_, spans)) => spans.push(v.span.clone()),
+                None => scopes.push((v, vec![v.span.clone()])),
+            }
+        }
+
+        let resource_spans = groups
+            .into_iter()
+            .map(|(r, scopes)| ResourceSpans {
+                resource: Some((*r.resource).clone()),
+                scope_spans: scopes
+                    .into_iter()
+                    .map(|(s, spans)| ScopeSpans {
+                        scope: Some((*s.scope).clone()),
+                        spans,
+                        schema_url: (*s.scope_schema_url).clone(),
+                    })
+                    .collect(),
+                schema_url: (*r.resource_schema_url).clone(),
+            })
+            .collect();
+
+        ExportTraceServiceRequest { resource_spans }
+    }
+
+    /// Convert the trace into the Jaeger JSON model.
+    ///
+    /// The output is deterministic, with spans sorted by the start time and the span ID.
+    pub(crate) fn to_jaeger(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("failed to serialize trace")
+    }
+
+    /// Serialize the trace in the Jaeger JSON format, optionally with the `criticalPath`.
+    ///
+    /// Spans are converted and written one at a time, instead of building the whole JSON
+    /// value of the trace first, to reduce the peak memory usage for large traces.
+    pub(crate) fn serialize_jaeger<S: Serializer>(
+        &self,
+        serializer: S,
+        critical_path: Option<&[SpanId]>,
+    ) -> Result<S::Ok, S::Error> {
+        let values = (self.iter_valid())
+            .sorted_by_key(|v| (v.span.start_time_unix_nano, &v.span.span_id))
+            .collect_vec();
+        let Some(first) = values.first() else {
+            return serializer.serialize_map(Some(0))?.end();
+        };
+
+        let mut processes = BTreeMap::new();
+        let keys = (values.iter())
             .map(|v| {
-                let process = JaegerProcess::from(v);
+                let process = JaegerProcess::from(*v);
                 let key = process.key.clone();
                 processes.insert(key.clone(), process);
-
-                span_to_jaeger_json(v.span.clone(), key)
+                key
             })
             .collect_vec();
+        let self_times = self.self_times();
+        let spans = (values.iter().zip(keys)).map(|(v, key)| {
+            let self_time = self_times.get(v.span.span_id.as_slice()).copied();
+            span_to_jaeger_json(v.span.clone(), &v.scope, key, self_time)
+        });
 
-        if entries.is_empty() {
-            return json!({});
+        let mut map = serializer.serialize_map(None)?;
+        if let Some(critical_path) = critical_path {
+            let critical_path = critical_path.iter().map(hex::encode).collect_vec();
+            map.serialize_entry("criticalPath", &critical_path)?;
         }
+        map.serialize_entry("processes", &processes)?;
+        map.serialize_entry("spans", &SerializeIter::new(spans))?;
+        map.serialize_entry("traceID", &hex::encode(&first.span.trace_id))?;
+        map.end()
+    }
+}
 
-        let trace_id = &entries[0]["traceID"];
+/// Serialize the trace in the Jaeger JSON format, same as [`Trace::to_jaeger_batch`] but
+/// only for a single trace, without the `data` wrapper.
+impl Serialize for Trace {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.serialize_jaeger(serializer, None)
+    }
+}
 
-        json!({
-            "traceID": trace_id,
-            "spans": entries,
-            "processes": processes,
-        })
+/// Serialize the items of an iterator as a sequence, consuming them one at a time.
+struct SerializeIter<I>(std::cell::Cell<Option<I>>);
+
+impl<I> SerializeIter<I> {
+    fn new(iter: I) -> Self {
+        Self(std::cell::Cell::new(Some(iter)))
+    }
+}
+
+impl<I> Serialize for SerializeIter<I>
+where
+    I: Iterator,
+    I::Item: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let iter = self.0.take().expect("already serialized");
+        serializer.collect_seq(iter)
     }
 }
 
 impl Trace {
+    /// Get the root span of the trace.
+    ///
+    /// If the root span is not received, the earliest-starting span is used as a pseudo-root,
+    /// unless disabled by [`Config::pseudo_root`](crate::Config::pseudo_root).
     pub(crate) fn root_span(&self) -> Option<&SpanValue> {
-        self.iter_valid().find(|v| v.span.parent_span_id.is_empty())
+        self.iter_valid()
+            .find(|v| v.span.parent_span_id.is_empty())
+            .or_else(|| {
+                let pseudo_root = self
+                    .iter_valid()
+                    .min_by_key(|v| v.span.start_time_unix_nano);
+                pseudo_root.filter(|_| self.pseudo_root)
+            })
     }
 
     /// Get the service name of the root span in this trace.
     ///
-    /// Returns `None` if the trace is not complete and the root span is not received.
+    /// If the root span is not received, the earliest-starting span may be used instead.
     pub fn service_name(&self) -> Option<&str> {
         self.root_span().map(|v| v.service_name())
     }
 
     /// Get the service instance ID of the root span in this trace.
     ///
-    /// Returns `None` if the trace is not complete and the root span is not received.
+    /// If the root span is not received, the earliest-starting span may be used instead.
     pub fn service_instance_id(&self) -> Option<&str> {
         self.root_span().map(|v| v.service_instance_id())
     }
 
     /// Get the operation (span name) of the root span in this trace.
     ///
-    /// Returns `None` if the trace is not complete and the root span is not received.
+    /// If the root span is not received, the earliest-starting span may be used instead.
     pub fn operation(&self) -> Option<&str> {
         self.root_span().map(|v| v.operation())
     }
+
+    /// Get the critical path of the trace, i.e., the spans that determine its total latency,
+    /// starting from the root span.
+    ///
+    /// Walking backward from the end of each span, the child that ends the latest is on the
+    /// critical path, then the one ending the latest among those starting before that child
+    /// starts, and so on. Such a child may still overlap with the one taken before it, in which
+    /// case the overlapped part is truncated as Jaeger does. Children starting no earlier than
+    /// the last one taken are covered by the path and skipped, and the gaps between the
+    /// children are spent in the parent itself. Each span is followed by the critical path of
+    /// its own children, in the order of time.
+    pub fn critical_path(&self) -> Vec<SpanId> {
+        let Some(root) = self.root_span() else {
+            return Vec::new();
+        };
+
+        let mut children: HashMap<&[u8], Vec<&SpanValue>> = HashMap::new();
+        for v in self.iter_valid() {
+            children
+                .entry(v.span.parent_span_id.as_slice())
+                .or_default()
+                .push(v);
+        }
+
+        let mut path = Vec::new();
+        let mut stack = vec![root];
+        // Malformed parent IDs may form a cycle.
+        let mut visited = HashSet::new();
+
+        while let Some(v) = stack.pop() {
+            if !visited.insert(v.span.span_id.as_slice()) {
+                continue;
+            }
+            path.push(v.span.span_id.clone());
+
+            let Some(children) = children.get(v.span.span_id.as_slice()) else {
+                continue;
+            };
+            let mut cursor = v.span.end_time_unix_nano;
+            // Chosen from the latest, which is exactly the order to be popped from the stack.
+            for child in children
+                .iter()
+                .sorted_by_key(|c| Reverse(c.span.end_time_unix_nano))
+            {
+                if child.span.start_time_unix_nano < cursor {
+                    stack.push(child);
+                    cursor = child.span.start_time_unix_nano;
+                }
+            }
+        }
+
+        path
+    }
+
+    /// Get the self time of each span in nanoseconds, i.e., the part of its duration not
+    /// covered by any of its children. Overlapping children are subtracted only once, and
+    /// the parts outside the span are ignored.
+    pub(crate) fn self_times(&self) -> HashMap<&[u8], u64> {
+        let mut children: HashMap<&[u8], Vec<(u64, u64)>> = HashMap::new();
+        for v in self.iter_valid() {
+            children
+                .entry(v.span.parent_span_id.as_slice())
+                .or_default()
+                .push((v.span.start_time_unix_nano, v.span.end_time_unix_nano));
+        }
+        for intervals in children.values_mut() {
+            intervals.sort_unstable();
+        }
+
+        (self.iter_valid())
+            .map(|v| {
+                let start = v.span.start_time_unix_nano;
+                let end = v.span.end_time_unix_nano.max(start);
+
+                // Sweep the children by the start time to sum up the length of their union.
+                let mut covered = 0;
+                let mut cursor = start;
+                let intervals = children.get(v.span.span_id.as_slice());
+                for &(child_start, child_end) in intervals.into_iter().flatten() {
+                    let child_start = child_start.max(cursor);
+                    let child_end = child_end.min(end);
+                    if child_end > child_start {
+                        covered += child_end - child_start;
+                        cursor = child_end;
+                    }
+                }
+
+                (v.span.span_id.as_slice(), end - start - covered)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{proto::trace::v1::span::Event, test_util::*};
+
+    #[test]
+    fn test_jaeger_entry() {
+        let mut child = span(1, 2, Some(1));
+        child.end_time_unix_nano = START + 3_000_000;
+        let mut trace = new_trace("frontend", vec![span(1, 1, None)]);
+        for value in SpanValue::from_resource_spans(resource_spans("backend", vec![child])) {
+            trace.add_value(value);
+        }
+
+        assert_eq!(
+            trace.to_jaeger_entry(),
+            json!({
+                "traceID": hex::encode(trace_id(1)),
+                "spans": 2,
+                "services": [
+                    { "name": "backend", "numberOfSpans": 1 },
+                    { "name": "frontend", "numberOfSpans": 1 },
+                ],
+                "operationName": "span-1",
+                "startTime": START / 1000,
+                "duration": 3000,
+            })
+        );
+        assert_eq!(Trace::default().to_jaeger_entry(), json!({}));
+    }
+
+    #[test]
+    fn test_zipkin_json() {
+        let trace = new_trace("svc", vec![span(1, 1, None), span(1, 2, Some(1))]);
+
+        let json = trace.to_zipkin_json();
+        let mut spans = json.as_array().unwrap().clone();
+        spans.sort_by_key(|span| span["id"].as_str().unwrap().to_owned());
+        assert_eq!(spans.len(), 2);
+
+        // The root has no parent.
+        assert_eq!(spans[0]["id"], hex::encode(span_id(1)));
+        assert!(spans[0]["parentId"].is_null());
+        assert_eq!(spans[1]["id"], hex::encode(span_id(2)));
+        assert_eq!(spans[1]["parentId"], hex::encode(span_id(1)));
+
+        for span in &spans {
+            assert_eq!(span["traceId"], hex::encode(trace_id(1)));
+            assert_eq!(span["localEndpoint"], json!({ "serviceName": "svc" }));
+        }
+        assert_eq!(Trace::default().to_zipkin_json(), json!([]));
+    }
+
+    /// Compute the estimated heap size of the trace from scratch.
+    fn recompute_heap_size(trace: &Trace) -> usize {
+        let mut shared = HashSet::new();
+        let spans = (trace.spans.iter())
+            .map(|(id, node)| id.estimate_heap_size() + node.estimate_heap_size())
+            .sum::<usize>();
+        let shared = (trace.iter_valid())
+            .map(|v| add_shared(&mut shared, v))
+            .sum::<usize>();
+        table_heap_size(trace.spans.capacity()) + spans + shared
+    }
+
+    #[test]
+    fn test_incremental_heap_size() {
+        let mut trace = Trace::default();
+        let mut total = 0isize;
+        let mut add = |trace: &mut Trace, spans| {
+            for value in SpanValue::from_resource_spans(resource_spans("svc", spans)) {
+                total += trace.add_value(value);
+            }
+            assert_eq!(trace.estimate_heap_size(), recompute_heap_size(trace));
+            assert_eq!(total as usize, trace.estimate_heap_size());
+        };
+
+        // Children before the parent, which fills the placeholder.
+        add(&mut trace, vec![span(1, 2, Some(1)), span(1, 3, Some(1))]);
+        add(&mut trace, vec![span(1, 1, None)]);
+
+        // Overwrite an attribute with a shorter value.
+        let mut long = span(1, 4, Some(1));
+        long.attributes = vec![kv("key", &"x".repeat(100))];
+        add(&mut trace, vec![long]);
+        let mut short = span(1, 4, Some(1));
+        short.attributes = vec![kv("key", "x")];
+        add(&mut trace, vec![short]);
+
+        // Grow the table.
+        add(&mut trace, (5..50).map(|i| span(1, i, Some(1))).collect());
+    }
+
+    #[test]
+    fn test_span_count_and_duration() {
+        assert_eq!(Trace::default().span_count(), 0);
+        assert_eq!(Trace::default().duration(), None);
+
+        let mut child = span(1, 2, Some(1));
+        child.start_time_unix_nano = START + 2_000_000;
+        child.end_time_unix_nano = START + 5_000_000;
+        // The parent is not received, so it's not counted.
+        let trace = new_trace("svc", vec![span(1, 3, None), child]);
+        assert_eq!(trace.span_count(), 2);
+        assert_eq!(trace.duration(), Some(Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn test_otlp_request_round_trip() {
+        let mut trace = new_trace("a", vec![span(1, 1, None), span(1, 2, Some(1))]);
+        for value in SpanValue::from_resource_spans(resource_spans("b", vec![span(1, 3, Some(1))]))
+        {
+            trace.add_value(value);
+        }
+
+        // Spans are grouped by the resource.
+        let request = trace.to_otlp_request();
+        let spans = (request.resource_spans.iter())
+            .map(|r| {
+                let service = find_string(&r.resource.as_ref().unwrap().attributes, "service.name");
+                let spans = (r.scope_spans.iter())
+                    .flat_map(|s| &s.spans)
+                    .map(|s| s.span_id[0])
+                    .sorted()
+                    .collect_vec();
+                (service.unwrap(), spans)
+            })
+            .sorted()
+            .collect_vec();
+        assert_eq!(spans, [("a", vec![1, 2]), ("b", vec![3])]);
+
+        let mut restored = Trace::default();
+        for resource_spans in request.resource_spans {
+            for value in SpanValue::from_resource_spans(resource_spans) {
+                restored.add_value(value);
+            }
+        }
+        assert_eq!(restored.to_jaeger(), trace.to_jaeger());
+    }
+
+    #[test]
+    fn test_dedup_retries() {
+        let mut first = span(1, 1, None);
+        first.attributes = vec![kv("k", "v1")];
+        first.events = vec![Event {
+            time_unix_nano: START,
+            name: "e".to_owned(),
+            ..Default::default()
+        }];
+        let mut trace = new_trace("svc", vec![first.clone()]);
+
+        // An exact re-delivery changes nothing.
+        for value in SpanValue::from_resource_spans(resource_spans("svc", vec![first.clone()])) {
+            assert_eq!(trace.add_value(value), 0);
+        }
+        assert_eq!(trace.span_count(), 1);
+
+        // A partial update overwrites the attributes and skips the duplicate events.
+        let mut update = first.clone();
+        update.attributes = vec![kv("k", "v2"), kv("k2", "v")];
+        for value in SpanValue::from_resource_spans(resource_spans("svc", vec![update])) {
+            trace.add_value(value);
+        }
+        let span = &trace.iter_valid().next().unwrap().span;
+        assert_eq!(span.attributes, [kv("k", "v2"), kv("k2", "v")]);
+        assert_eq!(span.events.len(), 1);
+        assert_eq!(trace.span_count(), 1);
+    }
+
+    #[test]
+    fn test_otlp_request_scopes() {
+        let mut resource_spans = resource_spans("svc", vec![span(1, 1, None)]);
+        resource_spans.scope_spans.push(ScopeSpans {
+            scope: Some(InstrumentationScope {
+                name: "my-lib".to_owned(),
+                ..Default::default()
+            }),
+            spans: vec![span(1, 2, Some(1))],
+            schema_url: String::new(),
+        });
+        let mut trace = Trace::default();
+        for value in SpanValue::from_resource_spans(resource_spans) {
+            trace.add_value(value);
+        }
+
+        let request = trace.to_otlp_request();
+        assert_eq!(request.resource_spans.len(), 1);
+        let scopes = (request.resource_spans[0].scope_spans.iter())
+            .map(|s| (s.scope.as_ref().unwrap().name.as_str(), s.spans.len()))
+            .sorted()
+            .collect_vec();
+        assert_eq!(scopes, [("", 1), ("my-lib", 1)]);
+    }
+
+    #[test]
+    fn test_deterministic_jaeger() {
+        let mut late = span(1, 1, None);
+        late.start_time_unix_nano += 1;
+        let spans = vec![late, span(1, 3, Some(1)), span(1, 2, Some(1))];
+
+        let trace = new_trace("svc", spans.clone());
+        let ids = (trace.to_jaeger()["spans"].as_array().unwrap().iter())
+            .map(|s| s["spanID"].as_str().unwrap().to_owned())
+            .collect_vec();
+        assert_eq!(ids, [2, 3, 1].map(|i| hex::encode(span_id(i))));
+
+        let reversed = new_trace("svc", spans.into_iter().rev().collect());
+        assert_eq!(trace.to_jaeger(), reversed.to_jaeger());
+    }
+
+    #[test]
+    fn test_schema_urls() {
+        let mut resource_spans = resource_spans("svc", vec![span(1, 1, None)]);
+        resource_spans.schema_url = "https://opentelemetry.io/schemas/1.0.0".to_owned();
+        resource_spans.scope_spans[0].schema_url =
+            "https://opentelemetry.io/schemas/1.1.0".to_owned();
+        let mut trace = Trace::default();
+        for value in SpanValue::from_resource_spans(resource_spans.clone()) {
+            trace.add_value(value);
+        }
+
+        let request = trace.to_otlp_request();
+        assert_eq!(
+            request.resource_spans[0].schema_url,
+            resource_spans.schema_url
+        );
+        assert_eq!(
+            request.resource_spans[0].scope_spans[0].schema_url,
+            resource_spans.scope_spans[0].schema_url
+        );
+
+        let batch = trace.to_tempo_batch();
+        assert_eq!(
+            batch["batches"][0]["schemaUrl"],
+            "https://opentelemetry.io/schemas/1.0.0"
+        );
+    }
+
+    #[test]
+    fn test_shared_resource_accounting() {
+        let big = "x".repeat(10_000);
+        let mut resource_spans = resource_spans("svc", vec![span(1, 1, None), span(1, 2, Some(1))]);
+        (resource_spans.resource.as_mut().unwrap().attributes).push(kv("big", &big));
+        let values = SpanValue::from_resource_spans(resource_spans.clone()).collect_vec();
+
+        let mut trace = Trace::default();
+        let mut values = values.into_iter();
+        let first = trace.add_value(values.next().unwrap());
+        assert!(first > big.len() as isize);
+        // The resource is shared with the first span, so it's not accounted again.
+        let second = trace.add_value(values.next().unwrap());
+        assert!(second < big.len() as isize);
+
+        // While a resource from another request is.
+        resource_spans.scope_spans[0].spans = vec![span(1, 3, Some(1))];
+        for value in SpanValue::from_resource_spans(resource_spans) {
+            assert!(trace.add_value(value) > big.len() as isize);
+        }
+        assert_eq!(trace.estimate_heap_size(), recompute_heap_size(&trace));
+    }
+
+    #[test]
+    fn test_critical_path() {
+        let ms: u64 = 1_000_000;
+        let with_time = |id, parent, start, end| {
+            let mut span = span(1, id, parent);
+            span.start_time_unix_nano = START + start * ms;
+            span.end_time_unix_nano = START + end * ms;
+            span
+        };
+        let trace = new_trace(
+            "svc",
+            vec![
+                with_time(1, None, 0, 10),
+                // Fully overlapped by the next one, so not on the path.
+                with_time(2, Some(1), 3, 4),
+                with_time(3, Some(1), 2, 6),
+                with_time(4, Some(3), 3, 5),
+                with_time(5, Some(1), 7, 9),
+            ],
+        );
+        assert_eq!(trace.critical_path(), [1, 3, 4, 5].map(span_id));
+
+        let trace = new_trace(
+            "svc",
+            vec![
+                with_time(1, None, 0, 10),
+                with_time(2, Some(1), 5, 9),
+                // Overlapping with the next one, but starting earlier, so still on the path.
+                with_time(3, Some(1), 3, 7),
+                // Starting after the previous one on the path, so skipped.
+                with_time(4, Some(1), 4, 6),
+            ],
+        );
+        assert_eq!(trace.critical_path(), [1, 3, 2].map(span_id));
+
+        // Empty without a root.
+        let trace = new_trace("svc", vec![span(1, 2, Some(1))]);
+        let trace = Trace {
+            pseudo_root: false,
+            ..trace
+        };
+        assert!(trace.critical_path().is_empty());
+    }
+
+    #[test]
+    fn test_serialize() {
+        // Processes are keyed by the service instance.
+        let mut trace = Trace::default();
+        for (service, id) in [("frontend", 1), ("backend", 2)] {
+            let mut resource_spans =
+                resource_spans(service, vec![span(1, id, (id > 1).then_some(1))]);
+            let resource = resource_spans.resource.as_mut().unwrap();
+            resource
+                .attributes
+                .push(kv("service.instance.id", &format!("{service}-0")));
+            for value in SpanValue::from_resource_spans(resource_spans) {
+                trace.add_value(value);
+            }
+        }
+
+        let streamed: serde_json::Value =
+            serde_json::from_slice(&serde_json::to_vec(&trace).unwrap()).unwrap();
+        assert_eq!(streamed, trace.to_jaeger());
+        assert_eq!(streamed["traceID"], hex::encode(trace_id(1)));
+
+        let processes = streamed["processes"].as_object().unwrap();
+        let services = (processes.values())
+            .map(|p| p["serviceName"].as_str().unwrap())
+            .sorted()
+            .collect_vec();
+        assert_eq!(services, ["backend", "frontend"]);
+        let spans = streamed["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert!(spans
+            .iter()
+            .all(|s| processes.contains_key(s["processID"].as_str().unwrap())));
+
+        assert_eq!(serde_json::to_string(&Trace::default()).unwrap(), "{}");
+    }
+
+    #[test]
+    fn test_self_times() {
+        const MS: u64 = 1_000_000;
+        let timed = |id, parent, start: u64, end: u64| {
+            let mut span = span(1, id, parent);
+            span.start_time_unix_nano = START + start * MS;
+            span.end_time_unix_nano = START + end * MS;
+            span
+        };
+
+        let trace = new_trace(
+            "svc",
+            vec![
+                timed(1, None, 0, 10),
+                // Non-overlapping children of the root.
+                timed(2, Some(1), 1, 3),
+                timed(3, Some(1), 5, 6),
+                // Children covering the whole parent, overlapping or exceeding it.
+                timed(4, Some(3), 5, 6),
+                timed(5, Some(2), 1, 2),
+                timed(6, Some(2), 1, 4),
+            ],
+        );
+        let self_times = trace.self_times();
+        let self_time = |id| self_times[span_id(id).as_slice()] / MS;
+        assert_eq!(self_time(1), 7);
+        assert_eq!(self_time(2), 0);
+        assert_eq!(self_time(3), 0);
+        assert_eq!(self_time(6), 3);
+
+        let jaeger = trace.to_jaeger();
+        let root = (jaeger["spans"].as_array().unwrap().iter())
+            .find(|s| s["spanID"] == hex::encode(span_id(1)))
+            .unwrap();
+        let tag = (root["tags"].as_array().unwrap().iter())
+            .find(|t| t["key"] == "self_time_us")
+            .unwrap();
+        assert_eq!(tag["value"], 7000);
+    }
 }
@@ -0,0 +1,89 @@
+// https://zipkin.io/zipkin-api/#/default/post_spans
+
+use std::collections::BTreeMap;
+
+use serde_json::json;
+
+use crate::{
+    jaeger::model::any_value_to_serde_value,
+    proto::{common::v1::AnyValue, trace::v1::span::SpanKind},
+    trace::SpanValue,
+};
+
+fn hex(bytes: &[u8]) -> String {
+    ::hex::encode(bytes)
+}
+
+pub(crate) fn span_to_zipkin_json(value: &SpanValue) -> serde_json::Value {
+    let span = &value.span;
+
+    let kind = match SpanKind::try_from(span.kind).unwrap_or_default() {
+        SpanKind::Unspecified | SpanKind::Internal => None,
+        SpanKind::Server => Some("SERVER"),
+        SpanKind::Client => Some("CLIENT"),
+        SpanKind::Producer => Some("PRODUCER"),
+        SpanKind::Consumer => Some("CONSUMER"),
+    };
+
+    // Zipkin only supports string tags.
+    let tags = span
+        .attributes
+        .iter()
+        .map(|kv| {
+            let value = match kv.value.clone() {
+                Some(AnyValue { value: Some(value) }) => match any_value_to_serde_value(value) {
+                    serde_json::Value::String(s) => s,
+                    other => other.to_string(),
+                },
+                _ => String::new(),
+            };
+            (kv.key.clone(), value)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    let parent_id = (!span.parent_span_id.is_empty()).then(|| hex(&span.parent_span_id));
+
+    json!({
+        "traceId": hex(&span.trace_id),
+        "id": hex(&span.span_id),
+        "parentId": parent_id,
+        "name": span.name,
+        "timestamp": span.start_time_unix_nano / 1000,
+        "duration": span.end_time_unix_nano.saturating_sub(span.start_time_unix_nano) / 1000,
+        "kind": kind,
+        "localEndpoint": {
+            "serviceName": value.service_name(),
+        },
+        "tags": tags,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn test_zipkin_json() {
+        let mut child = span(1, 2, Some(1));
+        child.kind = SpanKind::Client as i32;
+        child.attributes = vec![kv("http.method", "GET")];
+        let trace = new_trace("svc", vec![child]);
+        let value = trace.iter_valid().next().unwrap();
+
+        assert_eq!(
+            span_to_zipkin_json(value),
+            json!({
+                "traceId": hex(&trace_id(1)),
+                "id": hex(&span_id(2)),
+                "parentId": hex(&span_id(1)),
+                "name": "span-2",
+                "timestamp": START / 1000,
+                "duration": 1000,
+                "kind": "CLIENT",
+                "localEndpoint": { "serviceName": "svc" },
+                "tags": { "http.method": "GET" },
+            })
+        );
+    }
+}